@@ -0,0 +1,92 @@
+//! Throughput of DATA frames through the parser and serializer, in payload
+//! bytes per second, at several payload sizes.
+//!
+//! Parsing covers unescaping and checking the CRC, and serializing covers
+//! randomizing, computing the CRC and escaping. The `reserved` cases use
+//! payloads that are all reserved bytes on the wire, so every byte is escaped
+//! and the frame doubles in size.
+//!
+//! ```text
+//! cargo bench --bench data_throughput -- --save-baseline main
+//! cargo bench --bench data_throughput -- --baseline main
+//! ```
+//!
+//! Baseline throughput on an x86_64 Linux workstation, in MiB of payload per
+//! second:
+//!
+//! | payload | decode plain | decode reserved | encode plain | encode reserved |
+//! |---------|--------------|-----------------|--------------|-----------------|
+//! | 64      | 78           | 77              | 39           | 30              |
+//! | 128     | 78           | 79              | 43           | 37              |
+//! | 255     | 63           | 84              | 49           | 36              |
+//!
+//! Empty payloads take about 165 ns to decode and 90 ns to encode.
+use bytes::BytesMut;
+use criterion::{
+    black_box, criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput,
+};
+use ezsp_spi_driver::ash::{randomize, Frame, FrameNumber};
+
+const PAYLOAD_SIZES: [usize; 4] = [0, 64, 128, 255];
+
+/// A byte that is reserved on the wire, cycling through the flag, escape,
+/// XON, XOFF, substitute and cancel bytes.
+const RESERVED_BYTES: [u8; 6] = [0x7E, 0x7D, 0x11, 0x13, 0x18, 0x1A];
+
+fn data_frame(body: BytesMut) -> Frame {
+    Frame::data(FrameNumber::zero(), false, FrameNumber::zero(), body)
+}
+
+fn plain_payload(len: usize) -> BytesMut {
+    (0..len).map(|i| i as u8).collect::<Vec<_>>()[..].into()
+}
+
+/// A payload that randomizes to reserved bytes only.
+fn reserved_payload(len: usize) -> BytesMut {
+    let mut body: BytesMut = (0..len)
+        .map(|i| RESERVED_BYTES[i % RESERVED_BYTES.len()])
+        .collect::<Vec<_>>()[..]
+        .into();
+    randomize(&mut body);
+    body
+}
+
+fn cases() -> Vec<(&'static str, usize, Frame)> {
+    let mut cases = Vec::new();
+    for len in PAYLOAD_SIZES {
+        cases.push(("plain", len, data_frame(plain_payload(len))));
+        cases.push(("reserved", len, data_frame(reserved_payload(len))));
+    }
+    cases
+}
+
+fn bench_decode(c: &mut Criterion) {
+    let mut group = c.benchmark_group("decode_data");
+    for (name, len, frame) in cases() {
+        let mut input = BytesMut::new();
+        frame.serialize(&mut input);
+        group.throughput(Throughput::Bytes(len as u64));
+        group.bench_with_input(BenchmarkId::new(name, len), &input, |b, input| {
+            b.iter(|| Frame::parse(black_box(input)).expect("Expected a valid frame"))
+        });
+    }
+    group.finish();
+}
+
+fn bench_encode(c: &mut Criterion) {
+    let mut group = c.benchmark_group("encode_data");
+    for (name, len, frame) in cases() {
+        group.throughput(Throughput::Bytes(len as u64));
+        group.bench_with_input(BenchmarkId::new(name, len), &frame, |b, frame| {
+            b.iter_batched_ref(
+                || BytesMut::with_capacity(2 * len + 8),
+                |buf| frame.serialize(buf),
+                BatchSize::SmallInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_decode, bench_encode);
+criterion_main!(benches);
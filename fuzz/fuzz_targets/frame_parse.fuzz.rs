@@ -0,0 +1,26 @@
+#![no_main]
+
+use bytes::BytesMut;
+use ezsp_spi_driver::ash::{randomize, Frame};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    // Parsing untrusted bytes may fail, but must never panic
+    let frame = match Frame::parse(data) {
+        Ok((_, frame)) => frame,
+        Err(_) => return,
+    };
+
+    // The parser leaves DATA bodies randomized, while serializing randomizes
+    // them, so undo the randomization before serializing the frame again.
+    let mut plain = frame.clone();
+    if let Frame::Data { body, .. } = &mut plain {
+        randomize(body);
+    }
+    let mut buf = BytesMut::new();
+    plain.serialize(&mut buf);
+
+    let (rest, reparsed) = Frame::parse(&buf).expect("serialized frame failed to parse");
+    assert!(rest.is_empty(), "serialized frame was not fully consumed");
+    assert_eq!(reparsed, frame);
+});
@@ -3,17 +3,49 @@ use super::{
     frame::Frame,
     Error, Result,
 };
-use bytes::{Buf, BytesMut};
+use bytes::{Buf, BufMut, BytesMut};
 use nom::{Err, Finish, Needed, Offset};
 use tokio_util::codec::{Decoder, Encoder};
-use tracing::{instrument, trace};
+use tracing::{instrument, trace, warn};
+
+/// The number of bytes dropped due to framing errors in a session after which
+/// a warning is logged.
+pub const DEFAULT_DISCARD_WARN_THRESHOLD: u64 = 64;
 
 #[derive(Debug)]
 pub struct AshCodec {
     dropping: bool,
+    /// Total bytes dropped due to framing errors.
+    bytes_discarded: u64,
+    discard_warn_threshold: u64,
+    flag_after_decode_error: bool,
+    /// Set once a decode error is seen, until a frame is encoded.
+    flag_pending: bool,
 }
 
 impl AshCodec {
+    /// Create a codec that logs a warning once more than
+    /// `discard_warn_threshold` bytes have been dropped due to framing errors.
+    pub fn new(discard_warn_threshold: u64) -> AshCodec {
+        AshCodec {
+            dropping: false,
+            bytes_discarded: 0,
+            discard_warn_threshold,
+            flag_after_decode_error: false,
+            flag_pending: false,
+        }
+    }
+
+    /// Set whether the next frame encoded after a decode error is preceded by
+    /// a flag byte.
+    ///
+    /// A frame that failed to decode may mean the other end is also out of
+    /// step, so the flag makes it discard whatever partial frame it holds
+    /// before reading ours.
+    pub fn set_flag_after_decode_error(&mut self, enabled: bool) {
+        self.flag_after_decode_error = enabled;
+    }
+
     /// Locate unescaped cancel or substitute bytes and drop the portion of the
     /// buffer up to and including the detected bytes.
     ///
@@ -36,24 +68,22 @@ impl AshCodec {
     #[instrument]
     fn drop_buffer_before_substitute(&mut self, buf: &mut BytesMut) {
         trace!("Searching for framing error bytes");
-        loop {
-            if let Some(idx) = buf
-                .iter()
-                .position(|&b| b == SUB_BYTE || b == CANCEL_BYTE || b == FLAG_BYTE)
-            {
-                if buf[idx] == FLAG_BYTE {
-                    trace!("Flag byte detected at index {}, bailing", idx);
-                    break;
-                }
-                self.dropping = buf[idx] == SUB_BYTE;
-                trace!(
-                    dropping = self.dropping,
-                    "Found a framing byte {:x} at index {}",
-                    buf[idx],
-                    idx
-                );
-                buf.advance(idx + 1);
+        while let Some(idx) = buf
+            .iter()
+            .position(|&b| b == SUB_BYTE || b == CANCEL_BYTE || b == FLAG_BYTE)
+        {
+            if buf[idx] == FLAG_BYTE {
+                trace!("Flag byte detected at index {}, bailing", idx);
+                break;
             }
+            self.dropping = buf[idx] == SUB_BYTE;
+            trace!(
+                dropping = self.dropping,
+                "Found a framing byte {:x} at index {}",
+                buf[idx],
+                idx
+            );
+            self.discard(buf, idx + 1);
         }
     }
 
@@ -61,55 +91,104 @@ impl AshCodec {
         trace!("Dropping buffer until flag byte found");
         if let Some(idx) = buf.iter().position(|&b| b == FLAG_BYTE) {
             trace!("Flag byte found at pos {}, dropping bytes before", idx);
-            buf.advance(idx + 1);
+            self.discard(buf, idx + 1);
             self.dropping = false;
             trace!("Buffer drop operation complete")
         } else {
-            let _ = buf.split();
+            self.discard(buf, buf.len());
+        }
+    }
+
+    fn discard(&mut self, buf: &mut BytesMut, count: usize) {
+        let was_below = self.bytes_discarded <= self.discard_warn_threshold;
+        buf.advance(count);
+        self.bytes_discarded += count as u64;
+        if was_below && self.bytes_discarded > self.discard_warn_threshold {
+            warn!(
+                bytes_discarded = self.bytes_discarded,
+                "Dropped {} bytes due to framing errors, the connection may be noisy",
+                self.bytes_discarded
+            );
         }
     }
 
     pub fn is_dropping(&self) -> bool {
         self.dropping
     }
+
+    /// The number of bytes dropped so far due to framing errors.
+    pub fn bytes_discarded(&self) -> u64 {
+        self.bytes_discarded
+    }
+
+    /// Serialize several frames back to back into `dst`, so they can be
+    /// written to the host with a single write.
+    ///
+    /// `Framed` does the same for frames fed to it between flushes.
+    pub fn encode_all(
+        &mut self,
+        frames: impl IntoIterator<Item = Frame>,
+        dst: &mut BytesMut,
+    ) -> Result<()> {
+        for frame in frames {
+            self.encode(frame, dst)?;
+        }
+        Ok(())
+    }
 }
 
 impl Default for AshCodec {
     fn default() -> Self {
-        AshCodec { dropping: false }
+        AshCodec::new(DEFAULT_DISCARD_WARN_THRESHOLD)
     }
 }
 
 impl Decoder for AshCodec {
-    type Item = Result<Frame>;
+    type Item = Frame;
     type Error = Error;
 
+    /// Decode the next frame in `src`.
+    ///
+    /// Frames that fail validation are returned as an error once they have
+    /// been removed from `src`, so decoding can carry on with the next frame
+    /// after the host has been told to send them again. Frames with an unknown
+    /// control byte are line noise, and are dropped up to the next flag byte
+    /// without an error.
     #[instrument]
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>> {
-        self.drop_buffer_framing_errors(src);
+        loop {
+            self.drop_buffer_framing_errors(src);
 
-        let res = Frame::parse(&src[..]);
+            let res = Frame::parse(&src[..]);
 
-        if let Err(Err::Incomplete(needed)) = res {
-            trace!(bytes_needed = ?needed, "Incomplete frame detected");
-            if let Needed::Size(additional) = needed {
-                src.reserve(additional.into());
+            if let Err(Err::Incomplete(needed)) = res {
+                trace!(bytes_needed = ?needed, "Incomplete frame detected");
+                if let Needed::Size(additional) = needed {
+                    src.reserve(additional.into());
+                }
+                return Ok(None);
             }
-            return Ok(None);
-        }
 
-        let (rest, frame) = match res.finish() {
-            Ok(v) => v,
-            Err(e) => {
-                let (input, error) = e.into_inner();
-                src.advance(src.offset(input));
-                return Err(error);
-            }
-        };
-        let offset = src.offset(rest);
-        trace!("Frame decoded, {} bytes", offset);
-        src.advance(offset);
-        Ok(Some(Ok(frame)))
+            let (rest, frame) = match res.finish() {
+                Ok(v) => v,
+                Err(e) => {
+                    let (input, error) = e.into_inner();
+                    let offset = src.offset(input);
+                    if let Error::UnknownFrame = error {
+                        trace!("Dropping {} bytes of an unknown frame", offset);
+                        self.discard(src, offset);
+                        continue;
+                    }
+                    src.advance(offset);
+                    self.flag_pending = self.flag_after_decode_error;
+                    return Err(error);
+                }
+            };
+            let offset = src.offset(rest);
+            trace!("Frame decoded, {} bytes", offset);
+            src.advance(offset);
+            return Ok(Some(frame));
+        }
     }
 }
 
@@ -117,6 +196,11 @@ impl Encoder<Frame> for AshCodec {
     type Error = Error;
 
     fn encode(&mut self, item: Frame, dst: &mut BytesMut) -> Result<()> {
+        if self.flag_pending {
+            trace!("Sending a flag byte ahead of the frame after a decode error");
+            dst.put_u8(FLAG_BYTE);
+            self.flag_pending = false;
+        }
         item.serialize(dst);
         Ok(())
     }
@@ -124,9 +208,53 @@ impl Encoder<Frame> for AshCodec {
 
 #[cfg(test)]
 mod tests {
-    use bytes::BufMut;
+    use futures::{executor::block_on, SinkExt};
+    use std::{
+        io,
+        pin::Pin,
+        task::{Context, Poll},
+    };
+    use tokio::io::AsyncWrite;
+    use tokio_util::codec::FramedWrite;
 
     use super::*;
+    use crate::ash::frame::randomize;
+
+    /// Records each write made to it separately.
+    #[derive(Default)]
+    struct WriteRecorder(Vec<Vec<u8>>);
+
+    impl AsyncWrite for WriteRecorder {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            self.get_mut().0.push(buf.to_vec());
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    fn batch() -> Vec<Frame> {
+        vec![
+            Frame::data(
+                0.try_into().unwrap(),
+                false,
+                0.try_into().unwrap(),
+                BytesMut::from(&[0x00, 0x00, 0x00, 0x02][..]),
+            ),
+            Frame::ack(false, 1.try_into().unwrap()),
+            Frame::rst_ack(2, 0x02),
+        ]
+    }
 
     #[test]
     fn it_decodes_a_valid_frame() {
@@ -135,7 +263,7 @@ mod tests {
             .into();
         let mut codec = AshCodec::default();
 
-        assert!(matches!(codec.decode(&mut buf), Ok(Some(Ok(_)))));
+        assert!(matches!(codec.decode(&mut buf), Ok(Some(_))));
         assert_eq!(buf.len(), 0);
     }
 
@@ -158,7 +286,7 @@ mod tests {
 
         assert!(matches!(
             codec.decode(&mut buf),
-            Ok(Some(Err(Error::InvalidChecksum(_))))
+            Err(Error::InvalidChecksum(_))
         ));
         assert_eq!(buf.len(), 0);
     }
@@ -170,17 +298,35 @@ mod tests {
 
         assert!(matches!(
             codec.decode(&mut buf),
-            Ok(Some(Err(Error::InvalidDataField(_))))
+            Err(Error::InvalidDataField(_))
         ));
         assert_eq!(buf.len(), 0);
     }
 
     #[test]
-    fn it_hard_fails_if_invalid_control_byte_encountered() {
-        let mut buf: BytesMut = [0xFF].as_ref().into();
+    fn it_drops_a_frame_with_an_invalid_control_byte() {
+        let mut buf: BytesMut = [0xFF, 0x7E].as_ref().into();
+        let mut codec = AshCodec::default();
+
+        assert!(matches!(codec.decode(&mut buf), Ok(None)));
+        assert_eq!(buf.len(), 0);
+        assert_eq!(codec.bytes_discarded(), 2);
+    }
+
+    #[test]
+    fn it_resynchronizes_on_a_valid_frame_after_garbage() {
+        let mut buf: BytesMut = [
+            0xFF, 0x12, 0x34, 0x7E, 0x25, 0x42, 0x21, 0xA8, 0x56, 0xA6, 0x09, 0x7E,
+        ]
+        .as_ref()
+        .into();
         let mut codec = AshCodec::default();
 
-        assert!(matches!(codec.decode(&mut buf), Err(Error::UnknownFrame)))
+        assert!(matches!(
+            codec.decode(&mut buf),
+            Ok(Some(Frame::Data { .. }))
+        ));
+        assert_eq!(buf.len(), 0);
     }
 
     #[test]
@@ -202,7 +348,7 @@ mod tests {
         .into();
         let mut codec = AshCodec::default();
 
-        assert!(matches!(codec.decode(&mut buf), Ok(Some(Ok(_)))));
+        assert!(matches!(codec.decode(&mut buf), Ok(Some(_))));
         assert_eq!(*buf, [0xFF, 0xFF, 0xFF, 0x1A]);
     }
 
@@ -233,4 +379,153 @@ mod tests {
         assert_eq!(buf.len(), 0);
         assert!(!codec.is_dropping());
     }
+
+    #[test]
+    fn it_encodes_frames_back_to_back() {
+        let mut buf = BytesMut::new();
+        let mut codec = AshCodec::default();
+        codec
+            .encode_all(batch(), &mut buf)
+            .expect("Expected frames to encode");
+
+        for frame in batch() {
+            let mut decoded = match codec.decode(&mut buf) {
+                Ok(Some(f)) => f,
+                res => panic!("Expected a valid frame, got {:?}", res),
+            };
+            if let Frame::Data { body, .. } = &mut decoded {
+                randomize(body);
+            }
+            assert_eq!(decoded, frame);
+        }
+        assert_eq!(buf.len(), 0);
+    }
+
+    #[test]
+    fn it_writes_frames_fed_before_a_flush_at_once() {
+        let mut sink = FramedWrite::new(WriteRecorder::default(), AshCodec::default());
+        block_on(async {
+            for frame in batch() {
+                sink.feed(frame).await.expect("Expected frame to be fed");
+            }
+            sink.flush().await.expect("Expected flush to succeed");
+        });
+
+        let mut expected = BytesMut::new();
+        AshCodec::default()
+            .encode_all(batch(), &mut expected)
+            .unwrap();
+        assert_eq!(sink.get_ref().0, vec![expected.to_vec()]);
+    }
+
+    #[test]
+    fn it_sends_a_flag_byte_only_after_a_decode_error() {
+        let ack = || Frame::ack(false, 1.try_into().unwrap());
+        let mut plain = BytesMut::new();
+        ack().serialize(&mut plain);
+
+        let mut codec = AshCodec::default();
+        codec.set_flag_after_decode_error(true);
+        let mut buf = BytesMut::new();
+        codec.encode(ack(), &mut buf).unwrap();
+        assert_eq!(buf, plain);
+
+        let mut src: BytesMut = [0x81, 0x60, 0x58, 0x7E].as_ref().into();
+        assert!(codec.decode(&mut src).is_err());
+
+        let mut buf = BytesMut::new();
+        codec.encode_all([ack(), ack()], &mut buf).unwrap();
+        let mut expected = BytesMut::from(&[FLAG_BYTE][..]);
+        expected.extend_from_slice(&plain);
+        expected.extend_from_slice(&plain);
+        assert_eq!(buf, expected);
+    }
+
+    #[test]
+    fn it_sends_no_flag_byte_after_a_decode_error_unless_asked() {
+        let mut codec = AshCodec::default();
+        let mut src: BytesMut = [0x81, 0x60, 0x58, 0x7E].as_ref().into();
+        assert!(codec.decode(&mut src).is_err());
+
+        let mut buf = BytesMut::new();
+        codec
+            .encode(Frame::ack(false, 1.try_into().unwrap()), &mut buf)
+            .unwrap();
+        assert_eq!(buf[0], 0x81);
+    }
+
+    #[test]
+    fn it_decodes_frames_received_back_to_back() {
+        let mut buf: BytesMut = [
+            0x25, 0x42, 0x21, 0xA8, 0x56, 0xA6, 0x09, 0x7E, 0x81, 0x60, 0x59, 0x7E,
+        ]
+        .as_ref()
+        .into();
+        let mut codec = AshCodec::default();
+
+        assert!(matches!(
+            codec.decode(&mut buf),
+            Ok(Some(Frame::Data { .. }))
+        ));
+        assert!(matches!(
+            codec.decode(&mut buf),
+            Ok(Some(Frame::Ack { ack_num, .. })) if *ack_num == 1
+        ));
+        assert_eq!(buf.len(), 0);
+    }
+
+    #[test]
+    fn it_only_drops_the_cancelled_frame_between_valid_frames() {
+        let mut buf: BytesMut = [
+            0x25, 0x42, 0x21, 0xA8, 0x56, 0xA6, 0x09, 0x7E, 0x25, 0x42, 0x1A, 0x81, 0x60, 0x59,
+            0x7E,
+        ]
+        .as_ref()
+        .into();
+        let mut codec = AshCodec::default();
+
+        assert!(matches!(
+            codec.decode(&mut buf),
+            Ok(Some(Frame::Data { .. }))
+        ));
+        assert!(matches!(
+            codec.decode(&mut buf),
+            Ok(Some(Frame::Ack { ack_num, .. })) if *ack_num == 1
+        ));
+        assert_eq!(buf.len(), 0);
+    }
+
+    #[test]
+    fn it_only_drops_the_substituted_frame_between_valid_frames() {
+        let mut buf: BytesMut = [
+            0x25, 0x42, 0x21, 0xA8, 0x56, 0xA6, 0x09, 0x7E, 0x25, 0x18, 0x21, 0x7E, 0x81, 0x60,
+            0x59, 0x7E,
+        ]
+        .as_ref()
+        .into();
+        let mut codec = AshCodec::default();
+
+        assert!(matches!(
+            codec.decode(&mut buf),
+            Ok(Some(Frame::Data { .. }))
+        ));
+        assert!(matches!(
+            codec.decode(&mut buf),
+            Ok(Some(Frame::Ack { ack_num, .. })) if *ack_num == 1
+        ));
+        assert_eq!(buf.len(), 0);
+    }
+
+    #[test]
+    fn it_counts_the_bytes_dropped_due_to_framing_errors() {
+        let mut buf: BytesMut = [0xFF, 0xFF, 0x1A, 0xFF, 0x18, 0x25, 0x7E].as_ref().into();
+        let mut codec = AshCodec::default();
+
+        assert!(matches!(codec.decode(&mut buf), Ok(None)));
+        assert_eq!(codec.bytes_discarded(), 7);
+
+        buf.put_slice([0xFF, 0x18].as_ref());
+        assert!(matches!(codec.decode(&mut buf), Ok(None)));
+        assert_eq!(codec.bytes_discarded(), 9);
+    }
 }
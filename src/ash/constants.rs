@@ -1,18 +1,57 @@
+/// Marks the end of a frame. The byte after it starts the next frame.
 pub const FLAG_BYTE: u8 = 0x7E;
+/// Replaces a byte that was received with a framing or overrun error. The
+/// frame it appears in is discarded up to the next flag byte.
 pub const SUB_BYTE: u8 = 0x18;
+/// Terminates a frame in progress. The bytes before it are discarded, and the
+/// next byte starts a new frame.
 pub const CANCEL_BYTE: u8 = 0x1A;
+/// Precedes a reserved byte inside a frame, which is then sent with bit 5
+/// flipped.
 pub const ESCAPE_BYTE: u8 = 0x7D;
+/// Resumes transmission under software flow control.
+pub const XON_BYTE: u8 = 0x11;
+/// Pauses transmission under software flow control.
+pub const XOFF_BYTE: u8 = 0x13;
+/// Sent by the host to wake a sleeping NCP, outside of any frame.
+pub const WAKE_BYTE: u8 = 0xFF;
 
-pub const RESERVED_BYTES: [u8; 6] = [FLAG_BYTE, ESCAPE_BYTE, 0x11, 0x13, SUB_BYTE, CANCEL_BYTE];
+/// The bytes that have a meaning of their own on the line, and must be
+/// escaped when they appear inside a frame.
+pub const RESERVED_BYTES: [u8; 6] = [
+    FLAG_BYTE,
+    ESCAPE_BYTE,
+    XON_BYTE,
+    XOFF_BYTE,
+    SUB_BYTE,
+    CANCEL_BYTE,
+];
 
+/// Reset code in RSTACK and ERROR frames for a reset of unknown cause.
 pub const RESET_UNKNOWN: u8 = 0x00;
+/// Reset code for a reset through the NCP's reset line.
 pub const RESET_EXTERNAL: u8 = 0x01;
+/// Reset code for the NCP powering on.
 pub const RESET_POWERON: u8 = 0x02;
+/// Reset code for the NCP's watchdog timer expiring.
 pub const RESET_WATCHDOG: u8 = 0x03;
+/// Reset code for a failed assertion in the NCP firmware.
 pub const RESET_ASSERT: u8 = 0x06;
+/// Reset code for a reset into, or out of, the bootloader.
 pub const RESET_BOOTLOADER: u8 = 0x09;
+/// Reset code for a reset requested by software.
 pub const RESET_SOFTWARE: u8 = 0x0B;
+/// Error code for too many ACK timeouts in a row.
 pub const ERROR_MAX_ACK_TIMEOUT: u8 = 0x51;
+/// The first error code free for uses outside the ASH specification, such as
+/// the bridge's own failures.
 pub const ERROR_CUSTOM: u8 = 0x80;
 
+/// The version of ASH reported in RSTACK and ERROR frames.
 pub const ASH_VERSION_2: u8 = 0x02;
+
+/// First byte of the pseudo-random sequence DATA frame bodies are XORed with.
+pub const RANDOM_SEED: u8 = 0x42;
+/// Feedback applied by the LFSR generating the pseudo-random sequence when
+/// the bit shifted out is set.
+pub const RANDOM_FEEDBACK: u8 = 0xB8;
@@ -6,16 +6,36 @@ use super::frame::Frame;
 
 #[derive(Debug, Error)]
 pub enum Error {
-    #[error("A frame was received with an invalid checksum: {0}")]
+    #[error("Checksum mismatch in {}", describe(.0))]
     InvalidChecksum(Frame),
-    #[error("A frame was received with an invalid data field: {0}")]
+    #[error("Invalid data field in {}", describe(.0))]
     InvalidDataField(Frame),
-    #[error("An IO error occurred")]
+    #[error("An IO error occurred ({:?}): {0}", .0.kind())]
     Io(#[from] IoError),
     #[error("An unknown frame type was encountered")]
     UnknownFrame,
     #[error("An error occurred while sending a frame")]
-    Channel(#[from] SendError<Frame>)
+    Channel(#[from] SendError<Frame>),
+}
+
+/// Name the kind of a frame, and its frame number for DATA frames, which is
+/// all that is needed to find it in a capture.
+fn describe(frame: &Frame) -> String {
+    match frame {
+        Frame::Data { frm_num, .. } => format!("DATA frame (frm_num={})", **frm_num),
+        frame => format!("{} frame", frame.kind()),
+    }
+}
+
+impl Error {
+    /// Whether the error is a frame from the host that failed validation,
+    /// rather than a failure of the connection to the host.
+    pub fn is_invalid_frame(&self) -> bool {
+        matches!(
+            self,
+            Error::InvalidChecksum(_) | Error::InvalidDataField(_) | Error::UnknownFrame
+        )
+    }
 }
 
 impl PartialEq for Error {
@@ -25,3 +45,45 @@ impl PartialEq for Error {
 }
 
 pub type Result<T> = StdResult<T, Error>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::ErrorKind;
+
+    #[test]
+    fn it_names_the_frame_that_failed_validation() {
+        let data = Frame::data(
+            3.try_into().unwrap(),
+            false,
+            0.try_into().unwrap(),
+            Default::default(),
+        );
+
+        assert_eq!(
+            Error::InvalidChecksum(data).to_string(),
+            "Checksum mismatch in DATA frame (frm_num=3)"
+        );
+        assert_eq!(
+            Error::InvalidDataField(Frame::rst_ack(2, 0)).to_string(),
+            "Invalid data field in RSTACK frame"
+        );
+    }
+
+    #[test]
+    fn it_tells_invalid_frames_apart_from_connection_errors() {
+        assert!(Error::InvalidChecksum(Frame::rst()).is_invalid_frame());
+        assert!(Error::UnknownFrame.is_invalid_frame());
+        assert!(!Error::from(IoError::from(ErrorKind::BrokenPipe)).is_invalid_frame());
+    }
+
+    #[test]
+    fn it_includes_the_io_error_kind() {
+        let err = Error::from(IoError::new(ErrorKind::ConnectionReset, "peer went away"));
+
+        assert_eq!(
+            err.to_string(),
+            "An IO error occurred (ConnectionReset): peer went away"
+        );
+    }
+}
@@ -0,0 +1,116 @@
+use bytes::{BufMut, BytesMut};
+
+use super::constants::{ESCAPE_BYTE, RESERVED_BYTES};
+
+/// The bit flipped in a reserved byte when it is escaped.
+const ESCAPE_MASK: u8 = 0x20;
+
+/// Write `data` into `buf`, replacing each reserved byte with an escape byte
+/// followed by the reserved byte with bit 5 flipped.
+///
+/// `buf` is grown up front to fit the escaped data, which is up to twice as
+/// long as `data`.
+pub fn escape_reserved_bytes(data: &[u8], buf: &mut BytesMut) {
+    let reserved = data.iter().filter(|b| RESERVED_BYTES.contains(b)).count();
+    buf.reserve(data.len() + reserved);
+    for &byte in data {
+        if RESERVED_BYTES.contains(&byte) {
+            buf.put_u8(ESCAPE_BYTE);
+            buf.put_u8(byte ^ ESCAPE_MASK);
+        } else {
+            buf.put_u8(byte);
+        }
+    }
+}
+
+/// Undo `escape_reserved_bytes`, returning the original data.
+///
+/// The data can only shrink, so the buffer is sized once up front and
+/// written to in place, then truncated to the unescaped length.
+///
+/// An escape byte at the very end of `data` has nothing to escape, and is
+/// dropped.
+pub fn unescape_reserved_bytes(data: &[u8]) -> BytesMut {
+    let mut buf = BytesMut::zeroed(data.len());
+    let mut len = 0;
+    let mut bytes = data.iter();
+    while let Some(&byte) = bytes.next() {
+        buf[len] = if byte != ESCAPE_BYTE {
+            byte
+        } else if let Some(&escaped) = bytes.next() {
+            unescape_byte(escaped)
+        } else {
+            break;
+        };
+        len += 1;
+    }
+    buf.truncate(len);
+    buf
+}
+
+/// Recover the original value of a byte that followed an escape byte.
+pub fn unescape_byte(byte: u8) -> u8 {
+    byte ^ ESCAPE_MASK
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_escapes_reserved_bytes() {
+        let mut buf = BytesMut::new();
+        escape_reserved_bytes(&[0x01, 0x7E, 0x7D, 0x11, 0x13, 0x18, 0x1A, 0x02], &mut buf);
+
+        assert_eq!(
+            *buf,
+            [0x01, 0x7D, 0x5E, 0x7D, 0x5D, 0x7D, 0x31, 0x7D, 0x33, 0x7D, 0x38, 0x7D, 0x3A, 0x02]
+        );
+    }
+
+    #[test]
+    fn it_reserves_room_for_every_byte_to_be_escaped() {
+        let mut buf = BytesMut::new();
+        escape_reserved_bytes(&RESERVED_BYTES, &mut buf);
+
+        assert_eq!(buf.len(), RESERVED_BYTES.len() * 2);
+        assert!(buf.capacity() >= RESERVED_BYTES.len() * 2);
+    }
+
+    #[test]
+    fn it_unescapes_a_whole_buffer_without_touching_it() {
+        let data = [0x01, 0x7E, 0x02, 0x7D, 0x11];
+        let mut escaped = BytesMut::new();
+        escape_reserved_bytes(&data, &mut escaped);
+        let copy = escaped.clone();
+
+        assert_eq!(*unescape_reserved_bytes(&escaped), data);
+        assert_eq!(escaped, copy);
+    }
+
+    #[test]
+    fn it_removes_every_escape_byte() {
+        let escaped = [0x7D, 0x5E, 0x01, 0x7D, 0x31, 0x7D, 0x5D, 0x02];
+        let escapes = escaped.iter().filter(|&&b| b == ESCAPE_BYTE).count();
+
+        let data = unescape_reserved_bytes(&escaped);
+
+        assert_eq!(data.len(), escaped.len() - escapes);
+        assert_eq!(*data, [0x7E, 0x01, 0x11, 0x7D, 0x02]);
+    }
+
+    #[test]
+    fn it_drops_a_trailing_escape_byte() {
+        assert_eq!(*unescape_reserved_bytes(&[0x01, 0x7D]), [0x01]);
+    }
+
+    #[test]
+    fn it_unescapes_what_it_escapes() {
+        for byte in RESERVED_BYTES {
+            let mut buf = BytesMut::new();
+            escape_reserved_bytes(&[byte], &mut buf);
+
+            assert_eq!(unescape_byte(buf[1]), byte);
+        }
+    }
+}
@@ -1,5 +1,7 @@
 mod parsers;
 #[cfg(test)]
+mod proptests;
+#[cfg(test)]
 mod tests;
 
 pub use parsers::ParseError;
@@ -10,15 +12,16 @@ use self::parsers::{
 };
 use super::{
     checksum::{crc_digester, frame_checksum},
-    constants::{ESCAPE_BYTE, FLAG_BYTE, RESERVED_BYTES},
+    constants::{FLAG_BYTE, RANDOM_FEEDBACK, RANDOM_SEED},
     error::Error as AshError,
+    escaping::escape_reserved_bytes,
     FrameNumber,
 };
 use bytes::{Buf, BufMut, BytesMut};
 use nom::{branch::alt, combinator::consumed, Err, IResult, Needed};
 use std::{fmt::Display, iter::successors};
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Frame {
     Data {
         frm_num: FrameNumber,
@@ -47,6 +50,30 @@ pub enum Frame {
     },
 }
 
+/// The type of a frame, as determined by its control byte.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameKind {
+    Data,
+    Ack,
+    Nak,
+    Rst,
+    RstAck,
+    Error,
+}
+
+impl Display for FrameKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            FrameKind::Data => "DATA",
+            FrameKind::Ack => "ACK",
+            FrameKind::Nak => "NAK",
+            FrameKind::Rst => "RST",
+            FrameKind::RstAck => "RSTACK",
+            FrameKind::Error => "ERROR",
+        })
+    }
+}
+
 impl Display for Frame {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -104,6 +131,10 @@ impl Frame {
         }
     }
 
+    pub fn rst() -> Frame {
+        Frame::Rst
+    }
+
     pub fn rst_ack(version: u8, code: u8) -> Frame {
         Frame::RstAck { version, code }
     }
@@ -112,9 +143,37 @@ impl Frame {
         Frame::Error { version, code }
     }
 
+    /// Classify a frame from its control byte alone, without parsing the rest
+    /// of the frame.
+    pub fn kind(&self) -> FrameKind {
+        match self {
+            Frame::Data { .. } => FrameKind::Data,
+            Frame::Ack { .. } => FrameKind::Ack,
+            Frame::Nak { .. } => FrameKind::Nak,
+            Frame::Rst => FrameKind::Rst,
+            Frame::RstAck { .. } => FrameKind::RstAck,
+            Frame::Error { .. } => FrameKind::Error,
+        }
+    }
+
+    pub fn peek_type(control_byte: u8) -> Option<FrameKind> {
+        match control_byte {
+            0x00..=0x7F => Some(FrameKind::Data),
+            0x80..=0x9F => Some(FrameKind::Ack),
+            0xA0..=0xBF => Some(FrameKind::Nak),
+            0xC0 => Some(FrameKind::Rst),
+            0xC1 => Some(FrameKind::RstAck),
+            0xC2 => Some(FrameKind::Error),
+            _ => None,
+        }
+    }
+
     /// Try to parse a frame from the given buffer
     pub fn parse(input: &[u8]) -> IResult<&[u8], Frame, ParseError> {
         let mut crc = crc_digester();
+        // The control byte is escaped along with the rest of the frame
+        let (rest, unescaped) = frame_data_and_flag(input).map_err(Err::Incomplete)?;
+        let offset = input.len() - rest.len();
         let control_byte_res = consumed(alt((
             data_control_byte,
             ack_control_byte,
@@ -122,17 +181,20 @@ impl Frame {
             rst_control_byte,
             rst_ack_control_byte,
             error_control_byte,
-        )))(&input[..]);
+        )))(&unescaped[..]);
         let (i2, (ctrl, mut frame)) = match control_byte_res {
             Ok(v) => v,
             Err(_) => {
-                let (rest, _) = frame_data_and_flag(input).map_err(Err::Incomplete)?;
-                return Err(Err::Failure(ParseError::new(rest, AshError::UnknownFrame)));
+                return Err(Err::Failure(ParseError::new(
+                    rest,
+                    offset,
+                    AshError::UnknownFrame,
+                )));
             }
         };
         crc.update(ctrl);
 
-        let (rest, mut data_and_checksum) = frame_data_and_flag(i2).map_err(Err::Incomplete)?;
+        let mut data_and_checksum = BytesMut::from(i2);
 
         let mut checksum_bytes: BytesMut;
         if let Needed::Size(s) = frame.data_len() {
@@ -140,6 +202,7 @@ impl Frame {
             if data_and_checksum.len() != size {
                 return Err(Err::Failure(ParseError::new(
                     rest,
+                    offset,
                     AshError::InvalidDataField(frame),
                 )));
             }
@@ -148,6 +211,7 @@ impl Frame {
             if data_and_checksum.len() < 2 {
                 return Err(Err::Failure(ParseError::new(
                     rest,
+                    offset,
                     AshError::InvalidDataField(frame),
                 )));
             }
@@ -158,6 +222,7 @@ impl Frame {
         if crc.finalize() != checksum {
             return Err(Err::Failure(ParseError::new(
                 rest,
+                offset,
                 AshError::InvalidChecksum(frame),
             )));
         }
@@ -184,17 +249,17 @@ impl Frame {
     }
 
     /// Serialize the frame and write it into a buffer
+    ///
+    /// The checksum covers the frame before it is escaped, then the control
+    /// byte, data and checksum are all escaped together.
     pub fn serialize(&self, buf: &mut BytesMut) {
-        buf.put_u8(self.flag());
-        self.serialize_data(buf);
+        let mut frame = BytesMut::new();
+        frame.put_u8(self.flag());
+        self.serialize_data(&mut frame);
+        let checksum = frame_checksum(&frame);
+        frame.put_u16(checksum);
 
-        let checksum = frame_checksum(buf);
-        for mut byte in checksum.to_be_bytes() {
-            if RESERVED_BYTES.contains(&byte) {
-                byte ^= 0x20;
-            }
-            buf.put_u8(byte);
-        }
+        escape_reserved_bytes(&frame, buf);
         buf.put_u8(FLAG_BYTE);
     }
 
@@ -234,16 +299,9 @@ impl Frame {
     fn serialize_data(&self, buf: &mut BytesMut) {
         match self {
             Frame::Data { body, .. } => {
-                buf.reserve(body.len());
-
-                for (byte, seq) in body.iter().zip(rand_seq()) {
-                    let mut res = byte ^ seq;
-                    if RESERVED_BYTES.contains(&res) {
-                        res ^= 0x20;
-                        buf.put_u8(ESCAPE_BYTE);
-                    }
-                    buf.put_u8(res);
-                }
+                let start = buf.len();
+                buf.extend_from_slice(body);
+                randomize(&mut buf[start..]);
             }
             Frame::RstAck { version, code } => {
                 buf.reserve(2);
@@ -260,6 +318,55 @@ impl Frame {
     }
 }
 
-fn rand_seq() -> impl Iterator<Item = u8> {
-    successors(Some(0x42), |b| Some((b >> 1) ^ ((b & 0x01) * 0xB8)))
+/// Apply the ASH pseudo-random sequence to a DATA frame body in place.
+///
+/// The sequence is XORed with the data, so this both randomizes and
+/// derandomizes a body.
+pub fn randomize(data: &mut [u8]) {
+    #[cfg(test)]
+    if let Some(seq) = RAND_SEQ_OVERRIDE.with(|seq| seq.borrow().clone()) {
+        xor_with(data, seq.into_iter().cycle());
+        return;
+    }
+    xor_with(data, rand_seq());
+}
+
+fn xor_with(data: &mut [u8], seq: impl Iterator<Item = u8>) {
+    for (byte, seq) in data.iter_mut().zip(seq) {
+        *byte ^= seq;
+    }
+}
+
+#[cfg(test)]
+thread_local! {
+    static RAND_SEQ_OVERRIDE: std::cell::RefCell<Option<Vec<u8>>> = Default::default();
+}
+
+/// Run `f` with `seq`, repeated as often as needed, in place of the
+/// pseudo-random sequence on this thread, so tests can predict the bytes of a
+/// DATA frame body on the wire. A sequence of `[0x00]` leaves bodies as they
+/// are.
+#[cfg(test)]
+pub(crate) fn with_rand_seq<R>(seq: &[u8], f: impl FnOnce() -> R) -> R {
+    /// Puts back the sequence that was in place, even if `f` panics.
+    struct Restore(Option<Vec<u8>>);
+
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            let previous = self.0.take();
+            RAND_SEQ_OVERRIDE.with(|seq| *seq.borrow_mut() = previous);
+        }
+    }
+
+    let previous = RAND_SEQ_OVERRIDE.with(|current| current.replace(Some(seq.to_vec())));
+    let _restore = Restore(previous);
+    f()
+}
+
+/// The ASH pseudo-random sequence, generated by an 8-bit LFSR starting from
+/// `RANDOM_SEED`.
+pub(crate) fn rand_seq() -> impl Iterator<Item = u8> {
+    successors(Some(RANDOM_SEED), |b| {
+        Some((b >> 1) ^ ((b & 0x01) * RANDOM_FEEDBACK))
+    })
 }
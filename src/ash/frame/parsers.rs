@@ -1,9 +1,10 @@
 use super::Frame;
 use crate::ash::{
     constants::{ESCAPE_BYTE, FLAG_BYTE},
+    escaping::unescape_reserved_bytes,
     Error as AshError, FrameNumber,
 };
-use bytes::{BufMut, BytesMut};
+use bytes::BytesMut;
 use nom::{
     bytes::streaming::tag,
     combinator::map_opt,
@@ -11,18 +12,28 @@ use nom::{
     sequence::{preceded, tuple},
     IResult, Needed,
 };
+use std::fmt::Display;
 
 type ParserResult<'a, T> = IResult<&'a [u8], T>;
 
+/// A frame that was read in full but failed to parse.
 #[derive(Debug)]
 pub struct ParseError<'a> {
+    /// The input following the bad frame.
     pub input: &'a [u8],
+    /// The offset into the parsed buffer at which `input` starts, just past
+    /// the flag byte ending the bad frame.
+    pub offset: usize,
     pub error: AshError,
 }
 
 impl<'a> ParseError<'a> {
-    pub fn new(input: &'a [u8], error: AshError) -> ParseError<'a> {
-        ParseError { input, error }
+    pub fn new(input: &'a [u8], offset: usize, error: AshError) -> ParseError<'a> {
+        ParseError {
+            input,
+            offset,
+            error,
+        }
     }
 
     pub fn into_inner(self) -> (&'a [u8], AshError) {
@@ -30,6 +41,18 @@ impl<'a> ParseError<'a> {
     }
 }
 
+impl Display for ParseError<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} (the frame ends at offset {})",
+            self.error, self.offset
+        )
+    }
+}
+
+impl std::error::Error for ParseError<'_> {}
+
 pub fn data_control_byte(input: &[u8]) -> ParserResult<Frame> {
     use nom::bits::bits;
     use nom::bits::streaming::{bool, tag, take};
@@ -92,7 +115,7 @@ pub fn nak_control_byte(input: &[u8]) -> ParserResult<Frame> {
 
 pub fn rst_control_byte(input: &[u8]) -> ParserResult<Frame> {
     let (rest, _) = tag([0xC0])(input)?;
-    Ok((rest, Frame::Rst))
+    Ok((rest, Frame::rst()))
 }
 
 pub fn rst_ack_control_byte(input: &[u8]) -> ParserResult<Frame> {
@@ -120,27 +143,16 @@ pub fn error_control_byte(input: &[u8]) -> ParserResult<Frame> {
 /// Parses bytes until an unescaped Flag byte is reached, consuming the flag
 /// byte. Parser will unescape bytes that are preceded by an Escape byte.
 pub fn frame_data_and_flag(input: &[u8]) -> Result<(&[u8], BytesMut), Needed> {
-    let mut collector = BytesMut::new();
     let mut i = 0;
-
-    while let Some(j) = input[i..]
-        .iter()
-        .position(|&b| b == FLAG_BYTE || b == ESCAPE_BYTE)
-    {
-        collector.extend_from_slice(&input[i..i + j]);
-        i += j;
-        if input[i] == FLAG_BYTE {
-            return Ok((&input[i + 1..], collector));
-        }
-        i += 1;
-        if input[i..].len() >= 1 {
-            collector.put_u8(input[i] ^ 0x20);
-            i += 1;
-        } else {
-            return Err(Needed::new(1));
+    loop {
+        match input.get(i) {
+            Some(&FLAG_BYTE) => return Ok((&input[i + 1..], unescape_reserved_bytes(&input[..i]))),
+            // The byte following an escape byte is always data
+            Some(&ESCAPE_BYTE) => i += 2,
+            Some(_) => i += 1,
+            None => return Err(Needed::new(1)),
         }
     }
-    Err(Needed::new(1))
 }
 
 #[cfg(test)]
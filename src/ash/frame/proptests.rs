@@ -0,0 +1,73 @@
+use crate::ash::{
+    constants::{ESCAPE_BYTE, RESERVED_BYTES},
+    frame::{randomize, Frame},
+    FrameNumber,
+};
+use bytes::BytesMut;
+use nom::Needed;
+use proptest::{collection::vec, prelude::*, sample::select};
+
+fn frame_number() -> impl Strategy<Value = FrameNumber> {
+    (0u8..8).prop_map(FrameNumber::new_truncate)
+}
+
+/// DATA frame bodies, weighted towards bodies that randomize to reserved
+/// bytes and so need escaping.
+fn body() -> impl Strategy<Value = BytesMut> {
+    let wire_byte = prop_oneof![any::<u8>(), select(RESERVED_BYTES.to_vec())];
+    vec(wire_byte, 0..=128).prop_map(|wire| {
+        let mut body = BytesMut::from(&wire[..]);
+        randomize(&mut body);
+        body
+    })
+}
+
+/// Frames other than DATA, which have a fixed amount of data.
+fn fixed_frame() -> impl Strategy<Value = Frame> {
+    prop_oneof![
+        (any::<bool>(), frame_number()).prop_map(|(n_rdy, ack_num)| Frame::ack(n_rdy, ack_num)),
+        (any::<bool>(), frame_number()).prop_map(|(n_rdy, ack_num)| Frame::nak(n_rdy, ack_num)),
+        Just(Frame::rst()),
+        (any::<u8>(), any::<u8>()).prop_map(|(version, code)| Frame::rst_ack(version, code)),
+        (any::<u8>(), any::<u8>()).prop_map(|(version, code)| Frame::error(version, code)),
+    ]
+}
+
+fn frame() -> impl Strategy<Value = Frame> {
+    prop_oneof![
+        (frame_number(), any::<bool>(), frame_number(), body())
+            .prop_map(|(frm_num, re_tx, ack_num, body)| Frame::data(frm_num, re_tx, ack_num, body)),
+        fixed_frame(),
+    ]
+}
+
+proptest! {
+    #[test]
+    fn it_parses_what_it_serializes(frame in frame()) {
+        let mut buf = BytesMut::new();
+        frame.serialize(&mut buf);
+
+        let (rest, mut parsed) = Frame::parse(&buf).expect("Expected the frame to parse");
+        prop_assert!(rest.is_empty());
+        // The parser leaves DATA bodies randomized
+        if let Frame::Data { body, .. } = &mut parsed {
+            randomize(body);
+        }
+        prop_assert_eq!(parsed, frame);
+    }
+
+    #[test]
+    fn it_serializes_fixed_frames_to_their_data_len(frame in fixed_frame()) {
+        let mut buf = BytesMut::new();
+        frame.serialize(&mut buf);
+
+        let data_len = match frame.data_len() {
+            Needed::Size(size) => size.get(),
+            Needed::Unknown => unreachable!(),
+        };
+        // Each escaped byte adds an escape byte, on top of the control and
+        // flag bytes
+        let escapes = buf.iter().filter(|&&b| b == ESCAPE_BYTE).count();
+        prop_assert_eq!(buf.len(), data_len + 2 + escapes);
+    }
+}
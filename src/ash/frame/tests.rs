@@ -1,13 +1,20 @@
-use crate::ash::{frame::Frame, FrameNumber};
+use crate::ash::{
+    constants::RESERVED_BYTES,
+    frame::{rand_seq, randomize, with_rand_seq, Frame, FrameKind},
+    Error, FrameNumber,
+};
 use bytes::BytesMut;
 use nom::{Err, Needed};
 
 #[test]
 fn it_rejects_an_unknown_frame_type() {
-    let buf = [0xFF];
+    let buf = [0xFF, 0x7E];
     let res = Frame::parse(&buf).unwrap_err();
 
-    assert!(matches!(res, Err::Error(_)));
+    assert!(matches!(
+        res,
+        Err::Failure(err) if matches!(err.error, Error::UnknownFrame)
+    ));
 }
 
 #[test]
@@ -18,6 +25,27 @@ fn it_rejects_a_early_terminated_frame() {
     assert!(res.is_err());
 }
 
+#[test]
+fn it_describes_where_a_bad_frame_ends() {
+    // An ACK frame with a bad checksum, followed by a valid one
+    let buf = [0x81, 0x60, 0x58, 0x7E, 0x81, 0x60, 0x59, 0x7E];
+    let err = match Frame::parse(&buf) {
+        Err(Err::Failure(err)) => err,
+        res => panic!("Expected the frame to fail to parse, got {:?}", res),
+    };
+
+    assert_eq!(err.offset, 4);
+    assert_eq!(err.input, &buf[4..]);
+    assert!(matches!(
+        err.error,
+        Error::InvalidChecksum(Frame::Ack { .. })
+    ));
+    assert_eq!(
+        err.to_string(),
+        "Checksum mismatch in ACK frame (the frame ends at offset 4)"
+    );
+}
+
 #[test]
 fn it_parses_a_valid_data_frame() {
     let buf = [0x25, 0x00, 0x00, 0x00, 0x02, 0x1A, 0xAD, 0x7E];
@@ -78,7 +106,7 @@ fn it_parses_a_valid_rst_ack_frame() {
 
 #[test]
 fn it_parses_a_valid_error_frame() {
-    let buf = [0xC2, 0x02, 0x51, 0xA8, 0xBD, 0x7E];
+    let buf = [0xC2, 0x02, 0x52, 0x98, 0xDE, 0x7E];
     let (rest, frame) = Frame::parse(&buf).unwrap();
 
     assert_eq!(rest.len(), 0);
@@ -96,12 +124,12 @@ fn it_serializes_control_bytes_correctly() {
     assert_eq!(data_frame.flag(), 0x25);
 
     let ack_frame = Frame::ack(false, FrameNumber::new_truncate(6));
-    assert_eq!(ack_frame.flag(), 0x8E);
+    assert_eq!(ack_frame.flag(), 0x86);
 
     let nak_frame = Frame::nak(true, FrameNumber::new_truncate(5));
     assert_eq!(nak_frame.flag(), 0xAD);
 
-    let rst_frame = Frame::Rst;
+    let rst_frame = Frame::rst();
     assert_eq!(rst_frame.flag(), 0xC0);
 
     let rst_ack_frame = Frame::rst_ack(0x02, 0x02);
@@ -127,7 +155,7 @@ fn it_returns_correct_data_field_lens() {
     let nak_frame = Frame::nak(true, FrameNumber::new_truncate(6));
     assert!(matches!(nak_frame.data_len(), Needed::Size(size) if size.get() == 2));
 
-    let rst_frame = Frame::Rst;
+    let rst_frame = Frame::rst();
     assert!(matches!(rst_frame.data_len(), Needed::Size(size) if size.get() == 2));
 
     let rst_ack_frame = Frame::rst_ack(0x02, 0x02);
@@ -159,7 +187,7 @@ fn it_serializes_the_data_field_correctly() {
     nak_frame.serialize_data(&mut buf);
     assert_eq!(buf.len(), 0);
 
-    let rst_frame = Frame::Rst;
+    let rst_frame = Frame::rst();
     buf = BytesMut::new();
     rst_frame.serialize_data(&mut buf);
     assert_eq!(buf.len(), 0);
@@ -174,3 +202,130 @@ fn it_serializes_the_data_field_correctly() {
     error_frame.serialize_data(&mut buf);
     assert_eq!(*buf, [0x02, 0x52]);
 }
+
+#[test]
+fn it_classifies_frames_by_control_byte() {
+    for byte in 0x00..=0x7F {
+        assert_eq!(Frame::peek_type(byte), Some(FrameKind::Data));
+    }
+    for byte in 0x80..=0x9F {
+        assert_eq!(Frame::peek_type(byte), Some(FrameKind::Ack));
+    }
+    for byte in 0xA0..=0xBF {
+        assert_eq!(Frame::peek_type(byte), Some(FrameKind::Nak));
+    }
+    assert_eq!(Frame::peek_type(0xC0), Some(FrameKind::Rst));
+    assert_eq!(Frame::peek_type(0xC1), Some(FrameKind::RstAck));
+    assert_eq!(Frame::peek_type(0xC2), Some(FrameKind::Error));
+    for byte in 0xC3..=0xFF {
+        assert_eq!(Frame::peek_type(byte), None);
+    }
+}
+
+#[test]
+fn it_classifies_serialized_frames_consistently_with_the_parser() {
+    let frames = [
+        (
+            Frame::data(
+                FrameNumber::new_truncate(2),
+                false,
+                FrameNumber::new_truncate(5),
+                BytesMut::new(),
+            ),
+            FrameKind::Data,
+        ),
+        (
+            Frame::ack(false, FrameNumber::new_truncate(6)),
+            FrameKind::Ack,
+        ),
+        (
+            Frame::nak(true, FrameNumber::new_truncate(5)),
+            FrameKind::Nak,
+        ),
+        (Frame::rst(), FrameKind::Rst),
+        (Frame::rst_ack(0x02, 0x02), FrameKind::RstAck),
+        (Frame::error(0x02, 0x52), FrameKind::Error),
+    ];
+    for (frame, kind) in frames {
+        assert_eq!(Frame::peek_type(frame.flag()), Some(kind));
+    }
+}
+
+#[test]
+fn it_serializes_a_rst_frame() {
+    let mut buf = BytesMut::new();
+    Frame::rst().serialize(&mut buf);
+
+    assert_eq!(*buf, [0xC0, 0x38, 0xBC, 0x7E]);
+}
+
+#[test]
+fn it_generates_the_ash_pseudo_random_sequence() {
+    let seq: Vec<u8> = rand_seq().take(10).collect();
+    assert_eq!(
+        seq,
+        [0x42, 0x21, 0xA8, 0x54, 0x2A, 0x15, 0xB2, 0x59, 0x94, 0x4A]
+    );
+}
+
+#[test]
+fn it_uses_an_injected_sequence_in_place_of_the_pseudo_random_sequence() {
+    let mut data = [0x00, 0x00, 0x00];
+    with_rand_seq(&[0x01, 0x02], || randomize(&mut data));
+    assert_eq!(data, [0x01, 0x02, 0x01]);
+
+    // The pseudo-random sequence is back once the closure returns
+    let mut data = [0x00];
+    randomize(&mut data);
+    assert_eq!(data, [0x42]);
+}
+
+#[test]
+fn it_escapes_reserved_bytes_in_a_body_left_as_is() {
+    let frame = Frame::data(
+        FrameNumber::new_truncate(0),
+        false,
+        FrameNumber::new_truncate(0),
+        BytesMut::from(&[0x7E, 0x01][..]),
+    );
+    let mut buf = BytesMut::new();
+    with_rand_seq(&[0x00], || frame.serialize(&mut buf));
+
+    assert_eq!(buf[..4], [0x00, 0x7D, 0x5E, 0x01]);
+}
+
+#[test]
+fn it_round_trips_a_data_frame_that_randomizes_to_reserved_bytes() {
+    // A body that randomizes to every reserved byte, under a control byte
+    // that is itself reserved.
+    let body: BytesMut = RESERVED_BYTES
+        .iter()
+        .zip(rand_seq())
+        .map(|(byte, seq)| byte ^ seq)
+        .collect();
+    let frame = Frame::data(
+        FrameNumber::new_truncate(1),
+        false,
+        FrameNumber::new_truncate(1),
+        body.clone(),
+    );
+    let mut buf = BytesMut::new();
+    frame.serialize(&mut buf);
+
+    let (rest, parsed) = Frame::parse(&buf).expect("Expected the frame to parse");
+    assert!(rest.is_empty());
+    match parsed {
+        Frame::Data {
+            frm_num,
+            ack_num,
+            body: mut parsed_body,
+            ..
+        } => {
+            randomize(&mut parsed_body);
+            assert_eq!(*frm_num, 1);
+            assert_eq!(*ack_num, 1);
+            assert_eq!(parsed_body, body);
+        }
+        frame => panic!("Expected a DATA frame, got {}", frame),
+    }
+}
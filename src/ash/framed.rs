@@ -0,0 +1,134 @@
+use super::{codec::AshCodec, frame::Frame, Error, Result};
+use futures::{ready, Sink, SinkExt, Stream, StreamExt};
+use std::{
+    mem,
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_util::codec::{Decoder, Framed};
+
+/// A connection carrying ASH frames.
+///
+/// Frames that fail validation are yielded as an `Err` item, like any other
+/// decoding error, and the stream carries on with the next frame. `Framed`
+/// stops decoding after an error until more bytes arrive, so the frames that
+/// are already buffered behind an invalid one are decoded here instead, and
+/// the end `Framed` reports after the error is skipped.
+#[derive(Debug)]
+pub struct AshStream<T> {
+    inner: Framed<T, AshCodec>,
+    /// Set once `inner` has yielded a frame that failed validation, until it
+    /// has ended the stream in response.
+    after_invalid_frame: bool,
+}
+
+pub fn create_ash_stream<T: AsyncRead + AsyncWrite>(inner: T) -> AshStream<T> {
+    AshStream {
+        inner: Framed::with_capacity(inner, AshCodec::default(), 2048),
+        after_invalid_frame: false,
+    }
+}
+
+impl<T> AshStream<T> {
+    /// Decode the next frame already in the read buffer, without reading from
+    /// the connection.
+    fn decode_buffered(&mut self) -> Result<Option<Frame>> {
+        let mut buf = mem::take(self.inner.read_buffer_mut());
+        let res = self.inner.codec_mut().decode(&mut buf);
+        *self.inner.read_buffer_mut() = buf;
+        res
+    }
+}
+
+impl<T: AsyncRead + Unpin> Stream for AshStream<T> {
+    type Item = Result<Frame>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            if self.after_invalid_frame {
+                match self.decode_buffered() {
+                    Ok(None) => {}
+                    res => return Poll::Ready(res.transpose()),
+                }
+            }
+            let item = ready!(self.inner.poll_next_unpin(cx));
+            let after_invalid_frame = mem::replace(
+                &mut self.after_invalid_frame,
+                matches!(&item, Some(Err(e)) if e.is_invalid_frame()),
+            );
+            return Poll::Ready(match item {
+                None if after_invalid_frame => continue,
+                item => item,
+            });
+        }
+    }
+}
+
+impl<T: AsyncWrite + Unpin> Sink<Frame> for AshStream<T> {
+    type Error = Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.inner.poll_ready_unpin(cx)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: Frame) -> Result<()> {
+        self.inner.start_send_unpin(item)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.inner.poll_flush_unpin(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.inner.poll_close_unpin(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::{
+        io::{duplex, AsyncWriteExt},
+        time::timeout,
+    };
+
+    #[tokio::test]
+    async fn it_decodes_a_buffered_frame_after_an_invalid_one_without_more_input() {
+        let (mut host, bridge) = duplex(64);
+        let mut frames = create_ash_stream(bridge);
+        // An ACK with a bad checksum, then the same ACK intact, after which
+        // the host sends nothing more but keeps the connection open
+        host.write_all(&[0x81, 0x60, 0x58, 0x7E, 0x81, 0x60, 0x59, 0x7E])
+            .await
+            .expect("Expected to write to the bridge");
+
+        let res = timeout(Duration::from_millis(100), frames.next()).await;
+        assert!(matches!(
+            res,
+            Ok(Some(Err(Error::InvalidChecksum(Frame::Ack { .. }))))
+        ));
+        let res = timeout(Duration::from_millis(100), frames.next()).await;
+        assert!(matches!(res, Ok(Some(Ok(Frame::Ack { .. })))));
+    }
+
+    #[tokio::test]
+    async fn it_reads_on_after_an_invalid_frame() {
+        let (mut host, bridge) = duplex(64);
+        let mut frames = create_ash_stream(bridge);
+        host.write_all(&[0x81, 0x60, 0x58, 0x7E])
+            .await
+            .expect("Expected to write to the bridge");
+        assert!(matches!(frames.next().await, Some(Err(_))));
+
+        host.write_all(&[0x81, 0x60, 0x59, 0x7E])
+            .await
+            .expect("Expected to write to the bridge");
+        let res = timeout(Duration::from_millis(100), frames.next()).await;
+        assert!(matches!(res, Ok(Some(Ok(Frame::Ack { .. })))));
+
+        drop(host);
+        assert!(frames.next().await.is_none());
+    }
+}
@@ -2,19 +2,17 @@ mod checksum;
 mod codec;
 mod constants;
 mod error;
+mod escaping;
 mod frame;
+mod framed;
 mod protocol;
 mod types;
 
+pub use constants::*;
 pub use error::{Error, Result};
-use tokio::io::{AsyncRead, AsyncWrite};
-use tokio_util::codec::Framed;
+pub use frame::{randomize, Frame, FrameKind};
+pub use framed::{create_ash_stream, AshStream};
+pub use protocol::{
+    create_ash_stream_task, AshStreamOptions, AshStreamTask, ResetResult, StreamError, StreamResult,
+};
 pub use types::FrameNumber;
-
-use self::codec::AshCodec;
-
-pub type AshStream<T> = Framed<T, AshCodec>;
-
-pub fn create_ash_stream<T: AsyncRead + AsyncWrite>(inner: T) -> AshStream<T> {
-    Framed::with_capacity(inner, AshCodec::default(), 2048)
-}
\ No newline at end of file
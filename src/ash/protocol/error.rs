@@ -0,0 +1,77 @@
+use std::result::Result as StdResult;
+use thiserror::Error;
+
+use crate::{ash::Error as AshError, spi::Error as SpiError};
+
+#[derive(Debug, Error)]
+pub enum StreamError {
+    #[error("The stream has been closed")]
+    Closed,
+    #[error("The host has disconnected")]
+    HostDisconnected,
+    #[error("The host has been idle for too long")]
+    IdleTimeout,
+    #[error("A frame could not be read from or written to the host")]
+    Frame(#[from] AshError),
+    #[error("The NCP failed to process a request")]
+    Ncp(#[from] SpiError),
+    #[error(transparent)]
+    Unexpected(#[from] anyhow::Error),
+}
+
+impl StreamError {
+    /// Whether the error was caused by either end of the stream going away,
+    /// including a host that has gone quiet, rather than a fault in the
+    /// protocol or the NCP.
+    pub fn is_disconnect(&self) -> bool {
+        matches!(
+            self,
+            StreamError::Closed | StreamError::HostDisconnected | StreamError::IdleTimeout
+        )
+    }
+}
+
+pub type StreamResult<T> = StdResult<T, StreamError>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ash::Result as AshResult;
+
+    fn decode() -> AshResult<()> {
+        Err(AshError::UnknownFrame)
+    }
+
+    #[test]
+    fn it_propagates_frame_errors_with_a_single_question_mark() {
+        fn in_stream() -> StreamResult<()> {
+            decode()?;
+            Ok(())
+        }
+        fn in_anyhow() -> anyhow::Result<()> {
+            decode()?;
+            Ok(())
+        }
+
+        assert!(matches!(
+            in_stream(),
+            Err(StreamError::Frame(AshError::UnknownFrame))
+        ));
+        assert_eq!(
+            in_anyhow().unwrap_err().downcast_ref::<AshError>(),
+            Some(&AshError::UnknownFrame)
+        );
+    }
+
+    #[test]
+    fn it_keeps_anyhow_errors_unexpected() {
+        fn in_stream() -> StreamResult<()> {
+            Err(anyhow::anyhow!("Frame type not yet implemented"))?;
+            Ok(())
+        }
+
+        let err = in_stream().unwrap_err();
+        assert!(matches!(err, StreamError::Unexpected(_)));
+        assert_eq!(err.to_string(), "Frame type not yet implemented");
+    }
+}
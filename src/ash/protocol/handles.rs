@@ -1,38 +1,122 @@
+use super::error::{StreamError, StreamResult};
+use super::peekable::PeekableStream;
+use super::reader::FrameReader;
+use super::stream::{ErrorRequest, ResetResult};
 use crate::ash::frame::Frame;
 use crate::ash::Error;
-use anyhow::{bail, Context, Result};
 use bytes::BytesMut;
-use futures::{Sink, SinkExt, Stream, StreamExt, TryStreamExt};
-use std::pin::Pin;
-use tokio::sync::mpsc::{Receiver, Sender, UnboundedReceiver, UnboundedSender};
+use futures::{future::poll_fn, ready, FutureExt, Sink, Stream, TryStreamExt};
+use std::{
+    collections::VecDeque,
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tokio::select;
+use tokio::sync::mpsc::{error::TrySendError, Receiver, Sender};
 use tokio::sync::oneshot::{channel as oneshot_channel, Sender as OneshotSender};
+use tokio::time::{sleep_until, Instant};
+use tracing::{debug, warn};
+
+/// The most frames discarded after a reset before the host is suspected of
+/// being stuck in a reset loop.
+const MAX_QUIET_RST_DISCARDS: usize = 3;
+
+/// Something the connected state machine needs to act on.
+pub(crate) enum Event {
+    /// A frame, or a frame that failed validation, was received from the host.
+    Frame(Result<Frame, Error>),
+    /// Data was sent by the bridge for delivery to the host.
+    Data(BytesMut),
+    /// The bridge has room for more data from the host again.
+    Ready,
+    /// The bridge has hit an error that the host must recover from with a
+    /// reset. The sender is told once the ERROR frame has been written.
+    Error(u8, OneshotSender<()>),
+    /// A DATA frame sent to the host has gone unacknowledged for too long.
+    AckTimeout,
+    /// DATA from the host has waited as long as it may for an
+    /// acknowledgement to be piggybacked on a response.
+    AckDue,
+}
+
+/// Frames on their way to the host, queued while the connection to the host
+/// cannot keep up.
+struct FrameWriter {
+    sink: Pin<Box<dyn Sink<Frame, Error = Error> + Send>>,
+    queued: VecDeque<Frame>,
+    /// Whether frames have been handed to the sink without being flushed.
+    unflushed: bool,
+}
+
+impl FrameWriter {
+    fn new(sink: impl Sink<Frame, Error = Error> + Send + 'static) -> FrameWriter {
+        FrameWriter {
+            sink: Box::pin(sink),
+            queued: VecDeque::new(),
+            unflushed: false,
+        }
+    }
+
+    /// Whether there are frames that have not been written out yet.
+    fn is_pending(&self) -> bool {
+        !self.queued.is_empty() || self.unflushed
+    }
+
+    /// Hand the queued frames to the sink and flush them, in as far as the
+    /// sink accepts them.
+    ///
+    /// Frames only leave the queue once the sink has accepted them, so the
+    /// write can be abandoned and picked up again later without losing any.
+    fn poll_write(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        while !self.queued.is_empty() {
+            ready!(self.sink.as_mut().poll_ready(cx))?;
+            if let Some(frame) = self.queued.pop_front() {
+                self.sink.as_mut().start_send(frame)?;
+                self.unflushed = true;
+            }
+        }
+        if self.unflushed {
+            ready!(self.sink.as_mut().poll_flush(cx))?;
+            self.unflushed = false;
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    /// Wait until every queued frame has been written out.
+    async fn flush(&mut self) -> Result<(), Error> {
+        poll_fn(|cx| self.poll_write(cx)).await
+    }
+}
+
+type FrameStream = FrameReader<Pin<Box<dyn Stream<Item = Result<Frame, Error>> + Send>>>;
 
 pub struct AshStreamTaskHandles {
-    read: Pin<Box<dyn Stream<Item = Result<Result<Frame, Error>, Error>>>>,
-    write: Pin<Box<dyn Sink<Frame, Error = Error>>>,
-    peeked: Option<Result<Result<Frame, Error>, Error>>,
-    inbox: UnboundedReceiver<BytesMut>,
-    outbox: UnboundedSender<BytesMut>,
-    reset: Sender<OneshotSender<u8>>,
-    error: Receiver<u8>,
+    read: PeekableStream<FrameStream>,
+    write: FrameWriter,
+    /// The most frames queued for the host before it is told that no more
+    /// DATA can be accepted.
+    write_queue_depth: usize,
+    inbox: Receiver<BytesMut>,
+    outbox: Sender<BytesMut>,
+    reset: Sender<OneshotSender<ResetResult>>,
+    error: Receiver<ErrorRequest>,
 }
 
 impl AshStreamTaskHandles {
     pub(crate) fn new(
-        reader: impl Stream<Item = Result<Result<Frame, Error>, Error>> + 'static,
-        writer: impl Sink<Frame, Error = Error> + 'static,
-        inbox: UnboundedReceiver<BytesMut>,
-        outbox: UnboundedSender<BytesMut>,
-        reset: Sender<OneshotSender<u8>>,
-        error: Receiver<u8>,
+        reader: impl Stream<Item = Result<Frame, Error>> + Send + 'static,
+        writer: impl Sink<Frame, Error = Error> + Send + 'static,
+        inbox: Receiver<BytesMut>,
+        outbox: Sender<BytesMut>,
+        reset: Sender<OneshotSender<ResetResult>>,
+        error: Receiver<ErrorRequest>,
+        write_queue_depth: usize,
     ) -> AshStreamTaskHandles {
-        let read =
-            Box::pin(reader) as Pin<Box<dyn Stream<Item = Result<Result<Frame, Error>, Error>>>>;
-        let write = Box::pin(writer) as Pin<Box<dyn Sink<Frame, Error = Error>>>;
+        let read = PeekableStream::new(FrameReader::new(Box::pin(reader) as Pin<Box<_>>));
         AshStreamTaskHandles {
             read,
-            write,
-            peeked: None,
+            write: FrameWriter::new(writer),
+            write_queue_depth: write_queue_depth.max(1),
             inbox,
             outbox,
             reset,
@@ -40,64 +124,143 @@ impl AshStreamTaskHandles {
         }
     }
 
-    async fn get_next_frame(&mut self) -> Result<Option<Result<Frame, Error>>, Error> {
-        if let Some(res) = self.peeked.take() {
-            return Some(res).transpose();
+    pub(crate) async fn receive_frame(&mut self) -> StreamResult<Result<Frame, Error>> {
+        // Keep writing to the host while waiting for its next frame
+        while self.write.is_pending() {
+            select! {
+                _ = self.read.peek() => break,
+                res = self.write.flush() => res?,
+            }
         }
-        self.read.try_next().await
-    }
-
-    pub(crate) async fn receive_frame(&mut self) -> Result<Result<Frame, Error>> {
         loop {
-            match self.get_next_frame().await? {
-                Some(res) => return Ok(res),
-                None => bail!("Host has disconnected"),
+            select! {
+                res = self.read.try_next() => return match res? {
+                    Some(res) => Ok(res),
+                    None => Err(StreamError::HostDisconnected),
+                },
+                // The host has been sent an ERROR frame already, so there is
+                // nothing more to write
+                Some((_, written)) = self.error.recv() => {
+                    let _ = written.send(());
+                }
             }
         }
     }
 
-    async fn peek_frame(&mut self) -> Option<&Result<Result<Frame, Error>, Error>> {
+    /// Wait for either the next frame from the host or the next piece of data
+    /// from the bridge, whichever arrives first.
+    ///
+    /// If `wait_for_ready` is set, the bridge making room for more data from
+    /// the host is also reported. Data from the bridge is only taken while
+    /// `can_send` is set. Errors raised by the bridge are reported as they
+    /// arrive, and `retransmit_at` and `ack_due`, if set, are reported once
+    /// they pass.
+    ///
+    /// Frames queued for the host are written out in the meantime. While the
+    /// queue is full, no more data is taken from the bridge, and no more
+    /// frames are read from the host once it is over full.
+    pub(crate) async fn next_event(
+        &mut self,
+        wait_for_ready: bool,
+        can_send: bool,
+        retransmit_at: Option<Instant>,
+        ack_due: Option<Instant>,
+    ) -> StreamResult<Event> {
         loop {
-            if self.peeked.is_some() {
-                return self.peeked.as_ref();
-            } else if let Some(item) = self.read.next().await {
-                self.peeked = Some(item);
-            } else {
-                return None;
+            if let Some(res) = self.read.take_peeked() {
+                return Ok(Event::Frame(res?));
+            }
+            let backlogged = self.write_backlogged();
+            let overfull = self.write.queued.len() > self.write_queue_depth;
+            let writing = self.write.is_pending();
+            select! {
+                res = self.read.try_next(), if !overfull => return match res? {
+                    Some(frame) => Ok(Event::Frame(frame)),
+                    None => Err(StreamError::HostDisconnected),
+                },
+                Some(data) = self.inbox.recv(), if can_send && !backlogged => {
+                    return Ok(Event::Data(data))
+                }
+                Ok(_) = self.outbox.reserve(), if wait_for_ready && !backlogged => {
+                    return Ok(Event::Ready)
+                }
+                Some((code, written)) = self.error.recv() => {
+                    return Ok(Event::Error(code, written))
+                }
+                _ = sleep_until(retransmit_at.unwrap_or_else(Instant::now)), if retransmit_at.is_some() => {
+                    return Ok(Event::AckTimeout)
+                }
+                _ = sleep_until(ack_due.unwrap_or_else(Instant::now)), if ack_due.is_some() => {
+                    return Ok(Event::AckDue)
+                }
+                res = self.write.flush(), if writing => res?,
             }
         }
     }
 
-    pub(crate) async fn discard_extra_rst_frames(&mut self) -> Result<()> {
-        while let Some(Ok(res)) = self.peek_frame().await {
+    /// Whether the connection to the host has fallen so far behind that no
+    /// more DATA should be accepted from the host.
+    pub(crate) fn write_backlogged(&self) -> bool {
+        self.write.queued.len() >= self.write_queue_depth
+    }
+
+    /// Discard RST and invalid frames that have already been received,
+    /// without waiting for the host to send anything more.
+    ///
+    /// Returns the number of frames discarded.
+    pub(crate) async fn discard_extra_rst_frames(&mut self) -> StreamResult<usize> {
+        let mut discarded = 0;
+        while let Some(Some(Ok(res))) = self.read.peek().now_or_never() {
             if matches!(res, Err(_) | Ok(Frame::Rst)) {
-                let _ = self.get_next_frame().await;
+                self.read.take_peeked();
+                discarded += 1;
             } else {
                 break;
             }
         }
-        Ok(())
+        debug!(discarded, "Discarded {} frames after a reset", discarded);
+        if discarded > MAX_QUIET_RST_DISCARDS {
+            warn!(
+                discarded,
+                "Discarded {} frames after a reset, the host may be stuck in a reset loop",
+                discarded
+            );
+        }
+        Ok(discarded)
     }
 
-    pub(crate) async fn send_frame(&mut self, item: Frame) -> Result<()> {
-        self.write.as_mut().send(item).await?;
+    /// Queue a frame for the host, writing out as much of the queue as the
+    /// connection to the host takes without waiting.
+    ///
+    /// The rest of the queue is written while waiting for the next event.
+    pub(crate) async fn send_frame(&mut self, item: Frame) -> StreamResult<()> {
+        self.write.queued.push_back(item);
+        if let Some(res) = self.write.flush().now_or_never() {
+            res?;
+        }
         Ok(())
     }
 
-    pub(crate) async fn reset_ncp(&mut self) -> Result<u8> {
+    /// Wait until every frame queued for the host has been written out.
+    pub(crate) async fn flush(&mut self) -> StreamResult<()> {
+        Ok(self.write.flush().await?)
+    }
+
+    /// Ask the bridge to reset the NCP, returning the reset code, or the error
+    /// code if the NCP failed to reset.
+    pub(crate) async fn reset_ncp(&mut self) -> StreamResult<ResetResult> {
         let (tx, rx) = oneshot_channel();
-        self.reset
-            .send(tx)
-            .await
-            .context("Failed to send reset signal to NCP")?;
-        let reset_code = rx
-            .await
-            .context("Unable to receive reset response from NCP")?;
-        Ok(reset_code)
+        self.reset.send(tx).await.map_err(|_| StreamError::Closed)?;
+        rx.await.map_err(|_| StreamError::Closed)
     }
 
-    pub(crate) fn send_data(&mut self, item: BytesMut) -> Result<()> {
-        self.outbox.send(item)?;
-        Ok(())
+    /// Hand data received from the host to the bridge, returning `false` if
+    /// the bridge has fallen behind and has no room for it.
+    pub(crate) fn try_send_data(&mut self, item: BytesMut) -> StreamResult<bool> {
+        match self.outbox.try_send(item) {
+            Ok(()) => Ok(true),
+            Err(TrySendError::Full(_)) => Ok(false),
+            Err(TrySendError::Closed(_)) => Err(StreamError::Closed),
+        }
     }
 }
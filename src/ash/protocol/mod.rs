@@ -1,6 +1,13 @@
+mod error;
 mod handles;
+mod peekable;
+mod reader;
 mod state;
 mod stream;
 mod task;
 #[cfg(test)]
 mod tests;
+
+pub use error::{StreamError, StreamResult};
+pub use stream::ResetResult;
+pub use task::{create_ash_stream_task, AshStreamOptions, AshStreamTask};
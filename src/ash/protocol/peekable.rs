@@ -0,0 +1,93 @@
+use futures::{Stream, StreamExt};
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+/// A stream whose next item can be inspected before it is taken.
+///
+/// Unlike [`futures::stream::Peekable`], peeking only needs a mutable
+/// reference, so the stream can be kept in a struct without pinning it.
+pub struct PeekableStream<S: Stream> {
+    stream: S,
+    peeked: Option<S::Item>,
+}
+
+impl<S: Stream + Unpin> PeekableStream<S> {
+    pub fn new(stream: S) -> PeekableStream<S> {
+        PeekableStream {
+            stream,
+            peeked: None,
+        }
+    }
+
+    /// Wait for the next item, leaving it to be returned by the next call to
+    /// [`StreamExt::next`].
+    ///
+    /// Returns `None` once the stream has ended. This is cancel safe, an item
+    /// received by an abandoned peek is not lost.
+    pub async fn peek(&mut self) -> Option<&S::Item> {
+        if self.peeked.is_none() {
+            self.peeked = self.stream.next().await;
+        }
+        self.peeked.as_ref()
+    }
+
+    /// Take the item that has already been peeked at, if any, without waiting
+    /// for the stream.
+    pub fn take_peeked(&mut self) -> Option<S::Item> {
+        self.peeked.take()
+    }
+}
+
+// The peeked item is never pinned, so it does not need to be `Unpin` itself.
+impl<S: Stream + Unpin> Unpin for PeekableStream<S> {}
+
+impl<S: Stream + Unpin> Stream for PeekableStream<S> {
+    type Item = S::Item;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<S::Item>> {
+        if let Some(item) = self.peeked.take() {
+            return Poll::Ready(Some(item));
+        }
+        self.stream.poll_next_unpin(cx)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let peeked = usize::from(self.peeked.is_some());
+        let (lower, upper) = self.stream.size_hint();
+        (
+            lower.saturating_add(peeked),
+            upper.and_then(|upper| upper.checked_add(peeked)),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::stream::iter;
+
+    #[tokio::test]
+    async fn it_takes_the_peeked_item() {
+        let mut stream = PeekableStream::new(iter([1, 2]));
+
+        assert_eq!(stream.peek().await, Some(&1));
+        assert_eq!(stream.peek().await, Some(&1));
+        assert_eq!(stream.take_peeked(), Some(1));
+        assert_eq!(stream.take_peeked(), None);
+        assert_eq!(stream.next().await, Some(2));
+    }
+
+    #[tokio::test]
+    async fn it_returns_the_peeked_item_next() {
+        let mut stream = PeekableStream::new(iter([1, 2]));
+
+        assert_eq!(stream.peek().await, Some(&1));
+        assert_eq!(stream.next().await, Some(1));
+        assert_eq!(stream.peek().await, Some(&2));
+        assert_eq!(stream.next().await, Some(2));
+        assert_eq!(stream.peek().await, None);
+        assert_eq!(stream.next().await, None);
+    }
+}
@@ -0,0 +1,74 @@
+use crate::ash::{frame::Frame, Error};
+use futures::{ready, Stream, StreamExt};
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+/// Frames read from the host, telling frames that failed validation apart
+/// from failures of the connection itself.
+///
+/// Frames that failed validation are yielded as an `Ok(Err(_))` item, so the
+/// host can be asked to send them again.
+pub(crate) struct FrameReader<S> {
+    inner: S,
+}
+
+impl<S> FrameReader<S> {
+    pub(crate) fn new(inner: S) -> FrameReader<S> {
+        FrameReader { inner }
+    }
+}
+
+impl<S: Stream<Item = Result<Frame, Error>> + Unpin> Stream for FrameReader<S> {
+    type Item = Result<Result<Frame, Error>, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Poll::Ready(match ready!(self.inner.poll_next_unpin(cx)) {
+            Some(Err(e)) if !e.is_invalid_frame() => Some(Err(e)),
+            item => item.map(Ok),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{executor::block_on, stream::iter};
+    use std::io;
+
+    #[test]
+    fn it_reads_on_after_a_frame_fails_validation() {
+        let items = [
+            Err(Error::InvalidChecksum(Frame::ack(
+                false,
+                0.try_into().unwrap(),
+            ))),
+            Ok(Frame::ack(false, 0.try_into().unwrap())),
+        ];
+        let mut reader = FrameReader::new(iter(items));
+
+        block_on(async {
+            assert!(matches!(
+                reader.next().await,
+                Some(Ok(Err(Error::InvalidChecksum(Frame::Ack { .. }))))
+            ));
+            assert!(matches!(
+                reader.next().await,
+                Some(Ok(Ok(Frame::Ack { .. })))
+            ));
+            assert!(reader.next().await.is_none());
+        });
+    }
+
+    #[test]
+    fn it_passes_on_connection_errors() {
+        let items = [Err(Error::Io(io::ErrorKind::BrokenPipe.into()))];
+        let mut reader = FrameReader::new(iter(items));
+
+        block_on(async {
+            assert!(matches!(reader.next().await, Some(Err(Error::Io(_)))));
+            assert!(reader.next().await.is_none());
+        });
+    }
+}
@@ -1,13 +1,15 @@
-use super::handles::AshStreamTaskHandles;
+use super::error::StreamResult;
+use super::handles::{AshStreamTaskHandles, Event};
 use crate::ash::{
-    constants::{ASH_VERSION_2, RESET_POWERON},
-    frame::Frame,
+    constants::{ASH_VERSION_2, ERROR_MAX_ACK_TIMEOUT, RESET_POWERON},
+    frame::{randomize, Frame},
     Error, FrameNumber,
 };
-use anyhow::{bail, Result};
+use anyhow::anyhow;
 use bytes::BytesMut;
-use tokio::select;
-use tracing::{debug, warn};
+use std::{collections::HashMap, mem, time::Duration};
+use tokio::time::Instant;
+use tracing::{debug, info, warn};
 
 pub enum State {
     Failed(FailedState),
@@ -15,28 +17,92 @@ pub enum State {
 }
 
 impl State {
-    pub(crate) fn initial() -> State {
-        State::Failed(FailedState::default())
+    pub(crate) fn initial(
+        ack_window: u8,
+        ack_timeout: Duration,
+        retransmit_timeout: RetransmitTimeout,
+        max_retransmits: u32,
+    ) -> State {
+        State::Failed(FailedState {
+            ack_window,
+            ack_timeout,
+            retransmit_timeout,
+            max_retransmits,
+            ..FailedState::default()
+        })
     }
 
-    pub(crate) async fn process(&mut self, handles: &mut AshStreamTaskHandles) -> Result<()> {
+    pub(crate) async fn process(&mut self, handles: &mut AshStreamTaskHandles) -> StreamResult<()> {
         let res = match self {
             State::Failed(state) => state.process(handles).await?,
             State::Connected(state) => state.process(handles).await?,
         };
-        if let Some(next_state) = res {
-            *self = next_state;
+        if let Some(Transition { next, trigger }) = res {
+            info!(
+                from = self.name(),
+                to = next.name(),
+                trigger,
+                code = next.code(),
+                "ASH session went from {} to {} after {} (code {:#04x})",
+                self.name(),
+                next.name(),
+                trigger,
+                next.code()
+            );
+            *self = next;
         }
         Ok(())
     }
+
+    /// A short name for the state, for logging.
+    pub fn name(&self) -> &'static str {
+        match self {
+            State::Failed(_) => "Failed",
+            State::Connected(_) => "Connected",
+        }
+    }
+
+    /// The reset code reported to the host when connected, or the reason
+    /// reported in ERROR frames when failed.
+    fn code(&self) -> u8 {
+        match self {
+            State::Failed(state) => state.reason,
+            State::Connected(state) => state.reset_code,
+        }
+    }
+
+    pub(crate) fn set_ack_window(&mut self, ack_window: u8) {
+        match self {
+            State::Failed(state) => state.ack_window = ack_window,
+            State::Connected(state) => state.ack_window = ack_window,
+        }
+    }
+}
+
+/// A change of state, and what brought it about.
+struct Transition {
+    next: State,
+    /// The frame or event that caused the change, for logging.
+    trigger: &'static str,
 }
 
 pub struct FailedState {
     pub reason: u8,
+    /// The ACK window to use once the host has reset.
+    ack_window: u8,
+    /// The ACK timeout to use once the host has reset.
+    ack_timeout: Duration,
+    /// The retransmission timeout to start from once the host has reset.
+    retransmit_timeout: RetransmitTimeout,
+    /// The retransmission limit to use once the host has reset.
+    max_retransmits: u32,
 }
 
 impl FailedState {
-    async fn process(&mut self, handles: &mut AshStreamTaskHandles) -> Result<Option<State>> {
+    async fn process(
+        &mut self,
+        handles: &mut AshStreamTaskHandles,
+    ) -> StreamResult<Option<Transition>> {
         // Wait for a RST frame, replying to all other frames with an ERROR
         let frame = handles.receive_frame().await?;
 
@@ -47,8 +113,19 @@ impl FailedState {
             return Ok(None);
         }
 
-        // Send a reset request to the NCP and wait for a response
-        let code = handles.reset_ncp().await?;
+        // Send a reset request to the NCP and wait for a response, staying
+        // failed if the NCP did not come back
+        let code = match handles.reset_ncp().await? {
+            Ok(code) => code,
+            Err(code) => {
+                warn!(code, "NCP failed to reset, replying with ERROR");
+                self.reason = code;
+                handles
+                    .send_frame(Frame::error(ASH_VERSION_2, code))
+                    .await?;
+                return Ok(None);
+            }
+        };
         handles
             .send_frame(Frame::rst_ack(ASH_VERSION_2, code))
             .await?;
@@ -58,7 +135,17 @@ impl FailedState {
         handles.discard_extra_rst_frames().await?;
 
         // Transition to connected
-        Ok(Some(State::Connected(ConnectedState::default())))
+        Ok(Some(Transition {
+            next: State::Connected(ConnectedState {
+                ack_window: self.ack_window,
+                ack_timeout: self.ack_timeout,
+                retransmit_timeout: self.retransmit_timeout.restarted(),
+                max_retransmits: self.max_retransmits,
+                reset_code: code,
+                ..Default::default()
+            }),
+            trigger: "RST frame",
+        }))
     }
 }
 
@@ -66,22 +153,236 @@ impl Default for FailedState {
     fn default() -> Self {
         Self {
             reason: RESET_POWERON,
+            ack_window: MAX_UNACKED_FRAMES,
+            ack_timeout: ACK_TIMEOUT,
+            retransmit_timeout: RetransmitTimeout::default(),
+            max_retransmits: MAX_RETRANSMITS,
         }
     }
 }
 
-#[derive(Default)]
+/// The most DATA frames the host may have outstanding before it must wait
+/// for an acknowledgement.
+pub(crate) const MAX_UNACKED_FRAMES: u8 = 7;
+
+/// How long the host is expected to take to acknowledge a DATA frame, which
+/// is the initial ASH acknowledgement timeout.
+pub(crate) const ACK_TIMEOUT: Duration = Duration::from_millis(1600);
+
+/// The shortest the retransmission timeout adapts down to, as set by ASH.
+pub(crate) const MIN_RETRANSMIT_TIMEOUT: Duration = Duration::from_millis(400);
+
+/// The longest the retransmission timeout adapts up to, as set by ASH.
+pub(crate) const MAX_RETRANSMIT_TIMEOUT: Duration = Duration::from_millis(3200);
+
+/// How long an acknowledgement for DATA from the host waits to be piggybacked
+/// on a response before it is sent on its own, half the shortest time the
+/// host waits for one.
+pub(crate) const PIGGYBACK_TIMEOUT: Duration = Duration::from_millis(200);
+
+/// How long a DATA frame may go unacknowledged before it is sent again, the
+/// ASH `t_rx_ack`.
+///
+/// The timeout adapts to how quickly the host acknowledges DATA, staying
+/// between `min` and `max`. Each round-trip time measured moves it towards
+/// four times the recent round-trip times, as ASH describes, and each time
+/// the host fails to acknowledge a frame in time it doubles.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct RetransmitTimeout {
+    initial: Duration,
+    current: Duration,
+    min: Duration,
+    max: Duration,
+}
+
+impl RetransmitTimeout {
+    /// Start the timeout at `initial`, bounded by `min` and `max`.
+    pub(crate) fn new(initial: Duration, min: Duration, max: Duration) -> RetransmitTimeout {
+        let max = max.max(min);
+        let initial = initial.clamp(min, max);
+        RetransmitTimeout {
+            initial,
+            current: initial,
+            min,
+            max,
+        }
+    }
+
+    /// The current timeout.
+    fn get(&self) -> Duration {
+        self.current
+    }
+
+    /// The same timeout, back at its initial value for a new session.
+    fn restarted(&self) -> RetransmitTimeout {
+        RetransmitTimeout {
+            current: self.initial,
+            ..*self
+        }
+    }
+
+    /// Adapt to the host acknowledging a DATA frame `rtt` after it was sent.
+    fn record_rtt(&mut self, rtt: Duration) {
+        self.current = (self.current * 7 / 8 + rtt / 2).clamp(self.min, self.max);
+    }
+
+    /// Back off after the host failed to acknowledge a DATA frame in time.
+    fn back_off(&mut self) {
+        self.current = (self.current * 2).min(self.max);
+    }
+}
+
+impl Default for RetransmitTimeout {
+    fn default() -> Self {
+        RetransmitTimeout::new(ACK_TIMEOUT, MIN_RETRANSMIT_TIMEOUT, MAX_RETRANSMIT_TIMEOUT)
+    }
+}
+
+/// The most times a DATA frame is sent again for want of an acknowledgement
+/// before the session is failed, as allowed by ASH.
+pub(crate) const MAX_RETRANSMITS: u32 = 4;
+
+/// A DATA frame sent to the host that it has not acknowledged yet.
+struct SentFrame {
+    /// The frame body, kept in case the frame has to be sent again.
+    body: BytesMut,
+    /// When the frame was first sent.
+    first_sent: Instant,
+    /// When the frame was last sent, which its retransmission timer runs
+    /// from.
+    last_sent: Instant,
+    /// The number of times the frame was sent again after going
+    /// unacknowledged.
+    retransmits: u32,
+    /// Whether the frame was sent again for any reason.
+    resent: bool,
+}
+
+/// Counts of the gaps seen in the frame numbers of DATA frames from the host.
+///
+/// A single missing frame is usually a frame lost on the way, while a larger
+/// gap points to the host and the bridge having lost sync.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SequenceGaps {
+    /// Gaps where exactly one frame was skipped.
+    pub dropped: u64,
+    /// Gaps where more than one frame was skipped.
+    pub desynced: u64,
+    /// The largest gap seen, in frames.
+    pub largest: u8,
+}
+
+impl SequenceGaps {
+    fn record(&mut self, gap: u8) {
+        if gap == 1 {
+            self.dropped += 1;
+        } else {
+            self.desynced += 1;
+        }
+        self.largest = self.largest.max(gap);
+    }
+}
+
 pub struct ConnectedState {
+    /// The most DATA frames the host may have awaiting acknowledgement.
+    ack_window: u8,
+    /// How long the host may take to acknowledge a DATA frame before it is
+    /// counted as late.
+    ack_timeout: Duration,
+    /// How long a DATA frame goes unacknowledged before it is sent again.
+    retransmit_timeout: RetransmitTimeout,
+    /// The most times a DATA frame is sent again before the session fails.
+    max_retransmits: u32,
+    /// The reset code reported to the host when the session started.
+    reset_code: u8,
     reject: bool,
-    inflight_frame_number: FrameNumber,
-    acked_frame_number: FrameNumber,
+    /// Whether the host has been told that no more DATA can be accepted.
+    not_ready: bool,
+    /// The frame number expected on the next DATA frame from the host.
+    rx_frame_number: FrameNumber,
+    /// The last acknowledgement number sent to the host.
+    sent_ack_number: FrameNumber,
+    /// When DATA accepted from the host must be acknowledged by, if the
+    /// acknowledgement has not already been piggybacked on a response.
+    ack_due: Option<Instant>,
+    /// The frame number of the next DATA frame sent to the host.
+    tx_frame_number: FrameNumber,
+    /// The last acknowledgement number received from the host.
+    host_ack_number: FrameNumber,
+    /// The DATA frames sent to the host that are awaiting acknowledgement.
+    unacked: HashMap<FrameNumber, SentFrame>,
+    /// Moving average of the time taken for the host to acknowledge DATA.
+    avg_rtt_us: Option<u64>,
+    /// The number of DATA frames the host took longer than `ack_timeout` to
+    /// acknowledge.
+    late_acks: u64,
+    sequence_gaps: SequenceGaps,
+}
+
+impl Default for ConnectedState {
+    fn default() -> Self {
+        Self {
+            ack_window: MAX_UNACKED_FRAMES,
+            ack_timeout: ACK_TIMEOUT,
+            retransmit_timeout: RetransmitTimeout::default(),
+            max_retransmits: MAX_RETRANSMITS,
+            reset_code: RESET_POWERON,
+            reject: false,
+            not_ready: false,
+            rx_frame_number: FrameNumber::default(),
+            sent_ack_number: FrameNumber::default(),
+            ack_due: None,
+            tx_frame_number: FrameNumber::default(),
+            host_ack_number: FrameNumber::default(),
+            unacked: HashMap::new(),
+            avg_rtt_us: None,
+            late_acks: 0,
+            sequence_gaps: SequenceGaps::default(),
+        }
+    }
 }
 
 impl ConnectedState {
-    async fn process(&mut self, handles: &mut AshStreamTaskHandles) -> Result<Option<State>> {
-        select! {
-            Ok(res) = handles.receive_frame() => {
-                self.handle_frame(res, handles).await?;
+    async fn process(
+        &mut self,
+        handles: &mut AshStreamTaskHandles,
+    ) -> StreamResult<Option<Transition>> {
+        // Data for the host waits while the host has a full window of DATA
+        // frames to acknowledge
+        let can_send = self.unacked_tx_frames() < self.ack_window;
+        let retransmit_at = self.next_retransmit();
+        match handles
+            .next_event(self.not_ready, can_send, retransmit_at, self.ack_due)
+            .await?
+        {
+            Event::Frame(frame) => return self.handle_frame(frame, handles).await,
+            Event::Data(body) => self.send_data_frame(body, handles).await?,
+            Event::Ready => {
+                debug!("NCP has caught up, signalling ready");
+                self.not_ready = false;
+                self.send_ack(handles).await?;
+            }
+            Event::Error(code, written) => {
+                warn!(code, "Bridge failed, waiting for the host to reset");
+                // DATA that was accepted is still acknowledged, as the failed
+                // session that follows never will
+                self.send_pending_ack(handles).await?;
+                handles
+                    .send_frame(Frame::error(ASH_VERSION_2, code))
+                    .await?;
+                handles.flush().await?;
+                let _ = written.send(());
+                return Ok(Some(Transition {
+                    next: mem::take(self).into_failed(code),
+                    trigger: "bridge error",
+                }));
+            }
+            Event::AckTimeout => return self.retransmit_overdue_frames(handles).await,
+            Event::AckDue => {
+                self.ack_due = None;
+                if self.send_pending_ack(handles).await? {
+                    debug!("No response to piggyback on, sending a standalone ACK");
+                }
             }
         }
         Ok(None)
@@ -91,7 +392,7 @@ impl ConnectedState {
         &mut self,
         frame: Result<Frame, Error>,
         handles: &mut AshStreamTaskHandles,
-    ) -> Result<()> {
+    ) -> StreamResult<Option<Transition>> {
         match frame {
             Ok(Frame::Data {
                 frm_num,
@@ -102,17 +403,47 @@ impl ConnectedState {
                 self.process_data_frame(frm_num, re_tx, ack_num, body, handles)
                     .await?
             }
+            Ok(Frame::Ack { ack_num, .. }) => self.acknowledge(ack_num),
+            Ok(Frame::Nak { ack_num, .. }) => {
+                debug!(
+                    ack_num = *ack_num,
+                    "Host rejected DATA frames from {}, sending them again", ack_num
+                );
+                self.acknowledge(ack_num);
+                let now = Instant::now();
+                let mut frm_num = self.host_ack_number;
+                while frm_num != self.tx_frame_number {
+                    self.resend(frm_num, now, handles).await?;
+                    frm_num += 1;
+                }
+            }
             Err(
-                Error::InvalidChecksum(Frame::Data { frm_num, .. })
-                | Error::InvalidDataField(Frame::Data { frm_num, .. }),
-            ) => {
-                self.set_reject_condition_and_send_nak(frm_num, handles)
-                    .await?
+                Error::InvalidChecksum(Frame::Data { .. })
+                | Error::InvalidDataField(Frame::Data { .. }),
+            ) => self.set_reject_condition_and_send_nak(handles).await?,
+            // Only the NCP side sends these, so the host is confused. An ERROR
+            // means the host has given up on the session, so wait for it to
+            // reset; anything else is ignored.
+            Ok(Frame::Error { version, code }) => {
+                warn!(
+                    version,
+                    code, "Host sent an ERROR frame with code {:#04x}, waiting for a reset", code
+                );
+                return Ok(Some(Transition {
+                    next: mem::take(self).into_failed(code),
+                    trigger: "ERROR frame",
+                }));
+            }
+            Ok(Frame::RstAck { version, code }) => {
+                warn!(
+                    version,
+                    code, "Ignoring an RSTACK frame with code {:#04x} from the host", code
+                );
             }
             Err(e) => warn!("Received an invalid frame: {}", e),
-            _ => bail!("Frame type not yet implemented"),
+            _ => Err(anyhow!("Frame type not yet implemented"))?,
         };
-        Ok(())
+        Ok(None)
     }
 
     async fn process_data_frame(
@@ -120,11 +451,25 @@ impl ConnectedState {
         frm_num: FrameNumber,
         re_tx: bool,
         ack_num: FrameNumber,
-        body: BytesMut,
+        mut body: BytesMut,
         handles: &mut AshStreamTaskHandles,
-    ) -> Result<()> {
+    ) -> StreamResult<()> {
+        self.acknowledge(ack_num);
+
+        // A retransmission of the last accepted frame means our
+        // acknowledgement was lost, so acknowledge it again without
+        // forwarding the body twice.
+        if re_tx && frm_num + 1 == self.rx_frame_number {
+            self.send_ack(handles).await?;
+            return Ok(());
+        }
         // Check frame number is in sequence
-        if frm_num != self.inflight_frame_number + 1 {
+        if frm_num != self.rx_frame_number {
+            // Frames that follow the first out of sequence frame belong to
+            // the same gap, so it is only recorded once
+            if !self.reject {
+                self.record_sequence_gap(frm_num);
+            }
             debug!(
                 frm_num = *frm_num,
                 re_tx,
@@ -132,16 +477,11 @@ impl ConnectedState {
                 "Rejected DATA frame with out-of-sequence frame number {}",
                 frm_num
             );
-            self.set_reject_condition_and_send_nak(frm_num, handles)
-                .await?;
+            self.set_reject_condition_and_send_nak(handles).await?;
             return Ok(());
         }
         // Check that the host hasn't exceeded the in-flight limit for ACKs
-        if self
-            .inflight_frame_number
-            .abs_diff(*self.acked_frame_number)
-            > 7
-        {
+        if self.unacked_frames() >= self.ack_window {
             debug!(
                 frm_num = *frm_num,
                 re_tx,
@@ -149,27 +489,278 @@ impl ConnectedState {
                 "Rejected DATA frame {} as the in-flight window is full",
                 frm_num
             );
-            self.set_reject_condition_and_send_nak(frm_num, handles)
-                .await?;
+            self.set_reject_condition_and_send_nak(handles).await?;
             return Ok(());
         }
-        self.inflight_frame_number += 1;
+        // The parser leaves the body randomized, undo it before the body is
+        // handed to the NCP.
+        randomize(&mut body);
+        if handles.write_backlogged() || !handles.try_send_data(body)? {
+            // Leave the frame unaccepted so the host retransmits it once the
+            // NCP, or the connection to the host, has caught up, rather than
+            // buffering it here.
+            debug!(
+                frm_num = *frm_num,
+                "Bridge is busy, withholding DATA frame {} and signalling not ready", frm_num
+            );
+            self.not_ready = true;
+            self.send_ack(handles).await?;
+            return Ok(());
+        }
+        self.rx_frame_number += 1;
+        self.clear_reject_condition();
 
-        // Send frame data to outbox
-        handles.send_data(body)?;
-        
-        // Add ACK to
+        // The acknowledgement is piggy-backed on the response DATA frame,
+        // unless none is sent in time
+        self.ack_due
+            .get_or_insert_with(|| Instant::now() + PIGGYBACK_TIMEOUT);
         Ok(())
     }
 
-    async fn set_reject_condition_and_send_nak(
+    async fn send_data_frame(
+        &mut self,
+        body: BytesMut,
+        handles: &mut AshStreamTaskHandles,
+    ) -> StreamResult<()> {
+        let now = Instant::now();
+        self.unacked.insert(
+            self.tx_frame_number,
+            SentFrame {
+                body: body.clone(),
+                first_sent: now,
+                last_sent: now,
+                retransmits: 0,
+                resent: false,
+            },
+        );
+        let frame = Frame::data(self.tx_frame_number, false, self.rx_frame_number, body);
+        self.tx_frame_number += 1;
+        self.sent_ack_number = self.rx_frame_number;
+        handles.send_frame(frame).await
+    }
+
+    /// Send an unacknowledged DATA frame to the host again, restarting its
+    /// retransmission timer.
+    async fn resend(
         &mut self,
         frm_num: FrameNumber,
+        now: Instant,
         handles: &mut AshStreamTaskHandles,
-    ) -> Result<()> {
+    ) -> StreamResult<()> {
+        let body = match self.unacked.get_mut(&frm_num) {
+            Some(sent) => {
+                sent.last_sent = now;
+                sent.resent = true;
+                sent.body.clone()
+            }
+            None => return Ok(()),
+        };
+        let frame = Frame::data(frm_num, true, self.rx_frame_number, body);
+        self.sent_ack_number = self.rx_frame_number;
+        handles.send_frame(frame).await
+    }
+
+    /// When the earliest retransmission timer of the unacknowledged DATA
+    /// frames runs out, if there are any.
+    fn next_retransmit(&self) -> Option<Instant> {
+        let timeout = self.retransmit_timeout.get();
+        self.unacked
+            .values()
+            .map(|sent| sent.last_sent + timeout)
+            .min()
+    }
+
+    /// Send every DATA frame whose retransmission timer has run out again,
+    /// failing the session once a frame has been sent again too many times.
+    ///
+    /// The retransmission timeout backs off before the frames are sent
+    /// again, so their timers run for longer this time.
+    async fn retransmit_overdue_frames(
+        &mut self,
+        handles: &mut AshStreamTaskHandles,
+    ) -> StreamResult<Option<Transition>> {
+        let now = Instant::now();
+        let timeout = self.retransmit_timeout.get();
+        self.retransmit_timeout.back_off();
+        debug!(
+            timeout_ms = self.retransmit_timeout.get().as_millis() as u64,
+            "Retransmission timeout backed off to {:?}",
+            self.retransmit_timeout.get()
+        );
+        let mut frm_num = self.host_ack_number;
+        while frm_num != self.tx_frame_number {
+            let sent = match self.unacked.get_mut(&frm_num) {
+                Some(sent) if sent.last_sent + timeout <= now => sent,
+                _ => {
+                    frm_num += 1;
+                    continue;
+                }
+            };
+            if sent.retransmits >= self.max_retransmits {
+                warn!(
+                    frm_num = *frm_num,
+                    retransmits = sent.retransmits,
+                    "Host did not acknowledge DATA frame {} after {} retransmissions",
+                    frm_num,
+                    sent.retransmits
+                );
+                handles
+                    .send_frame(Frame::error(ASH_VERSION_2, ERROR_MAX_ACK_TIMEOUT))
+                    .await?;
+                return Ok(Some(Transition {
+                    next: mem::take(self).into_failed(ERROR_MAX_ACK_TIMEOUT),
+                    trigger: "ACK timeout",
+                }));
+            }
+            sent.retransmits += 1;
+            debug!(
+                frm_num = *frm_num,
+                retransmits = sent.retransmits,
+                "Host did not acknowledge DATA frame {} in time, sending it again",
+                frm_num
+            );
+            self.resend(frm_num, now, handles).await?;
+            frm_num += 1;
+        }
+        Ok(None)
+    }
+
+    async fn send_ack(&mut self, handles: &mut AshStreamTaskHandles) -> StreamResult<()> {
+        self.sent_ack_number = self.rx_frame_number;
+        handles
+            .send_frame(Frame::ack(self.not_ready, self.rx_frame_number))
+            .await
+    }
+
+    /// Send a standalone ACK if DATA from the host has been accepted without
+    /// being acknowledged, returning whether one was sent.
+    ///
+    /// ACKs that the host has not prompted, such as those sent once a
+    /// piggyback would have taken too long, go through here so that an idle
+    /// link is left quiet.
+    async fn send_pending_ack(&mut self, handles: &mut AshStreamTaskHandles) -> StreamResult<bool> {
+        if self.unacked_frames() == 0 {
+            return Ok(false);
+        }
+        self.send_ack(handles).await?;
+        Ok(true)
+    }
+
+    /// Take note of the host acknowledging every DATA frame before `ack_num`,
+    /// recording the round-trip time of each.
+    ///
+    /// Frames that were sent more than once are left out of the average
+    /// round-trip time, as it is not known which of the copies was
+    /// acknowledged.
+    fn acknowledge(&mut self, ack_num: FrameNumber) {
+        let now = Instant::now();
+        let mut frm_num = self.host_ack_number;
+        while frm_num != ack_num {
+            if let Some(sent) = self.unacked.remove(&frm_num) {
+                let rtt = now.duration_since(sent.first_sent);
+                let rtt_us = rtt.as_micros() as u64;
+                if rtt > self.ack_timeout {
+                    self.late_acks += 1;
+                    warn!(
+                        frm_num = *frm_num,
+                        rtt_us, "Host took {}us to acknowledge DATA frame {}", rtt_us, frm_num
+                    );
+                }
+                debug!(
+                    frm_num = *frm_num,
+                    rtt_us, "DATA frame {} acknowledged after {}us", frm_num, rtt_us
+                );
+                if !sent.resent {
+                    self.avg_rtt_us = Some(match self.avg_rtt_us {
+                        Some(avg) => (avg * 7 + rtt_us) / 8,
+                        None => rtt_us,
+                    });
+                    self.retransmit_timeout.record_rtt(rtt);
+                }
+            }
+            frm_num += 1;
+        }
+        self.host_ack_number = ack_num;
+    }
+
+    /// Take note of a DATA frame arriving ahead of the expected frame number.
+    fn record_sequence_gap(&mut self, frm_num: FrameNumber) {
+        let gap = (*frm_num + 8 - *self.rx_frame_number) % 8;
+        self.sequence_gaps.record(gap);
+        debug!(
+            expected = *self.rx_frame_number,
+            received = *frm_num,
+            gap,
+            "Expected DATA frame {} but received {}, {} frames apart",
+            self.rx_frame_number,
+            frm_num,
+            gap
+        );
+    }
+
+    /// The state to move to when the session can't go on, keeping the
+    /// connection to the host open until it resets.
+    ///
+    /// `reason` is reported in the ERROR frames sent until then.
+    fn into_failed(self, reason: u8) -> State {
+        State::Failed(FailedState {
+            reason,
+            ack_window: self.ack_window,
+            ack_timeout: self.ack_timeout,
+            retransmit_timeout: self.retransmit_timeout,
+            max_retransmits: self.max_retransmits,
+        })
+    }
+
+    /// The reason the NCP last reset, such as power-on or watchdog.
+    pub fn reset_code(&self) -> u8 {
+        self.reset_code
+    }
+
+    /// The average time taken for the host to acknowledge a DATA frame, in
+    /// microseconds, weighted towards recent frames.
+    pub fn avg_rtt_us(&self) -> Option<u64> {
+        self.avg_rtt_us
+    }
+
+    /// The number of DATA frames the host took longer than the ACK timeout
+    /// to acknowledge.
+    pub fn late_acks(&self) -> u64 {
+        self.late_acks
+    }
+
+    /// How long a DATA frame may currently go unacknowledged before it is
+    /// sent again.
+    pub fn retransmit_timeout(&self) -> Duration {
+        self.retransmit_timeout.get()
+    }
+
+    /// The gaps seen in the frame numbers of DATA frames from the host.
+    pub fn sequence_gaps(&self) -> SequenceGaps {
+        self.sequence_gaps
+    }
+
+    /// The number of DATA frames accepted from the host that have not been
+    /// acknowledged yet.
+    fn unacked_frames(&self) -> u8 {
+        (*self.rx_frame_number + 8 - *self.sent_ack_number) % 8
+    }
+
+    /// The number of DATA frames sent to the host that it has not
+    /// acknowledged yet.
+    fn unacked_tx_frames(&self) -> u8 {
+        (*self.tx_frame_number + 8 - *self.host_ack_number) % 8
+    }
+
+    async fn set_reject_condition_and_send_nak(
+        &mut self,
+        handles: &mut AshStreamTaskHandles,
+    ) -> StreamResult<()> {
         if !self.reject {
             self.reject = true;
-            handles.send_frame(Frame::nak(false, frm_num)).await?;
+            handles
+                .send_frame(Frame::nak(self.not_ready, self.rx_frame_number))
+                .await?;
         }
         Ok(())
     }
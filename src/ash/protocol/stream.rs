@@ -1,55 +1,201 @@
-use anyhow::{bail, Result};
+use super::error::{StreamError, StreamResult};
 use bytes::BytesMut;
+use futures::{future::poll_fn, ready, Sink, SinkExt, Stream};
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
 use tokio::select;
-use tokio::sync::mpsc::error::TrySendError;
-use tokio::sync::mpsc::{Receiver, Sender, UnboundedReceiver, UnboundedSender};
-use tokio::sync::oneshot::Sender as OneshotSender;
-use tokio_util::either::Either;
+use tokio::sync::mpsc::{Receiver, Sender};
+use tokio::sync::oneshot::{
+    channel as oneshot_channel, Receiver as OneshotReceiver, Sender as OneshotSender,
+};
+use tokio::time::timeout;
+use tokio_util::{either::Either, sync::PollSender};
+use tracing::debug;
 
+/// The outcome of an NCP reset requested by the host: the reset code to send
+/// in the RSTACK, or the error code to send in an ERROR frame if the NCP
+/// failed to reset.
+pub type ResetResult = Result<u8, u8>;
+
+/// An error code for the protocol task to send the host, and a channel to
+/// report back on once the ERROR frame has been written.
+pub(crate) type ErrorRequest = (u8, OneshotSender<()>);
+
+/// How long closing the stream waits on data from the host that is still on
+/// its way to the bridge.
+const CLOSE_DRAIN_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// The bridge's end of an ASH session.
+///
+/// Besides `receive` and `send`, this is a `Stream` of data and reset
+/// requests from the host, and a `Sink` of data and error codes for the host,
+/// so it can be used with the `futures` adapters. The stream ends once the
+/// protocol task has stopped, and does not apply the idle timeout.
 pub struct AshStream {
-    read: UnboundedReceiver<BytesMut>,
-    reset: Receiver<OneshotSender<u8>>,
-    write: UnboundedSender<BytesMut>,
-    error: Sender<u8>,
+    read: Receiver<BytesMut>,
+    reset: Receiver<OneshotSender<ResetResult>>,
+    write: PollSender<BytesMut>,
+    error: PollSender<ErrorRequest>,
+    idle_timeout: Option<Duration>,
 }
 
 impl AshStream {
     pub(crate) fn new(
-        read: UnboundedReceiver<BytesMut>,
-        reset: Receiver<OneshotSender<u8>>,
-        write: UnboundedSender<BytesMut>,
-        error: Sender<u8>,
+        read: Receiver<BytesMut>,
+        reset: Receiver<OneshotSender<ResetResult>>,
+        write: Sender<BytesMut>,
+        error: Sender<ErrorRequest>,
     ) -> AshStream {
         AshStream {
             read,
             reset,
-            write,
-            error,
+            write: PollSender::new(write),
+            error: PollSender::new(error),
+            idle_timeout: None,
         }
     }
 
-    pub async fn receive(&mut self) -> Result<Either<BytesMut, OneshotSender<u8>>> {
-        select! {
-            biased;
-            Some(reset) = self.reset.recv() => Ok(Either::Right(reset)),
-            Some(frame) = self.read.recv() => Ok(Either::Left(frame)),
-            else => bail!("Stream has been closed")
+    /// Give up on `receive` when the host has sent nothing for `idle_timeout`,
+    /// or wait forever if it is `None`.
+    pub fn set_idle_timeout(&mut self, idle_timeout: Option<Duration>) {
+        self.idle_timeout = idle_timeout;
+    }
+
+    pub async fn receive(&mut self) -> StreamResult<Either<BytesMut, OneshotSender<ResetResult>>> {
+        let next = async {
+            select! {
+                biased;
+                Some(reset) = self.reset.recv() => Ok(Either::Right(reset)),
+                Some(frame) = self.read.recv() => Ok(Either::Left(frame)),
+                else => Err(StreamError::Closed)
+            }
+        };
+        match self.idle_timeout {
+            Some(idle_timeout) => timeout(idle_timeout, next)
+                .await
+                .map_err(|_| StreamError::IdleTimeout)?,
+            None => next.await,
         }
     }
 
-    pub fn send(&mut self, message: Either<BytesMut, u8>) -> Result<()> {
+    /// Send data to the host, or an error code to the protocol task.
+    ///
+    /// Data waits for room in the channel to the protocol task, so a host that
+    /// is slow to drain responses holds up the caller.
+    pub async fn send(&mut self, message: Either<BytesMut, u8>) -> StreamResult<()> {
         match message {
+            // Error codes do not wait for room for data
+            Either::Right(code) => self.send_error(code).await.map(|_| ()),
+            message => SinkExt::send(self, message).await,
+        }
+    }
+
+    /// End the session, telling the host why with an ERROR frame carrying
+    /// `reason` rather than just dropping the connection.
+    ///
+    /// Returns once the ERROR frame has been written to the host, and closes
+    /// the stream after. A session that has already failed has sent the host
+    /// an ERROR frame of its own, so no other is sent.
+    ///
+    /// Data the host sent that the bridge has not received yet is drained and
+    /// discarded, waiting up to a short timeout for it.
+    pub async fn close(&mut self, reason: u8) -> StreamResult<()> {
+        let written = self.send_error(reason).await?;
+        let res = written.await.map_err(|_| StreamError::Closed);
+        self.read.close();
+        let mut drained = 0;
+        let _ = timeout(CLOSE_DRAIN_TIMEOUT, async {
+            while self.read.recv().await.is_some() {
+                drained += 1;
+            }
+        })
+        .await;
+        if drained > 0 {
+            debug!(
+                drained,
+                "Discarded {} messages from the host while closing the stream", drained
+            );
+        }
+        self.reset.close();
+        self.write.close();
+        self.error.close();
+        res
+    }
+
+    /// Hand an error code to the protocol task, waiting for room if another
+    /// is still being handled.
+    ///
+    /// Returns a receiver that resolves once the ERROR frame has been written
+    /// to the host.
+    async fn send_error(&mut self, code: u8) -> StreamResult<OneshotReceiver<()>> {
+        poll_fn(|cx| self.error.poll_reserve(cx))
+            .await
+            .map_err(|_| StreamError::Closed)?;
+        let (written, receiver) = oneshot_channel();
+        self.error
+            .send_item((code, written))
+            .map_err(|_| StreamError::Closed)?;
+        Ok(receiver)
+    }
+}
+
+impl Stream for AshStream {
+    type Item = Either<BytesMut, OneshotSender<ResetResult>>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        // Resets take priority over data, as in `receive`
+        let reset = this.reset.poll_recv(cx);
+        if let Poll::Ready(Some(reset)) = reset {
+            return Poll::Ready(Some(Either::Right(reset)));
+        }
+        match this.read.poll_recv(cx) {
+            Poll::Ready(Some(frame)) => Poll::Ready(Some(Either::Left(frame))),
+            Poll::Ready(None) if reset.is_ready() => Poll::Ready(None),
+            _ => Poll::Pending,
+        }
+    }
+}
+
+impl Sink<Either<BytesMut, u8>> for AshStream {
+    type Error = StreamError;
+
+    /// Wait for room for both data and an error code, as it is not known yet
+    /// which of the two is sent next.
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<StreamResult<()>> {
+        let this = self.get_mut();
+        ready!(this.write.poll_reserve(cx)).map_err(|_| StreamError::Closed)?;
+        ready!(this.error.poll_reserve(cx)).map_err(|_| StreamError::Closed)?;
+        Poll::Ready(Ok(()))
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: Either<BytesMut, u8>) -> StreamResult<()> {
+        let this = self.get_mut();
+        match item {
             Either::Left(frame) => {
-                if let Err(_) = self.write.send(frame) {
-                    bail!("Stream has been closed")
-                }
+                this.error.abort_send();
+                this.write.send_item(frame).map_err(|_| StreamError::Closed)
             }
             Either::Right(code) => {
-                if let Err(TrySendError::Closed(_)) = self.error.try_send(code) {
-                    bail!("Stream has been closed")
-                }
+                // The slot reserved for data is not needed for an error code
+                this.write.abort_send();
+                let (written, _) = oneshot_channel();
+                this.error
+                    .send_item((code, written))
+                    .map_err(|_| StreamError::Closed)
             }
-        };
-        Ok(())
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<StreamResult<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<StreamResult<()>> {
+        self.get_mut().write.close();
+        Poll::Ready(Ok(()))
     }
 }
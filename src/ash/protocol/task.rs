@@ -1,16 +1,76 @@
+use super::error::StreamResult;
 use super::handles::AshStreamTaskHandles;
-use super::state::State;
-use super::stream::AshStream;
+use super::state::{
+    RetransmitTimeout, State, ACK_TIMEOUT, MAX_RETRANSMITS, MAX_RETRANSMIT_TIMEOUT,
+    MAX_UNACKED_FRAMES, MIN_RETRANSMIT_TIMEOUT,
+};
+use super::stream::{AshStream, ErrorRequest, ResetResult};
 use crate::ash::frame::Frame;
 use crate::ash::Error;
-use anyhow::Result;
 use bytes::BytesMut;
 use futures::{Sink, Stream};
-use tokio::sync::mpsc::{
-    channel, unbounded_channel, Receiver, Sender, UnboundedReceiver, UnboundedSender,
-};
+use std::time::Duration;
+use tokio::sync::mpsc::{channel, Receiver, Sender};
 use tokio::sync::oneshot::Sender as OneshotSender;
 
+/// The number of EZSP frames buffered in each direction between the protocol
+/// task and the bridge.
+pub const DATA_CHANNEL_CAPACITY: usize = 4;
+
+/// The number of frames queued for a host that is slow to read them before
+/// it is told that no more DATA can be accepted.
+pub const WRITE_QUEUE_DEPTH: usize = 8;
+
+/// Settings for an ASH session with a host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AshStreamOptions {
+    /// The most DATA frames the host may have awaiting acknowledgement, from
+    /// 1 up to the ASH maximum of 7.
+    pub window_size: u8,
+    /// How long the host may take to acknowledge a DATA frame before it is
+    /// counted as late.
+    pub ack_timeout: Duration,
+    /// How long a DATA frame sent to the host may go unacknowledged before it
+    /// is sent again, at the start of a session. The timeout then adapts to
+    /// how quickly the host acknowledges DATA.
+    pub retransmit_timeout: Duration,
+    /// The shortest the retransmission timeout adapts down to.
+    pub min_retransmit_timeout: Duration,
+    /// The longest the retransmission timeout adapts up to.
+    pub max_retransmit_timeout: Duration,
+    /// The most times a DATA frame is sent again for want of an
+    /// acknowledgement before the session fails and the host has to reset.
+    pub max_retransmits: u32,
+    /// How long `AshStream::receive` waits for the host before giving up, or
+    /// `None` to wait forever.
+    pub idle_timeout: Option<Duration>,
+    /// The number of EZSP frames buffered in each direction between the
+    /// protocol task and the bridge. More than this from the host is held
+    /// back by signalling not ready.
+    pub channel_capacity: usize,
+    /// The number of frames queued for the host, while the connection cannot
+    /// keep up, before the host is told that no more DATA can be accepted.
+    /// A deeper queue rides out longer stalls, at the cost of memory and of
+    /// delaying the frames behind it.
+    pub write_queue_depth: usize,
+}
+
+impl Default for AshStreamOptions {
+    fn default() -> Self {
+        AshStreamOptions {
+            window_size: MAX_UNACKED_FRAMES,
+            ack_timeout: ACK_TIMEOUT,
+            retransmit_timeout: ACK_TIMEOUT,
+            min_retransmit_timeout: MIN_RETRANSMIT_TIMEOUT,
+            max_retransmit_timeout: MAX_RETRANSMIT_TIMEOUT,
+            max_retransmits: MAX_RETRANSMITS,
+            idle_timeout: None,
+            channel_capacity: DATA_CHANNEL_CAPACITY,
+            write_queue_depth: WRITE_QUEUE_DEPTH,
+        }
+    }
+}
+
 pub struct AshStreamTask {
     state: State,
     handles: AshStreamTaskHandles,
@@ -18,16 +78,34 @@ pub struct AshStreamTask {
 
 impl AshStreamTask {
     fn new(
-        reader: impl Stream<Item = Result<Result<Frame, Error>, Error>> + 'static,
-        writer: impl Sink<Frame, Error = Error> + 'static,
-        inbox: UnboundedReceiver<BytesMut>,
-        outbox: UnboundedSender<BytesMut>,
-        reset: Sender<OneshotSender<u8>>,
-        error: Receiver<u8>,
+        reader: impl Stream<Item = Result<Frame, Error>> + Send + 'static,
+        writer: impl Sink<Frame, Error = Error> + Send + 'static,
+        inbox: Receiver<BytesMut>,
+        outbox: Sender<BytesMut>,
+        reset: Sender<OneshotSender<ResetResult>>,
+        error: Receiver<ErrorRequest>,
+        options: &AshStreamOptions,
     ) -> AshStreamTask {
-        let handles = AshStreamTaskHandles::new(reader, writer, inbox, outbox, reset, error);
+        let handles = AshStreamTaskHandles::new(
+            reader,
+            writer,
+            inbox,
+            outbox,
+            reset,
+            error,
+            options.write_queue_depth,
+        );
         AshStreamTask {
-            state: State::initial(),
+            state: State::initial(
+                options.window_size.clamp(1, MAX_UNACKED_FRAMES),
+                options.ack_timeout,
+                RetransmitTimeout::new(
+                    options.retransmit_timeout,
+                    options.min_retransmit_timeout,
+                    options.max_retransmit_timeout,
+                ),
+                options.max_retransmits,
+            ),
             handles,
         }
     }
@@ -36,11 +114,20 @@ impl AshStreamTask {
         &self.state
     }
 
-    pub async fn step(&mut self) -> Result<()> {
+    /// Limit the number of DATA frames the host may have awaiting
+    /// acknowledgement, from 1 up to the ASH maximum of 7.
+    ///
+    /// Further DATA frames are rejected with a NAK until the window has room.
+    pub fn set_ack_window(&mut self, ack_window: u8) {
+        self.state
+            .set_ack_window(ack_window.clamp(1, MAX_UNACKED_FRAMES));
+    }
+
+    pub async fn step(&mut self) -> StreamResult<()> {
         self.state.process(&mut self.handles).await
     }
 
-    pub async fn run(&mut self) -> Result<()> {
+    pub async fn run(&mut self) -> StreamResult<()> {
         loop {
             self.step().await?;
         }
@@ -48,14 +135,25 @@ impl AshStreamTask {
 }
 
 pub fn create_ash_stream_task(
-    reader: impl Stream<Item = Result<Result<Frame, Error>, Error>> + 'static,
-    writer: impl Sink<Frame, Error = Error> + 'static,
+    reader: impl Stream<Item = Result<Frame, Error>> + Send + 'static,
+    writer: impl Sink<Frame, Error = Error> + Send + 'static,
+    options: AshStreamOptions,
 ) -> (AshStreamTask, AshStream) {
-    let (write, inbox) = unbounded_channel();
-    let (outbox, read) = unbounded_channel();
+    let capacity = options.channel_capacity.max(1);
+    let (write, inbox) = channel(capacity);
+    let (outbox, read) = channel(capacity);
     let (reset_sender, reset) = channel(1);
     let (error, error_receiver) = channel(1);
-    let task = AshStreamTask::new(reader, writer, inbox, outbox, reset_sender, error_receiver);
-    let stream = AshStream::new(read, reset, write, error);
+    let task = AshStreamTask::new(
+        reader,
+        writer,
+        inbox,
+        outbox,
+        reset_sender,
+        error_receiver,
+        &options,
+    );
+    let mut stream = AshStream::new(read, reset, write, error);
+    stream.set_idle_timeout(options.idle_timeout);
     (task, stream)
 }
@@ -1,32 +1,59 @@
 use crate::{
     ash::{
-        constants::{ASH_VERSION_2, RESET_POWERON},
-        frame::Frame,
-        protocol::{state::State, task::create_ash_stream_task}, Error,
+        constants::{
+            ASH_VERSION_2, ERROR_CUSTOM, ERROR_MAX_ACK_TIMEOUT, RESET_BOOTLOADER, RESET_POWERON,
+        },
+        frame::{randomize, Frame},
+        protocol::{
+            handles::AshStreamTaskHandles,
+            state::{State, ACK_TIMEOUT, PIGGYBACK_TIMEOUT},
+            stream::AshStream,
+            task::{
+                create_ash_stream_task, AshStreamOptions, AshStreamTask, DATA_CHANNEL_CAPACITY,
+                WRITE_QUEUE_DEPTH,
+            },
+            StreamError,
+        },
+        Error,
     },
     test::MockTestSink,
 };
-use anyhow::{anyhow, Context};
 use bytes::BytesMut;
-use futures::{stream::iter, TryStreamExt};
+use futures::{
+    executor::block_on,
+    future::ready,
+    stream::{iter, pending},
+    FutureExt, SinkExt, StreamExt,
+};
 use tokio_util::either::Either;
 use std::{
+    io,
     sync::{Arc, Mutex},
     task::Poll,
+    time::Duration,
+};
+use tokio::{
+    join, select, spawn,
+    sync::{
+        mpsc::{channel, unbounded_channel, UnboundedReceiver, UnboundedSender},
+        oneshot::channel as oneshot_channel,
+    },
+    time::timeout,
 };
-use tokio::{spawn, sync::mpsc::unbounded_channel};
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use tokio_util::sync::PollSender;
 
 #[tokio::test]
 async fn it_responds_to_non_rst_frames_with_error_before_reset() {
-    let read_buf = [Ok(Ok(Frame::data(
+    let read_buf = [Ok(Frame::data(
         0.try_into().unwrap(),
         false,
         0.try_into().unwrap(),
         BytesMut::new(),
-    )))];
+    ))];
     let reader = iter(read_buf);
 
-    let (tx, rx) = unbounded_channel();
+    let (tx, mut rx) = unbounded_channel();
     let mut writer = MockTestSink::default();
     writer
         .expect_poll_ready()
@@ -39,7 +66,7 @@ async fn it_responds_to_non_rst_frames_with_error_before_reset() {
         .expect_poll_flush()
         .returning(|_| Poll::Ready(Ok(())));
 
-    let (mut task, _handles) = create_ash_stream_task(reader, writer);
+    let (mut task, _handles) = create_ash_stream_task(reader, writer, AshStreamOptions::default());
 
     let res = task.step().await;
 
@@ -50,7 +77,7 @@ async fn it_responds_to_non_rst_frames_with_error_before_reset() {
 
 #[tokio::test]
 async fn it_responds_to_rst_frame_with_rst_ack() {
-    let read_buf = [Ok(Ok(Frame::Rst))];
+    let read_buf = [Ok(Frame::rst())];
     let reader = iter(read_buf);
 
     let buffer = Arc::new(Mutex::new(Vec::new()));
@@ -60,17 +87,15 @@ async fn it_responds_to_rst_frame_with_rst_ack() {
         .expect_poll_ready()
         .returning(|_| Poll::Ready(Ok(())));
     writer.expect_start_send().returning(move |item| {
-        writer_buffer
-            .lock()
-            .map_err(|_| anyhow!("Mutex was poisoned"))?
-            .push(item);
+        writer_buffer.lock().expect("Mutex was poisoned").push(item);
         Ok(())
     });
     writer
         .expect_poll_flush()
         .returning(|_| Poll::Ready(Ok(())));
 
-    let (mut stream, mut handles) = create_ash_stream_task(reader, writer);
+    let (mut stream, mut handles) =
+        create_ash_stream_task(reader, writer, AshStreamOptions::default());
 
     let task = spawn(async move { stream.step().await.map(|_| stream) });
 
@@ -81,7 +106,7 @@ async fn it_responds_to_rst_frame_with_rst_ack() {
         _ => unreachable!()
     };
     rst_ret
-        .send(RESET_POWERON)
+        .send(Ok(RESET_POWERON))
         .expect("Expected to successfully send reset result");
 
     let stream = task
@@ -89,10 +114,1177 @@ async fn it_responds_to_rst_frame_with_rst_ack() {
         .expect("Expected to successfully join stream task")
         .expect("Expected task execution to succeed");
 
-    assert!(matches!(stream.state(), State::Connected(_)));
+    assert!(matches!(stream.state(), State::Connected(state) if state.reset_code() == RESET_POWERON));
     let lock = buffer.lock().expect("Mutex was poisoned");
     let frame = lock.first().expect("Expected frame to be sent.");
     assert!(
         matches!(frame, Frame::RstAck{ version , code } if *version == ASH_VERSION_2 && *code == RESET_POWERON)
     );
 }
+
+/// Connect a task to a host that sends one more DATA frame than the bridge
+/// has room for, without the bridge ever draining them.
+///
+/// Returns the task, the bridge's end of the stream, and the frames sent to
+/// the host after the RSTACK.
+async fn saturate_bridge(capacity: usize) -> (AshStreamTask, AshStream, UnboundedReceiver<Frame>) {
+    let mut read_buf = vec![Ok(Frame::rst())];
+    for frm_num in 0..=capacity as u8 {
+        read_buf.push(Ok(Frame::data(
+            frm_num.try_into().unwrap(),
+            false,
+            0.try_into().unwrap(),
+            BytesMut::from(&[frm_num][..]),
+        )));
+    }
+    let reader = iter(read_buf).chain(pending());
+
+    let (tx, mut rx) = unbounded_channel();
+    let mut writer = MockTestSink::default();
+    writer
+        .expect_poll_ready()
+        .returning(|_| Poll::Ready(Ok(())));
+    writer.expect_start_send().returning(move |item| {
+        tx.send(item)?;
+        Ok(())
+    });
+    writer
+        .expect_poll_flush()
+        .returning(|_| Poll::Ready(Ok(())));
+
+    let options = AshStreamOptions {
+        channel_capacity: capacity,
+        ..AshStreamOptions::default()
+    };
+    let (mut stream, mut handles) = create_ash_stream_task(reader, writer, options);
+
+    // Step through the reset and every DATA frame
+    let task = spawn(async move {
+        for _ in 0..capacity + 2 {
+            stream.step().await?;
+        }
+        Ok::<_, StreamError>(stream)
+    });
+
+    match handles
+        .receive()
+        .await
+        .expect("Expected to receive reset signal")
+    {
+        Either::Right(ret) => ret
+            .send(Ok(RESET_POWERON))
+            .expect("Expected to successfully send reset result"),
+        _ => unreachable!(),
+    }
+    let stream = task
+        .await
+        .expect("Expected to successfully join stream task")
+        .expect("Expected task execution to succeed");
+
+    let frame = rx.recv().await.expect("Expected RSTACK to be sent");
+    assert!(matches!(frame, Frame::RstAck { .. }));
+    (stream, handles, rx)
+}
+
+#[tokio::test]
+async fn it_signals_not_ready_when_the_bridge_falls_behind() {
+    let (_stream, _handles, mut rx) = saturate_bridge(DATA_CHANNEL_CAPACITY).await;
+
+    let frame = rx.recv().await.expect("Expected ACK to be sent");
+    assert!(
+        matches!(frame, Frame::Ack { n_rdy, ack_num, .. } if n_rdy && *ack_num == DATA_CHANNEL_CAPACITY as u8)
+    );
+}
+
+#[tokio::test]
+async fn it_signals_ready_once_the_bridge_catches_up() {
+    let (mut stream, mut handles, mut rx) = saturate_bridge(DATA_CHANNEL_CAPACITY).await;
+    let _ = rx.recv().await.expect("Expected ACK to be sent");
+
+    let data = handles
+        .receive()
+        .await
+        .expect("Expected to receive data from the host");
+    assert!(matches!(data, Either::Left(_)));
+    stream.step().await.expect("Expected task execution to succeed");
+
+    let frame = rx.recv().await.expect("Expected ACK to be sent");
+    assert!(
+        matches!(frame, Frame::Ack { n_rdy, ack_num, .. } if !n_rdy && *ack_num == DATA_CHANNEL_CAPACITY as u8)
+    );
+}
+
+#[tokio::test]
+async fn it_signals_not_ready_once_a_smaller_channel_is_full() {
+    let (_stream, _handles, mut rx) = saturate_bridge(1).await;
+
+    let frame = rx.recv().await.expect("Expected ACK to be sent");
+    assert!(matches!(frame, Frame::Ack { n_rdy, ack_num, .. } if n_rdy && *ack_num == 1));
+}
+
+#[tokio::test]
+async fn it_signals_not_ready_while_the_host_is_slow_to_read() {
+    let (host, reader) = unbounded_channel();
+    let reader = UnboundedReceiverStream::new(reader);
+    // The connection to the host has room for a single frame until the host
+    // reads it
+    let (tx, mut rx) = channel(1);
+    let writer = PollSender::new(tx).sink_map_err(|_| Error::Io(io::ErrorKind::BrokenPipe.into()));
+    let options = AshStreamOptions {
+        write_queue_depth: 2,
+        ..AshStreamOptions::default()
+    };
+    let (mut stream, mut handles) = create_ash_stream_task(reader, writer, options);
+
+    host.send(Ok(Frame::rst())).unwrap();
+    let (res, _) = join!(stream.step(), async {
+        match handles.receive().await {
+            Ok(Either::Right(ret)) => ret.send(Ok(RESET_POWERON)).unwrap(),
+            _ => unreachable!(),
+        }
+    });
+    res.expect("Expected task execution to succeed");
+
+    // Fill the queue behind the RSTACK, without the host reading anything
+    for body in 0..2u8 {
+        handles
+            .send(Either::Left(BytesMut::from(&[body][..])))
+            .await
+            .expect("Expected to send data to the host");
+        stream
+            .step()
+            .await
+            .expect("Expected task execution to succeed");
+    }
+    host.send(Ok(Frame::data(
+        0.try_into().unwrap(),
+        false,
+        0.try_into().unwrap(),
+        BytesMut::from(&[0x01][..]),
+    )))
+    .unwrap();
+    stream
+        .step()
+        .await
+        .expect("Expected task execution to succeed");
+    assert!(
+        handles.receive().now_or_never().is_none(),
+        "Expected the DATA frame to be withheld"
+    );
+
+    // Once the host catches up, it is told it may send DATA again
+    let read = async {
+        let mut frames = Vec::new();
+        for _ in 0..5 {
+            frames.push(rx.recv().await.expect("Expected a frame to be sent"));
+        }
+        frames
+    };
+    let frames = select! {
+        res = stream.run() => panic!("Expected the task to keep running, got {:?}", res.err()),
+        frames = read => frames,
+    };
+
+    assert!(matches!(frames[0], Frame::RstAck { .. }));
+    assert!(matches!(frames[1], Frame::Data { .. }));
+    assert!(matches!(frames[2], Frame::Data { .. }));
+    assert!(matches!(frames[3], Frame::Ack { n_rdy, ack_num, .. } if n_rdy && *ack_num == 0));
+    assert!(matches!(frames[4], Frame::Ack { n_rdy, ack_num, .. } if !n_rdy && *ack_num == 0));
+}
+
+#[tokio::test(start_paused = true)]
+async fn it_applies_the_idle_timeout_from_the_options() {
+    let options = AshStreamOptions {
+        idle_timeout: Some(Duration::from_secs(5)),
+        ..AshStreamOptions::default()
+    };
+    let (_task, mut handles) = create_ash_stream_task(pending(), MockTestSink::default(), options);
+
+    let res = handles.receive().await;
+
+    assert!(matches!(res, Err(StreamError::IdleTimeout)));
+}
+
+#[tokio::test(start_paused = true)]
+async fn it_times_out_when_the_host_is_idle() {
+    let reader = pending();
+    let writer = MockTestSink::default();
+
+    let (_task, mut handles) = create_ash_stream_task(reader, writer, AshStreamOptions::default());
+    handles.set_idle_timeout(Some(Duration::from_secs(30)));
+
+    let res = handles.receive().await;
+
+    assert!(matches!(res, Err(StreamError::IdleTimeout)));
+}
+
+#[tokio::test]
+async fn it_tracks_the_round_trip_time_of_acknowledged_data() {
+    let (host, reader) = unbounded_channel();
+    let reader = UnboundedReceiverStream::new(reader);
+
+    let (tx, mut rx) = unbounded_channel();
+    let mut writer = MockTestSink::default();
+    writer
+        .expect_poll_ready()
+        .returning(|_| Poll::Ready(Ok(())));
+    writer.expect_start_send().returning(move |item| {
+        tx.send(item)?;
+        Ok(())
+    });
+    writer
+        .expect_poll_flush()
+        .returning(|_| Poll::Ready(Ok(())));
+
+    let (mut stream, mut handles) =
+        create_ash_stream_task(reader, writer, AshStreamOptions::default());
+
+    host.send(Ok(Frame::rst())).unwrap();
+    let (res, _) = join!(stream.step(), async {
+        match handles.receive().await {
+            Ok(Either::Right(ret)) => ret.send(Ok(RESET_POWERON)).unwrap(),
+            _ => unreachable!(),
+        }
+    });
+    res.expect("Expected task execution to succeed");
+
+    handles
+        .send(Either::Left(BytesMut::from(&[0x01][..])))
+        .await
+        .expect("Expected to send data to the host");
+    stream.step().await.expect("Expected task execution to succeed");
+    let rtt = match stream.state() {
+        State::Connected(state) => state.avg_rtt_us(),
+        _ => panic!("Expected task to be connected"),
+    };
+    assert!(rtt.is_none());
+
+    host.send(Ok(Frame::ack(false, 1.try_into().unwrap())))
+        .unwrap();
+    stream.step().await.expect("Expected task execution to succeed");
+    let rtt = match stream.state() {
+        State::Connected(state) => state.avg_rtt_us(),
+        _ => panic!("Expected task to be connected"),
+    };
+    assert!(rtt.is_some());
+
+    let _ = rx.recv().await.expect("Expected RSTACK to be sent");
+    let frame = rx.recv().await.expect("Expected DATA to be sent");
+    assert!(matches!(frame, Frame::Data { frm_num, .. } if *frm_num == 0));
+}
+
+#[tokio::test]
+async fn it_reports_bridge_errors_to_the_host_and_waits_for_a_reset() {
+    let (host, reader) = unbounded_channel();
+    let reader = UnboundedReceiverStream::new(reader);
+
+    let (tx, mut rx) = unbounded_channel();
+    let mut writer = MockTestSink::default();
+    writer
+        .expect_poll_ready()
+        .returning(|_| Poll::Ready(Ok(())));
+    writer.expect_start_send().returning(move |item| {
+        tx.send(item)?;
+        Ok(())
+    });
+    writer
+        .expect_poll_flush()
+        .returning(|_| Poll::Ready(Ok(())));
+
+    let (mut stream, mut handles) =
+        create_ash_stream_task(reader, writer, AshStreamOptions::default());
+
+    host.send(Ok(Frame::rst())).unwrap();
+    let (res, _) = join!(stream.step(), async {
+        match handles.receive().await {
+            Ok(Either::Right(ret)) => ret.send(Ok(RESET_POWERON)).unwrap(),
+            _ => unreachable!(),
+        }
+    });
+    res.expect("Expected task execution to succeed");
+
+    handles
+        .send(Either::Right(RESET_BOOTLOADER))
+        .await
+        .expect("Expected to send the error to the task");
+    stream.step().await.expect("Expected task execution to succeed");
+    assert!(matches!(stream.state(), State::Failed(state) if state.reason == RESET_BOOTLOADER));
+
+    let _ = rx.recv().await.expect("Expected RSTACK to be sent");
+    let frame = rx.recv().await.expect("Expected ERROR to be sent");
+    assert!(matches!(frame, Frame::Error { code, .. } if code == RESET_BOOTLOADER));
+}
+
+#[tokio::test]
+async fn it_replies_with_error_when_the_ncp_fails_to_reset() {
+    let (host, reader) = unbounded_channel();
+    let reader = UnboundedReceiverStream::new(reader);
+
+    let (tx, mut rx) = unbounded_channel();
+    let mut writer = MockTestSink::default();
+    writer
+        .expect_poll_ready()
+        .returning(|_| Poll::Ready(Ok(())));
+    writer.expect_start_send().returning(move |item| {
+        tx.send(item)?;
+        Ok(())
+    });
+    writer
+        .expect_poll_flush()
+        .returning(|_| Poll::Ready(Ok(())));
+
+    let (mut stream, mut handles) =
+        create_ash_stream_task(reader, writer, AshStreamOptions::default());
+
+    host.send(Ok(Frame::rst())).unwrap();
+    let (res, _) = join!(stream.step(), async {
+        match handles.receive().await {
+            Ok(Either::Right(ret)) => ret.send(Err(RESET_BOOTLOADER)).unwrap(),
+            _ => unreachable!(),
+        }
+    });
+    res.expect("Expected task execution to succeed");
+
+    assert!(matches!(stream.state(), State::Failed(state) if state.reason == RESET_BOOTLOADER));
+    let frame = rx.recv().await.expect("Expected ERROR to be sent");
+    assert!(matches!(frame, Frame::Error { code, .. } if code == RESET_BOOTLOADER));
+}
+
+#[tokio::test]
+async fn it_discards_repeated_rst_frames_after_a_reset() {
+    let reader = iter((0..5).map(|_| Ok(Frame::rst())));
+
+    let (tx, mut rx) = unbounded_channel();
+    let mut writer = MockTestSink::default();
+    writer
+        .expect_poll_ready()
+        .returning(|_| Poll::Ready(Ok(())));
+    writer.expect_start_send().returning(move |item| {
+        tx.send(item)?;
+        Ok(())
+    });
+    writer
+        .expect_poll_flush()
+        .returning(|_| Poll::Ready(Ok(())));
+
+    let (mut stream, mut handles) =
+        create_ash_stream_task(reader, writer, AshStreamOptions::default());
+
+    let (res, _) = join!(stream.step(), async {
+        match handles.receive().await {
+            Ok(Either::Right(ret)) => ret.send(Ok(RESET_POWERON)).unwrap(),
+            _ => unreachable!(),
+        }
+    });
+    res.expect("Expected task execution to succeed");
+
+    assert!(matches!(stream.state(), State::Connected(_)));
+    let frame = rx.recv().await.expect("Expected RSTACK to be sent");
+    assert!(matches!(frame, Frame::RstAck { .. }));
+    assert!(rx.try_recv().is_err());
+    // Only the end of the host's frames is left
+    assert!(matches!(
+        stream.step().await,
+        Err(StreamError::HostDisconnected)
+    ));
+}
+
+#[tokio::test]
+async fn it_counts_the_rst_frames_it_discards() {
+    let reader = iter(
+        (0..4)
+            .map(|_| Ok(Frame::rst()))
+            .chain([Ok(Frame::ack(false, 0.try_into().unwrap()))]),
+    );
+    let (_inbox_tx, inbox) = channel(1);
+    let (outbox, _outbox_rx) = channel(1);
+    let (reset, _reset_rx) = channel(1);
+    let (_error_tx, error) = channel(1);
+    let mut handles = AshStreamTaskHandles::new(
+        reader,
+        MockTestSink::default(),
+        inbox,
+        outbox,
+        reset,
+        error,
+        WRITE_QUEUE_DEPTH,
+    );
+
+    let discarded = handles
+        .discard_extra_rst_frames()
+        .await
+        .expect("Expected discarding to succeed");
+
+    assert_eq!(discarded, 4);
+    let frame = handles
+        .receive_frame()
+        .await
+        .expect("Expected a frame to be left");
+    assert!(matches!(frame, Ok(Frame::Ack { .. })));
+}
+
+/// Connect a task to a host through channels, completing the reset.
+///
+/// Returns the task, the bridge's end of the stream, the channel feeding
+/// frames from the host, and the frames sent to the host after the RSTACK.
+async fn connect() -> (
+    AshStreamTask,
+    AshStream,
+    UnboundedSender<Result<Frame, Error>>,
+    UnboundedReceiver<Frame>,
+) {
+    connect_with(AshStreamOptions::default()).await
+}
+
+/// Connect a task to a host like [`connect`], creating the task with
+/// `options`.
+async fn connect_with(
+    options: AshStreamOptions,
+) -> (
+    AshStreamTask,
+    AshStream,
+    UnboundedSender<Result<Frame, Error>>,
+    UnboundedReceiver<Frame>,
+) {
+    let (host, reader) = unbounded_channel();
+    let reader = UnboundedReceiverStream::new(reader);
+
+    let (tx, mut rx) = unbounded_channel();
+    let mut writer = MockTestSink::default();
+    writer
+        .expect_poll_ready()
+        .returning(|_| Poll::Ready(Ok(())));
+    writer.expect_start_send().returning(move |item| {
+        tx.send(item)?;
+        Ok(())
+    });
+    writer
+        .expect_poll_flush()
+        .returning(|_| Poll::Ready(Ok(())));
+
+    let (mut stream, mut handles) = create_ash_stream_task(reader, writer, options);
+
+    host.send(Ok(Frame::rst())).unwrap();
+    let (res, _) = join!(stream.step(), async {
+        match handles.receive().await {
+            Ok(Either::Right(ret)) => ret.send(Ok(RESET_POWERON)).unwrap(),
+            _ => unreachable!(),
+        }
+    });
+    res.expect("Expected task execution to succeed");
+    let _ = rx.recv().await.expect("Expected RSTACK to be sent");
+
+    (stream, handles, host, rx)
+}
+
+#[tokio::test]
+async fn it_waits_for_a_reset_when_the_host_sends_an_error() {
+    let (mut stream, _handles, host, _rx) = connect().await;
+
+    host.send(Ok(Frame::error(ASH_VERSION_2, RESET_BOOTLOADER)))
+        .unwrap();
+    stream.step().await.expect("Expected task execution to succeed");
+
+    assert!(matches!(stream.state(), State::Failed(state) if state.reason == RESET_BOOTLOADER));
+}
+
+#[tokio::test]
+async fn it_logs_each_state_transition_once() {
+    let logs = Arc::new(Mutex::new(Vec::new()));
+    let writer = logs.clone();
+    let subscriber = tracing_subscriber::fmt()
+        .with_ansi(false)
+        .with_writer(move || LogWriter(writer.clone()))
+        .finish();
+    let _guard = tracing::subscriber::set_default(subscriber);
+
+    let (mut stream, _handles, host, _rx) = connect().await;
+    assert_eq!(stream.state().name(), "Connected");
+    host.send(Ok(Frame::error(ASH_VERSION_2, RESET_BOOTLOADER)))
+        .unwrap();
+    stream
+        .step()
+        .await
+        .expect("Expected task execution to succeed");
+    assert_eq!(stream.state().name(), "Failed");
+
+    let logs = String::from_utf8(logs.lock().unwrap().clone()).unwrap();
+    let transitions: Vec<_> = logs
+        .lines()
+        .filter(|line| line.contains("ASH session went from"))
+        .collect();
+    assert_eq!(transitions.len(), 2, "Unexpected transitions in {}", logs);
+    assert!(transitions[0].contains("from Failed to Connected after RST frame"));
+    assert!(transitions[1].contains("from Connected to Failed after ERROR frame"));
+}
+
+/// Collects log output written to it.
+struct LogWriter(Arc<Mutex<Vec<u8>>>);
+
+impl io::Write for LogWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn it_reconnects_when_the_host_resets_after_an_error() {
+    let (mut stream, mut handles, host, mut rx) = connect().await;
+    stream.set_ack_window(2);
+
+    host.send(Ok(Frame::error(ASH_VERSION_2, RESET_BOOTLOADER)))
+        .unwrap();
+    stream
+        .step()
+        .await
+        .expect("Expected task execution to succeed");
+    assert!(matches!(stream.state(), State::Failed(_)));
+
+    host.send(Ok(Frame::rst())).unwrap();
+    let (res, _) = join!(stream.step(), async {
+        match handles.receive().await {
+            Ok(Either::Right(ret)) => ret.send(Ok(RESET_POWERON)).unwrap(),
+            _ => unreachable!(),
+        }
+    });
+    res.expect("Expected task execution to succeed");
+
+    assert!(matches!(stream.state(), State::Connected(_)));
+    let frame = rx.recv().await.expect("Expected RSTACK to be sent");
+    assert!(matches!(frame, Frame::RstAck { code, .. } if code == RESET_POWERON));
+
+    // The ACK window set before the error still applies to the new session
+    for frm_num in 0..3u8 {
+        host.send(Ok(Frame::data(
+            frm_num.try_into().unwrap(),
+            false,
+            0.try_into().unwrap(),
+            BytesMut::from(&[frm_num][..]),
+        )))
+        .unwrap();
+        stream
+            .step()
+            .await
+            .expect("Expected task execution to succeed");
+    }
+    let frame = rx.recv().await.expect("Expected NAK to be sent");
+    assert!(matches!(frame, Frame::Nak { ack_num, .. } if *ack_num == 2));
+}
+
+#[tokio::test]
+async fn it_sends_a_final_error_when_the_stream_is_closed() {
+    let (mut stream, mut handles, _host, mut rx) = connect().await;
+
+    let res = select! {
+        res = stream.run() => panic!("Expected the task to keep running, got {:?}", res.err()),
+        res = handles.close(ERROR_CUSTOM) => res,
+    };
+    res.expect("Expected the stream to close");
+
+    // The ERROR frame has been written by the time the stream is closed
+    let frame = rx.try_recv().expect("Expected ERROR to be sent");
+    assert!(matches!(frame, Frame::Error { code, .. } if code == ERROR_CUSTOM));
+    assert!(matches!(handles.receive().await, Err(StreamError::Closed)));
+}
+
+#[tokio::test]
+async fn it_drains_data_from_the_host_when_the_stream_is_closed() {
+    let (mut stream, mut handles, host, mut rx) = connect().await;
+    host.send(Ok(Frame::data(
+        0.try_into().unwrap(),
+        false,
+        0.try_into().unwrap(),
+        BytesMut::from(&[0x01][..]),
+    )))
+    .unwrap();
+    stream
+        .step()
+        .await
+        .expect("Expected task execution to succeed");
+
+    let res = select! {
+        res = stream.run() => panic!("Expected the task to keep running, got {:?}", res.err()),
+        res = handles.close(ERROR_CUSTOM) => res,
+    };
+    res.expect("Expected the stream to close");
+
+    let frame = rx.try_recv().expect("Expected ACK to be sent");
+    assert!(matches!(frame, Frame::Ack { ack_num, .. } if *ack_num == 1));
+    let frame = rx.try_recv().expect("Expected ERROR to be sent");
+    assert!(matches!(frame, Frame::Error { code, .. } if code == ERROR_CUSTOM));
+    assert!(matches!(handles.receive().await, Err(StreamError::Closed)));
+}
+
+#[tokio::test]
+async fn it_closes_a_failed_stream_without_another_error() {
+    let (mut stream, mut handles, host, mut rx) = connect().await;
+    host.send(Ok(Frame::error(ASH_VERSION_2, RESET_BOOTLOADER)))
+        .unwrap();
+    stream
+        .step()
+        .await
+        .expect("Expected task execution to succeed");
+
+    let res = select! {
+        res = stream.run() => panic!("Expected the task to keep running, got {:?}", res.err()),
+        res = handles.close(ERROR_CUSTOM) => res,
+    };
+    res.expect("Expected the stream to close");
+    assert!(rx.try_recv().is_err());
+}
+
+#[tokio::test]
+async fn it_waits_for_room_to_send_an_error_code() {
+    let (mut stream, mut handles, _host, mut rx) = connect().await;
+
+    handles
+        .send(Either::Right(RESET_BOOTLOADER))
+        .await
+        .expect("Expected to send the error to the task");
+    let res = timeout(
+        Duration::from_millis(50),
+        handles.send(Either::Right(ERROR_CUSTOM)),
+    )
+    .await;
+    assert!(res.is_err(), "Expected the second code to wait for room");
+
+    let res = select! {
+        res = stream.run() => panic!("Expected the task to keep running, got {:?}", res.err()),
+        res = handles.send(Either::Right(ERROR_CUSTOM)) => res,
+    };
+    res.expect("Expected to send the error to the task");
+    let frame = rx.try_recv().expect("Expected ERROR to be sent");
+    assert!(matches!(frame, Frame::Error { code, .. } if code == RESET_BOOTLOADER));
+}
+
+#[tokio::test]
+async fn it_ignores_an_rst_ack_from_the_host() {
+    let (mut stream, _handles, host, mut rx) = connect().await;
+
+    host.send(Ok(Frame::rst_ack(ASH_VERSION_2, RESET_POWERON)))
+        .unwrap();
+    stream.step().await.expect("Expected task execution to succeed");
+
+    assert!(matches!(stream.state(), State::Connected(_)));
+    assert!(rx.try_recv().is_err());
+}
+
+#[tokio::test]
+async fn it_rejects_data_frames_beyond_the_ack_window() {
+    let (mut stream, _handles, host, mut rx) = connect().await;
+    stream.set_ack_window(2);
+
+    for frm_num in 0..3u8 {
+        host.send(Ok(Frame::data(
+            frm_num.try_into().unwrap(),
+            false,
+            0.try_into().unwrap(),
+            BytesMut::from(&[frm_num][..]),
+        )))
+        .unwrap();
+        stream.step().await.expect("Expected task execution to succeed");
+    }
+
+    let frame = rx.recv().await.expect("Expected NAK to be sent");
+    assert!(matches!(frame, Frame::Nak { ack_num, .. } if *ack_num == 2));
+    assert!(rx.try_recv().is_err());
+}
+
+#[tokio::test]
+async fn it_applies_the_window_size_from_the_options() {
+    let options = AshStreamOptions {
+        window_size: 1,
+        ..AshStreamOptions::default()
+    };
+    let (mut stream, _handles, host, mut rx) = connect_with(options).await;
+
+    for frm_num in 0..2u8 {
+        host.send(Ok(Frame::data(
+            frm_num.try_into().unwrap(),
+            false,
+            0.try_into().unwrap(),
+            BytesMut::from(&[frm_num][..]),
+        )))
+        .unwrap();
+        stream
+            .step()
+            .await
+            .expect("Expected task execution to succeed");
+    }
+
+    let frame = rx.recv().await.expect("Expected NAK to be sent");
+    assert!(matches!(frame, Frame::Nak { ack_num, .. } if *ack_num == 1));
+}
+
+#[tokio::test]
+async fn it_counts_acknowledgements_slower_than_the_ack_timeout() {
+    let options = AshStreamOptions {
+        ack_timeout: Duration::from_millis(1),
+        ..AshStreamOptions::default()
+    };
+    let (mut stream, mut handles, host, _rx) = connect_with(options).await;
+
+    handles
+        .send(Either::Left(BytesMut::from(&[0x01][..])))
+        .await
+        .expect("Expected to send data to the host");
+    stream
+        .step()
+        .await
+        .expect("Expected task execution to succeed");
+    tokio::time::sleep(Duration::from_millis(5)).await;
+    host.send(Ok(Frame::ack(false, 1.try_into().unwrap())))
+        .unwrap();
+    stream
+        .step()
+        .await
+        .expect("Expected task execution to succeed");
+
+    let late_acks = match stream.state() {
+        State::Connected(state) => state.late_acks(),
+        _ => panic!("Expected task to be connected"),
+    };
+    assert_eq!(late_acks, 1);
+}
+
+#[tokio::test(start_paused = true)]
+async fn it_sends_no_acks_while_the_session_is_idle() {
+    let (mut stream, _handles, _host, mut rx) = connect().await;
+
+    let res = timeout(ACK_TIMEOUT * 10, stream.run()).await;
+
+    assert!(res.is_err(), "Expected the task to keep running");
+    assert!(rx.try_recv().is_err(), "Expected no frames to be sent");
+}
+
+#[tokio::test(start_paused = true)]
+async fn it_acknowledges_data_on_its_own_when_no_response_follows() {
+    let (mut stream, _handles, host, mut rx) = connect().await;
+    host.send(Ok(Frame::data(
+        0.try_into().unwrap(),
+        false,
+        0.try_into().unwrap(),
+        BytesMut::from(&[0x01][..]),
+    )))
+    .unwrap();
+    stream
+        .step()
+        .await
+        .expect("Expected task execution to succeed");
+    assert!(
+        rx.try_recv().is_err(),
+        "Expected the ACK to wait for a response"
+    );
+
+    let res = timeout(PIGGYBACK_TIMEOUT * 2, stream.run()).await;
+
+    assert!(res.is_err(), "Expected the task to keep running");
+    let frame = rx.try_recv().expect("Expected ACK to be sent");
+    assert!(matches!(frame, Frame::Ack { n_rdy, ack_num, .. } if !n_rdy && *ack_num == 1));
+    assert!(rx.try_recv().is_err(), "Expected a single ACK");
+}
+
+#[tokio::test(start_paused = true)]
+async fn it_sends_no_standalone_ack_after_a_piggybacked_one() {
+    let (mut stream, mut handles, host, mut rx) = connect().await;
+    host.send(Ok(Frame::data(
+        0.try_into().unwrap(),
+        false,
+        0.try_into().unwrap(),
+        BytesMut::from(&[0x01][..]),
+    )))
+    .unwrap();
+    stream
+        .step()
+        .await
+        .expect("Expected task execution to succeed");
+    handles
+        .send(Either::Left(BytesMut::from(&[0x02][..])))
+        .await
+        .expect("Expected to send data to the host");
+    stream
+        .step()
+        .await
+        .expect("Expected task execution to succeed");
+    let frame = rx.try_recv().expect("Expected DATA to be sent");
+    assert!(matches!(frame, Frame::Data { ack_num, .. } if *ack_num == 1));
+    host.send(Ok(Frame::ack(false, 1.try_into().unwrap())))
+        .unwrap();
+
+    let res = timeout(PIGGYBACK_TIMEOUT * 2, stream.run()).await;
+
+    assert!(res.is_err(), "Expected the task to keep running");
+    assert!(rx.try_recv().is_err(), "Expected no standalone ACK");
+}
+
+#[tokio::test]
+async fn it_exchanges_data_in_both_directions_at_once() {
+    let (mut stream, mut handles, host, mut rx) = connect().await;
+    let task = spawn(async move { stream.run().await });
+
+    let bridge = spawn(async move {
+        for body in 0..3u8 {
+            handles
+                .send(Either::Left(BytesMut::from(&[body][..])))
+                .await
+                .expect("Expected to send data to the host");
+        }
+        let mut received = Vec::new();
+        while received.len() < 3 {
+            match handles.receive().await {
+                Ok(Either::Left(mut data)) => {
+                    randomize(&mut data);
+                    received.push(data[0]);
+                }
+                res => panic!("Expected data from the host, got {:?}", res.err()),
+            }
+        }
+        received
+    });
+    for frm_num in 0..3u8 {
+        host.send(Ok(Frame::data(
+            frm_num.try_into().unwrap(),
+            false,
+            0.try_into().unwrap(),
+            BytesMut::from(&[0x10 + frm_num][..]),
+        )))
+        .unwrap();
+    }
+
+    let mut sent = Vec::new();
+    while sent.len() < 3 {
+        match rx.recv().await.expect("Expected a frame to be sent") {
+            Frame::Data { body, .. } => sent.push(body[0]),
+            Frame::Ack { .. } => {}
+            frame => panic!("Expected DATA or ACK, got {}", frame),
+        }
+    }
+    let received = bridge.await.expect("Expected to join the bridge");
+    task.abort();
+
+    assert_eq!(sent, vec![0, 1, 2]);
+    assert_eq!(received, vec![0x10, 0x11, 0x12]);
+}
+
+#[tokio::test]
+async fn it_waits_for_the_host_to_acknowledge_a_full_window() {
+    let options = AshStreamOptions {
+        window_size: 2,
+        ..AshStreamOptions::default()
+    };
+    let (mut stream, mut handles, host, mut rx) = connect_with(options).await;
+    for body in 0..3u8 {
+        handles
+            .send(Either::Left(BytesMut::from(&[body][..])))
+            .await
+            .expect("Expected to send data to the host");
+    }
+    let task = spawn(async move { stream.run().await });
+
+    for expected in 0..2u8 {
+        let frame = rx.recv().await.expect("Expected DATA to be sent");
+        assert!(matches!(frame, Frame::Data { frm_num, .. } if *frm_num == expected));
+    }
+    let res = timeout(Duration::from_millis(50), rx.recv()).await;
+    assert!(res.is_err(), "Expected no DATA beyond the window");
+
+    host.send(Ok(Frame::ack(false, 2.try_into().unwrap())))
+        .unwrap();
+    let frame = rx.recv().await.expect("Expected DATA to be sent");
+    assert!(matches!(frame, Frame::Data { frm_num, .. } if *frm_num == 2));
+    task.abort();
+}
+
+#[tokio::test(start_paused = true)]
+async fn it_retransmits_data_the_host_does_not_acknowledge_in_time() {
+    let options = AshStreamOptions {
+        retransmit_timeout: Duration::from_millis(100),
+        ..AshStreamOptions::default()
+    };
+    let (mut stream, mut handles, host, mut rx) = connect_with(options).await;
+    handles
+        .send(Either::Left(BytesMut::from(&[0x01][..])))
+        .await
+        .expect("Expected to send data to the host");
+
+    stream
+        .step()
+        .await
+        .expect("Expected task execution to succeed");
+    let frame = rx.recv().await.expect("Expected DATA to be sent");
+    assert!(matches!(frame, Frame::Data { frm_num, re_tx, .. } if *frm_num == 0 && !re_tx));
+
+    stream
+        .step()
+        .await
+        .expect("Expected task execution to succeed");
+    let frame = rx.recv().await.expect("Expected DATA to be sent again");
+    assert!(matches!(
+        frame,
+        Frame::Data { frm_num, re_tx, body, .. } if *frm_num == 0 && re_tx && body[..] == [0x01]
+    ));
+
+    host.send(Ok(Frame::ack(false, 1.try_into().unwrap())))
+        .unwrap();
+    stream
+        .step()
+        .await
+        .expect("Expected task execution to succeed");
+    let res = timeout(Duration::from_secs(1), stream.step()).await;
+    assert!(res.is_err(), "Expected nothing more to send");
+    assert!(rx.try_recv().is_err(), "Expected no more retransmissions");
+}
+
+#[tokio::test(start_paused = true)]
+async fn it_fails_the_session_after_too_many_retransmissions() {
+    let options = AshStreamOptions {
+        retransmit_timeout: Duration::from_millis(100),
+        max_retransmits: 2,
+        ..AshStreamOptions::default()
+    };
+    let (mut stream, mut handles, _host, mut rx) = connect_with(options).await;
+    handles
+        .send(Either::Left(BytesMut::from(&[0x01][..])))
+        .await
+        .expect("Expected to send data to the host");
+
+    for _ in 0..4 {
+        stream
+            .step()
+            .await
+            .expect("Expected task execution to succeed");
+    }
+
+    for re_tx in [false, true, true] {
+        let frame = rx.recv().await.expect("Expected DATA to be sent");
+        assert!(matches!(frame, Frame::Data { re_tx: sent, .. } if sent == re_tx));
+    }
+    let frame = rx.recv().await.expect("Expected ERROR to be sent");
+    assert!(matches!(frame, Frame::Error { code, .. } if code == ERROR_MAX_ACK_TIMEOUT));
+    assert!(
+        matches!(stream.state(), State::Failed(state) if state.reason == ERROR_MAX_ACK_TIMEOUT)
+    );
+}
+
+/// Send a DATA frame to the host and have the host acknowledge it after
+/// `rtt`, returning the retransmission timeout that leaves the task with.
+async fn exchange_data_with_rtt(
+    stream: &mut AshStreamTask,
+    handles: &mut AshStream,
+    host: &UnboundedSender<Result<Frame, Error>>,
+    ack_num: u8,
+    rtt: Duration,
+) -> Duration {
+    handles
+        .send(Either::Left(BytesMut::from(&[0x01][..])))
+        .await
+        .expect("Expected to send data to the host");
+    stream
+        .step()
+        .await
+        .expect("Expected task execution to succeed");
+    tokio::time::sleep(rtt).await;
+    host.send(Ok(Frame::ack(false, ack_num.try_into().unwrap())))
+        .unwrap();
+    stream
+        .step()
+        .await
+        .expect("Expected task execution to succeed");
+    match stream.state() {
+        State::Connected(state) => state.retransmit_timeout(),
+        _ => panic!("Expected task to be connected"),
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn it_adapts_the_retransmit_timeout_to_the_round_trip_time() {
+    let (mut stream, mut handles, host, _rx) = connect().await;
+
+    let mut timeout = ACK_TIMEOUT;
+    for ack_num in 1..4u8 {
+        let next = exchange_data_with_rtt(
+            &mut stream,
+            &mut handles,
+            &host,
+            ack_num,
+            Duration::from_millis(1200),
+        )
+        .await;
+        assert!(next > timeout, "Expected {:?} > {:?}", next, timeout);
+        timeout = next;
+    }
+    for ack_num in 4..8u8 {
+        let next = exchange_data_with_rtt(
+            &mut stream,
+            &mut handles,
+            &host,
+            ack_num,
+            Duration::from_millis(10),
+        )
+        .await;
+        assert!(next < timeout, "Expected {:?} < {:?}", next, timeout);
+        timeout = next;
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn it_keeps_the_retransmit_timeout_within_its_bounds() {
+    let options = AshStreamOptions {
+        min_retransmit_timeout: Duration::from_millis(500),
+        max_retransmit_timeout: Duration::from_millis(2000),
+        max_retransmits: 8,
+        ..AshStreamOptions::default()
+    };
+    let (mut stream, mut handles, host, _rx) = connect_with(options).await;
+    let retransmit_timeout = |stream: &AshStreamTask| match stream.state() {
+        State::Connected(state) => state.retransmit_timeout(),
+        _ => panic!("Expected task to be connected"),
+    };
+
+    for sent in 1..=12u8 {
+        exchange_data_with_rtt(
+            &mut stream,
+            &mut handles,
+            &host,
+            sent % 8,
+            Duration::from_millis(1),
+        )
+        .await;
+    }
+    assert_eq!(retransmit_timeout(&stream), Duration::from_millis(500));
+
+    // Each retransmission doubles the timeout, up to the maximum
+    handles
+        .send(Either::Left(BytesMut::from(&[0x01][..])))
+        .await
+        .expect("Expected to send data to the host");
+    stream
+        .step()
+        .await
+        .expect("Expected task execution to succeed");
+    for expected in [1000, 2000, 2000] {
+        stream
+            .step()
+            .await
+            .expect("Expected task execution to succeed");
+        assert_eq!(retransmit_timeout(&stream), Duration::from_millis(expected));
+    }
+}
+
+#[tokio::test]
+async fn it_resends_data_the_host_rejects() {
+    let (mut stream, mut handles, host, mut rx) = connect().await;
+    for body in 0..2u8 {
+        handles
+            .send(Either::Left(BytesMut::from(&[body][..])))
+            .await
+            .expect("Expected to send data to the host");
+        stream
+            .step()
+            .await
+            .expect("Expected task execution to succeed");
+    }
+
+    host.send(Ok(Frame::nak(false, 1.try_into().unwrap())))
+        .unwrap();
+    stream
+        .step()
+        .await
+        .expect("Expected task execution to succeed");
+
+    let sent: Vec<_> = (0..3)
+        .map(|_| match rx.try_recv().expect("Expected DATA to be sent") {
+            Frame::Data { frm_num, re_tx, .. } => (*frm_num, re_tx),
+            frame => panic!("Expected DATA, got {}", frame),
+        })
+        .collect();
+    assert_eq!(sent, vec![(0, false), (1, false), (1, true)]);
+}
+
+#[tokio::test]
+async fn it_reports_the_size_of_a_gap_in_frame_numbers() {
+    let (mut stream, _handles, host, mut rx) = connect().await;
+
+    // Frames 0 to 2 went missing, and the frames after the first one belong
+    // to the same gap
+    for frm_num in 3..5u8 {
+        host.send(Ok(Frame::data(
+            frm_num.try_into().unwrap(),
+            false,
+            0.try_into().unwrap(),
+            BytesMut::from(&[frm_num][..]),
+        )))
+        .unwrap();
+        stream
+            .step()
+            .await
+            .expect("Expected task execution to succeed");
+    }
+
+    let gaps = match stream.state() {
+        State::Connected(state) => state.sequence_gaps(),
+        _ => panic!("Expected task to be connected"),
+    };
+    assert_eq!(gaps.largest, 3);
+    assert_eq!(gaps.desynced, 1);
+    assert_eq!(gaps.dropped, 0);
+    let frame = rx.recv().await.expect("Expected NAK to be sent");
+    assert!(matches!(frame, Frame::Nak { ack_num, .. } if *ack_num == 0));
+}
+
+#[test]
+fn it_streams_data_and_resets_from_the_task() {
+    let (read_tx, read) = channel(2);
+    let (reset_tx, reset) = channel(1);
+    let (write, _write_rx) = channel(1);
+    let (error, _error_rx) = channel(1);
+    let stream = AshStream::new(read, reset, write, error);
+
+    block_on(async {
+        read_tx.send(BytesMut::from(&[0x01][..])).await.unwrap();
+        read_tx.send(BytesMut::from(&[0x02][..])).await.unwrap();
+        let (ret, _res) = oneshot_channel();
+        reset_tx.send(ret).await.unwrap();
+        drop(read_tx);
+        drop(reset_tx);
+
+        let items: Vec<Option<u8>> = stream
+            .map(|item| match item {
+                Either::Left(frame) => Some(frame[0]),
+                Either::Right(_) => None,
+            })
+            .collect()
+            .await;
+        assert_eq!(items, [None, Some(0x01), Some(0x02)]);
+    });
+}
+
+#[test]
+fn it_sinks_data_and_errors_to_the_task() {
+    let (_read_tx, read) = channel(1);
+    let (_reset_tx, reset) = channel(1);
+    let (write, mut write_rx) = channel(1);
+    let (error, mut error_rx) = channel(1);
+    let stream = AshStream::new(read, reset, write, error);
+    let mut sink = stream
+        .with(|frame: &[u8]| ready(Ok::<_, StreamError>(Either::Left(BytesMut::from(frame)))));
+
+    block_on(async {
+        sink.send(&[0x01, 0x02][..]).await.unwrap();
+        assert_eq!(write_rx.recv().await.unwrap(), [0x01, 0x02][..]);
+
+        sink.get_mut()
+            .send(Either::Right(RESET_BOOTLOADER))
+            .await
+            .unwrap();
+        assert_eq!(
+            error_rx.recv().await.map(|(code, _)| code),
+            Some(RESET_BOOTLOADER)
+        );
+    });
+}
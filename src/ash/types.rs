@@ -7,7 +7,11 @@ fn three_bit_wrapped_add(lhs: u8, rhs: u8) -> u8 {
     (lhs + rhs) % 8
 }
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// A 3-bit ASH frame number.
+///
+/// Frame numbers are ordered by value, not by their position in the wrapping
+/// sequence, so `7` sorts after `0` even when it was sent first.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct FrameNumber(u8);
 
 impl FrameNumber {
@@ -79,6 +83,7 @@ impl Display for FrameNumber {
 #[cfg(test)]
 mod tests {
     use super::FrameNumber;
+    use std::collections::{BTreeMap, HashMap};
 
     #[test]
     fn it_accepts_a_valid_frame_number() {
@@ -97,4 +102,27 @@ mod tests {
         let res = FrameNumber::new_truncate(0xBE);
         assert_eq!(*res, 6);
     }
+
+    #[test]
+    fn it_can_key_a_hash_map() {
+        let mut pending = HashMap::new();
+        for n in 0..8 {
+            pending.insert(FrameNumber::new_truncate(n), n * 2);
+        }
+
+        assert_eq!(pending.len(), 8);
+        assert_eq!(pending.get(&FrameNumber::new_truncate(3)), Some(&6));
+        assert_eq!(pending.get(&(FrameNumber::new_truncate(7) + 1)), Some(&0));
+    }
+
+    #[test]
+    fn it_orders_frame_numbers_by_value() {
+        let ordered: BTreeMap<_, _> = [5, 0, 7, 2]
+            .into_iter()
+            .map(|n| (FrameNumber::new_truncate(n), ()))
+            .collect();
+        let keys: Vec<u8> = ordered.into_keys().map(u8::from).collect();
+
+        assert_eq!(keys, vec![0, 2, 5, 7]);
+    }
 }
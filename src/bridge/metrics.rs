@@ -0,0 +1,65 @@
+use std::{fmt::Display, time::Instant};
+
+/// Traffic counters for a single host session.
+///
+/// Frames and bytes count EZSP frames passed between the host and the NCP,
+/// not ASH control frames.
+#[derive(Debug, Clone)]
+pub struct BridgeMetrics {
+    pub connected_at: Instant,
+    /// EZSP frames received from the host.
+    pub frames_rx: u64,
+    /// EZSP frames sent to the host, including callbacks.
+    pub frames_tx: u64,
+    pub bytes_rx: u64,
+    pub bytes_tx: u64,
+    pub ncp_resets: u32,
+}
+
+impl BridgeMetrics {
+    pub fn new() -> BridgeMetrics {
+        BridgeMetrics {
+            connected_at: Instant::now(),
+            frames_rx: 0,
+            frames_tx: 0,
+            bytes_rx: 0,
+            bytes_tx: 0,
+            ncp_resets: 0,
+        }
+    }
+
+    pub(crate) fn record_rx(&mut self, len: usize) {
+        self.frames_rx += 1;
+        self.bytes_rx += len as u64;
+    }
+
+    pub(crate) fn record_tx(&mut self, len: usize) {
+        self.frames_tx += 1;
+        self.bytes_tx += len as u64;
+    }
+
+    pub(crate) fn record_reset(&mut self) {
+        self.ncp_resets += 1;
+    }
+}
+
+impl Default for BridgeMetrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Display for BridgeMetrics {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "uptime {:?}, {} frames ({} bytes) in, {} frames ({} bytes) out, {} NCP resets",
+            self.connected_at.elapsed(),
+            self.frames_rx,
+            self.bytes_rx,
+            self.frames_tx,
+            self.bytes_tx,
+            self.ncp_resets
+        )
+    }
+}
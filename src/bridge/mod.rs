@@ -0,0 +1,351 @@
+mod metrics;
+mod transparent;
+
+pub use metrics::BridgeMetrics;
+pub use transparent::handle_transparent;
+
+use crate::{
+    ash::{
+        create_ash_stream, create_ash_stream_task, AshStreamOptions, ERROR_CUSTOM,
+        RESET_BOOTLOADER, RESET_UNKNOWN, WAKE_BYTE,
+    },
+    health::{AshState, Health},
+    spi::{self, SpiDeviceHandle, State},
+};
+use anyhow::{Context, Result};
+use bytes::BytesMut;
+use futures::{future::pending, StreamExt};
+use std::{
+    future::Future,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    select, spawn,
+    time::timeout,
+};
+use tokio_util::either::Either;
+use tracing::{debug, info, trace, warn};
+
+/// The EZSP frame control and frame ID that ask the NCP for a pending
+/// callback, following the sequence number.
+const CALLBACK_COMMAND: [u8; 2] = [0xFF, 0x05];
+
+/// A DATA payload asking the bridge to wake the NCP. It is too short to be an
+/// EZSP frame, so it is never forwarded to the NCP.
+const WAKE_REQUEST: [u8; 1] = [WAKE_BYTE];
+
+/// The ASH error reported to the host when the NCP does not wake up.
+const ERROR_WAKE_FAILED: u8 = ERROR_CUSTOM;
+
+/// The ASH error reported to the host when the NCP does not come back from a
+/// reset.
+const ERROR_RESET_FAILED: u8 = ERROR_CUSTOM + 1;
+
+/// The ASH error reported to the host when the bridge ends the session
+/// because of an error of its own.
+const ERROR_BRIDGE_FAILED: u8 = ERROR_CUSTOM + 2;
+
+/// The ASH error reported to the host when the bridge closes the session
+/// because the server is shutting down.
+const ERROR_BRIDGE_CLOSED: u8 = ERROR_CUSTOM + 3;
+
+/// How long a host that is not reading is given to take the final ERROR frame
+/// before its connection is dropped anyway.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Numbers the EZSP frames the bridge sends to the NCP on its own, such as
+/// requests for a pending callback.
+///
+/// The host numbers its own commands, and may have sent the next few already
+/// when the bridge fetches a callback. Frames from the bridge are numbered
+/// counting on from half the sequence space away from the host's latest
+/// command, so the host never sees the sequence number of a command it is
+/// still waiting on, and the NCP never sees one twice in a row.
+#[derive(Debug)]
+struct Sequencer {
+    next: u8,
+}
+
+impl Sequencer {
+    fn new() -> Sequencer {
+        Sequencer { next: 0x80 }
+    }
+
+    /// Note the sequence number of a command sent by the host.
+    fn host_command(&mut self, sequence: u8) {
+        self.next = sequence.wrapping_add(0x80);
+    }
+
+    /// The sequence number for the next frame sent by the bridge.
+    fn next(&mut self) -> u8 {
+        let sequence = self.next;
+        self.next = sequence.wrapping_add(1);
+        sequence
+    }
+}
+
+/// The reset code to report to the host in an ERROR frame when the NCP has
+/// to be reset after `error`, or `None` if the session cannot carry on.
+///
+/// `state` is the state the NCP was left in by the request that failed.
+fn reset_reason(error: &spi::Error, state: State) -> Option<u8> {
+    match error {
+        // The NCP fell into its bootloader
+        spi::Error::NeedsReset | spi::Error::Unresponsive if state == State::Bootloader => {
+            Some(RESET_BOOTLOADER)
+        }
+        // The NCP stopped answering, or was never reset, so what it is doing
+        // is anyone's guess
+        spi::Error::NeedsReset | spi::Error::Unresponsive => Some(RESET_UNKNOWN),
+        // The NCP reset itself, such as after a watchdog timeout or an assert
+        spi::Error::UnexpectedReset(code) => Some(*code),
+        _ => None,
+    }
+}
+
+/// A bridge between a single host connection and the NCP.
+pub struct Bridge {
+    device: SpiDeviceHandle,
+    health: Health,
+    options: AshStreamOptions,
+    metrics: Arc<Mutex<BridgeMetrics>>,
+}
+
+impl Bridge {
+    /// Create a bridge to the NCP behind `device`, reporting the state of the
+    /// session to `health`.
+    ///
+    /// The ASH session with the host is run with `options`. If its idle
+    /// timeout is set, the connection is closed once nothing has passed
+    /// through the bridge for that long.
+    pub fn new(device: SpiDeviceHandle, health: Health, options: AshStreamOptions) -> Bridge {
+        Bridge {
+            device,
+            health,
+            options,
+            metrics: Arc::new(Mutex::new(BridgeMetrics::new())),
+        }
+    }
+
+    /// The traffic counters for this session, updated while the bridge runs.
+    pub fn metrics(&self) -> Arc<Mutex<BridgeMetrics>> {
+        self.metrics.clone()
+    }
+
+    /// Bridge the host connection to the NCP until the host disconnects.
+    ///
+    /// The ASH protocol is run in its own task, while this task relays EZSP
+    /// frames from the host to the NCP and their responses back, and performs
+    /// NCP resets when the host requests them. Once the NCP has been reset,
+    /// callbacks signalled by the NCP are fetched and delivered to the host
+    /// without waiting for the host to poll for them. If the NCP fails to
+    /// reset, the host is sent an ERROR frame instead of an RSTACK, and if
+    /// the SPI bus itself failed, the session ends with that error. A session
+    /// ended by an error in the bridge is closed with a final ERROR frame, so
+    /// the host knows it was not a network fault.
+    ///
+    /// If the NCP resets while handling a frame, or falls into its
+    /// bootloader, the host is sent an ERROR frame carrying the reset code and
+    /// must reset the NCP. The same goes for an NCP that stops answering,
+    /// reported with the unknown reset code.
+    ///
+    /// The host wakes a sleeping NCP by sending a DATA frame containing only
+    /// the ASH wake byte, `0xFF`. The same payload is sent back once the NCP
+    /// is awake. If the NCP fails to wake, the host is sent an ERROR frame and
+    /// must reset the NCP.
+    pub async fn run<T>(&self, client: T) -> Result<()>
+    where
+        T: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        self.run_until(client, pending()).await
+    }
+
+    /// Bridge the host connection to the NCP until the host disconnects, or
+    /// until `close` completes.
+    ///
+    /// Once `close` completes, the host is sent a final ERROR frame and the
+    /// session ends. See [`Bridge::run`] for everything else.
+    pub async fn run_until<T, F>(&self, client: T, close: F) -> Result<()>
+    where
+        T: AsyncRead + AsyncWrite + Send + 'static,
+        F: Future<Output = ()>,
+    {
+        self.health.set_ash_state(AshState::Failed);
+        let res = self.relay(client, close).await;
+        self.health.set_ash_state(AshState::Disconnected);
+        info!("Bridge session ended: {}", self.lock_metrics());
+        res
+    }
+
+    fn lock_metrics(&self) -> std::sync::MutexGuard<'_, BridgeMetrics> {
+        // The metrics are plain counters, so they remain usable even if a
+        // panic poisoned the lock.
+        self.metrics.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    async fn relay<T, F>(&self, client: T, close: F) -> Result<()>
+    where
+        T: AsyncRead + AsyncWrite + Send + 'static,
+        F: Future<Output = ()>,
+    {
+        let device = &self.device;
+        let (writer, reader) = create_ash_stream(Box::pin(client)).split();
+        let (mut task, mut stream) = create_ash_stream_task(reader, writer, self.options);
+        let mut task = spawn(async move { task.run().await });
+
+        let relay = async {
+            let mut ncp_ready = false;
+            let mut sequencer = Sequencer::new();
+            loop {
+                select! {
+                    msg = stream.receive() => match msg? {
+                        Either::Left(frame) if frame[..] == WAKE_REQUEST => {
+                            debug!("Waking the NCP at the request of the host");
+                            match device.wake().await {
+                                Ok(()) => {
+                                    stream
+                                        .send(Either::Left(BytesMut::from(&WAKE_REQUEST[..])))
+                                        .await?;
+                                }
+                                Err(e) => {
+                                    warn!(error = ?e, "Failed to wake the NCP: {}", e);
+                                    ncp_ready = false;
+                                    self.health.set_ash_state(AshState::Failed);
+                                    stream.send(Either::Right(ERROR_WAKE_FAILED)).await?;
+                                }
+                            }
+                        }
+                        Either::Left(frame) => {
+                            trace!(len = frame.len(), "Forwarding EZSP frame to the NCP");
+                            self.lock_metrics().record_rx(frame.len());
+                            if let Some(&sequence) = frame.first() {
+                                sequencer.host_command(sequence);
+                            }
+                            match device.send_frame(frame.freeze()).await {
+                                Ok(response) => {
+                                    self.lock_metrics().record_tx(response.len());
+                                    stream
+                                        .send(Either::Left(BytesMut::from(&response[..])))
+                                        .await?;
+                                }
+                                Err(e) => {
+                                    let code = reset_reason(&e, device.ncp_state()).ok_or(e)?;
+                                    warn!(code, "NCP needs a reset, asking the host to reset");
+                                    ncp_ready = false;
+                                    self.health.set_ash_state(AshState::Failed);
+                                    stream.send(Either::Right(code)).await?;
+                                }
+                            }
+                        }
+                        Either::Right(ret) => {
+                            debug!("Resetting the NCP at the request of the host");
+                            match device.reset_normal().await {
+                                Ok(code) => {
+                                    self.lock_metrics().record_reset();
+                                    ncp_ready = true;
+                                    self.health.set_ash_state(AshState::Connected);
+                                    let _ = ret.send(Ok(code));
+                                }
+                                Err(e) => {
+                                    warn!(error = ?e, "Failed to reset the NCP: {}", e);
+                                    ncp_ready = false;
+                                    self.health.set_ash_state(AshState::Failed);
+                                    let _ = ret.send(Err(ERROR_RESET_FAILED));
+                                    // Another reset from the host will not
+                                    // mend a broken bus
+                                    if e.is_transfer() {
+                                        return Err(e.into());
+                                    }
+                                }
+                            }
+                        }
+                    },
+                    _ = device.has_callback(), if ncp_ready => {
+                        trace!("Fetching pending callback from the NCP");
+                        let mut command = BytesMut::with_capacity(1 + CALLBACK_COMMAND.len());
+                        command.extend_from_slice(&[sequencer.next()]);
+                        command.extend_from_slice(&CALLBACK_COMMAND);
+                        match device.send_frame(command.freeze()).await {
+                            Ok(response) => {
+                                self.lock_metrics().record_tx(response.len());
+                                stream
+                                    .send(Either::Left(BytesMut::from(&response[..])))
+                                    .await?;
+                            }
+                            Err(e) => {
+                                let code = reset_reason(&e, device.ncp_state()).ok_or(e)?;
+                                warn!(code, "NCP needs a reset, asking the host to reset");
+                                ncp_ready = false;
+                                self.health.set_ash_state(AshState::Failed);
+                                stream.send(Either::Right(code)).await?;
+                            }
+                        }
+                    }
+                }
+            }
+        };
+
+        let res = select! {
+            res = &mut task => res.context("ASH stream task failed to complete")?,
+            res = relay => res,
+            _ = close => {
+                info!("Closing the bridge session");
+                let _ = timeout(CLOSE_TIMEOUT, stream.close(ERROR_BRIDGE_CLOSED)).await;
+                task.abort();
+                return Ok(());
+            }
+        };
+        if matches!(&res, Err(e) if !e.is_disconnect()) {
+            let _ = timeout(CLOSE_TIMEOUT, stream.close(ERROR_BRIDGE_FAILED)).await;
+        }
+        task.abort();
+
+        match res {
+            Err(e) if e.is_disconnect() => {
+                debug!("Bridge closed: {}", e);
+                Ok(())
+            }
+            res => Ok(res?),
+        }
+    }
+}
+
+/// Bridge a host connection to the NCP until the host disconnects.
+///
+/// See [`Bridge::run`] for details.
+pub async fn handle<T>(
+    client: T,
+    device: SpiDeviceHandle,
+    health: Health,
+    options: AshStreamOptions,
+) -> Result<()>
+where
+    T: AsyncRead + AsyncWrite + Send + 'static,
+{
+    Bridge::new(device, health, options).run(client).await
+}
+
+/// Bridge a host connection to the NCP until the host disconnects, or until
+/// `close` completes.
+///
+/// See [`Bridge::run_until`] for details.
+pub async fn handle_until<T, F>(
+    client: T,
+    device: SpiDeviceHandle,
+    health: Health,
+    options: AshStreamOptions,
+    close: F,
+) -> Result<()>
+where
+    T: AsyncRead + AsyncWrite + Send + 'static,
+    F: Future<Output = ()>,
+{
+    Bridge::new(device, health, options)
+        .run_until(client, close)
+        .await
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,564 @@
+use super::*;
+use crate::{
+    ash::{randomize, AshStream, Frame, FrameNumber, StreamError, RESET_POWERON, RESET_WATCHDOG},
+    spi::{spi_device_handle, MockSpiDevice, NcpOptions},
+    test::{scripted_device, scripted_device_with_interrupt, scripted_reads},
+};
+use futures::SinkExt;
+use std::{
+    io,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+use tokio::{
+    io::{duplex, DuplexStream},
+    sync::oneshot,
+    time::timeout,
+};
+
+/// The SPI responses the NCP gives to a reset: the reset code, its protocol
+/// version and status, then its answer to the EZSP version command.
+const RESET_RESPONSES: [&[u8]; 4] = [
+    &[0x00, 0x02, 0xA7],
+    &[0x82, 0xA7],
+    &[0xC1, 0xA7],
+    &[0xFE, 0x07, 0x00, 0x80, 0x00, 0x08, 0x02, 0x00, 0x67, 0xA7],
+];
+
+async fn next_frame(host: &mut AshStream<DuplexStream>) -> Frame {
+    timeout(Duration::from_secs(5), host.next())
+        .await
+        .expect("Expected a frame before the timeout")
+        .expect("Expected the bridge to stay connected")
+        .expect("Expected a valid frame")
+}
+
+#[tokio::test]
+async fn it_relays_data_frames_between_the_host_and_the_ncp() {
+    let device = scripted_device(&[
+        RESET_RESPONSES[0],
+        RESET_RESPONSES[1],
+        RESET_RESPONSES[2],
+        RESET_RESPONSES[3],
+        &[0xFE, 0x03, 0x01, 0x80, 0x00, 0xA7],
+    ]);
+    let (_actor, device) =
+        spi_device_handle(device, NcpOptions::default()).expect("Expected to spawn the SPI actor");
+    let health = Health::new(&device);
+    let (host, client) = duplex(1024);
+    let bridge = spawn(handle(
+        client,
+        device,
+        health.clone(),
+        AshStreamOptions::default(),
+    ));
+    let mut host = create_ash_stream(host);
+
+    host.send(Frame::rst()).await.expect("Expected to send RST");
+    let frame = next_frame(&mut host).await;
+    assert!(matches!(frame, Frame::RstAck { code, .. } if code == RESET_POWERON));
+    let report = health.report();
+    assert_eq!(report.ash_state, AshState::Connected);
+    assert!(report.last_ncp_transaction.is_some());
+
+    host.send(Frame::data(
+        FrameNumber::zero(),
+        false,
+        FrameNumber::zero(),
+        BytesMut::from(&[0x01, 0x00, 0x00][..]),
+    ))
+    .await
+    .expect("Expected to send DATA");
+    let frame = next_frame(&mut host).await;
+    match frame {
+        Frame::Data {
+            frm_num,
+            ack_num,
+            mut body,
+            ..
+        } => {
+            assert_eq!(*frm_num, 0);
+            assert_eq!(*ack_num, 1);
+            randomize(&mut body);
+            assert_eq!(body.as_ref(), [0x01, 0x80, 0x00]);
+        }
+        frame => panic!("Expected a DATA frame, got {}", frame),
+    }
+
+    drop(host);
+    timeout(Duration::from_secs(5), bridge)
+        .await
+        .expect("Expected the bridge to close")
+        .expect("Expected to join the bridge task")
+        .expect("Expected a clean disconnect");
+    assert_eq!(health.report().ash_state, AshState::Disconnected);
+}
+
+#[tokio::test]
+async fn it_forwards_callbacks_signalled_by_the_ncp() {
+    let pending = Arc::new(AtomicBool::new(false));
+    let interrupt = pending.clone();
+    let device = scripted_device_with_interrupt(
+        &[
+            RESET_RESPONSES[0],
+            RESET_RESPONSES[1],
+            RESET_RESPONSES[2],
+            RESET_RESPONSES[3],
+            // Callback response
+            &[0xFE, 0x04, 0x00, 0x90, 0x19, 0x01, 0xA7],
+        ],
+        move || interrupt.swap(false, Ordering::SeqCst),
+    );
+    let (_actor, device) =
+        spi_device_handle(device, NcpOptions::default()).expect("Expected to spawn the SPI actor");
+    let health = Health::new(&device);
+    let (host, client) = duplex(1024);
+    let _bridge = spawn(handle(client, device, health, AshStreamOptions::default()));
+    let mut host = create_ash_stream(host);
+
+    host.send(Frame::rst()).await.expect("Expected to send RST");
+    let frame = next_frame(&mut host).await;
+    assert!(matches!(frame, Frame::RstAck { .. }));
+
+    pending.store(true, Ordering::SeqCst);
+    let frame = next_frame(&mut host).await;
+    match frame {
+        Frame::Data {
+            frm_num, mut body, ..
+        } => {
+            assert_eq!(*frm_num, 0);
+            randomize(&mut body);
+            assert_eq!(body.as_ref(), [0x00, 0x90, 0x19, 0x01]);
+        }
+        frame => panic!("Expected a DATA frame, got {}", frame),
+    }
+}
+
+#[tokio::test]
+async fn it_numbers_callbacks_apart_from_host_commands() {
+    let pending = Arc::new(AtomicBool::new(false));
+    let interrupt = pending.clone();
+    let written = Arc::new(Mutex::new(Vec::new()));
+    let commands = written.clone();
+    let mut device = MockSpiDevice::new();
+    scripted_reads(
+        &mut device,
+        &[
+            RESET_RESPONSES[0],
+            RESET_RESPONSES[1],
+            RESET_RESPONSES[2],
+            RESET_RESPONSES[3],
+            // Command response
+            &[0xFE, 0x03, 0x01, 0x80, 0x00, 0xA7],
+            // Callback response, numbered like the callback request
+            &[0xFE, 0x04, 0x81, 0x90, 0x19, 0x01, 0xA7],
+        ],
+    );
+    device.expect_set_wake_signal().returning(|_| Ok(()));
+    device.expect_set_reset_signal().returning(|_| Ok(()));
+    device.expect_write().returning(move |buf| {
+        commands
+            .lock()
+            .expect("Mutex was poisoned")
+            .push(buf.to_vec());
+        Ok(())
+    });
+    device
+        .expect_poll_interrupt_signal()
+        .returning(|_| Ok(true));
+    device
+        .expect_poll_interrupt_signal_or_wakeup()
+        .returning(|_, _| Ok(true));
+    device
+        .expect_get_interrupt_value()
+        .returning(move || Ok(interrupt.swap(false, Ordering::SeqCst)));
+    let (_actor, device) =
+        spi_device_handle(device, NcpOptions::default()).expect("Expected to spawn the SPI actor");
+    let health = Health::new(&device);
+    let (host, client) = duplex(1024);
+    let _bridge = spawn(handle(client, device, health, AshStreamOptions::default()));
+    let mut host = create_ash_stream(host);
+
+    host.send(Frame::rst()).await.expect("Expected to send RST");
+    let _ = next_frame(&mut host).await;
+    host.send(Frame::data(
+        FrameNumber::zero(),
+        false,
+        FrameNumber::zero(),
+        BytesMut::from(&[0x01, 0x00, 0x00][..]),
+    ))
+    .await
+    .expect("Expected to send DATA");
+    let response = next_frame(&mut host).await;
+    pending.store(true, Ordering::SeqCst);
+    let callback = next_frame(&mut host).await;
+
+    let sequences: Vec<u8> = [response, callback]
+        .into_iter()
+        .map(|frame| match frame {
+            Frame::Data { mut body, .. } => {
+                randomize(&mut body);
+                body[0]
+            }
+            frame => panic!("Expected a DATA frame, got {}", frame),
+        })
+        .collect();
+    assert_eq!(sequences, [0x01, 0x81]);
+    // The EZSP frames sent to the NCP, after the reset and version commands
+    let sent: Vec<u8> = written
+        .lock()
+        .expect("Mutex was poisoned")
+        .iter()
+        .filter(|buf| buf[0] == 0xFE)
+        .skip(1)
+        .map(|buf| buf[2])
+        .collect();
+    assert_eq!(sent, [0x01, 0x81]);
+}
+
+#[tokio::test]
+async fn it_wakes_the_ncp_when_the_host_asks() {
+    let device = scripted_device(&RESET_RESPONSES);
+    let (_actor, device) =
+        spi_device_handle(device, NcpOptions::default()).expect("Expected to spawn the SPI actor");
+    let health = Health::new(&device);
+    let (host, client) = duplex(1024);
+    let _bridge = spawn(handle(client, device, health, AshStreamOptions::default()));
+    let mut host = create_ash_stream(host);
+
+    host.send(Frame::rst()).await.expect("Expected to send RST");
+    let frame = next_frame(&mut host).await;
+    assert!(matches!(frame, Frame::RstAck { .. }));
+
+    host.send(Frame::data(
+        FrameNumber::zero(),
+        false,
+        FrameNumber::zero(),
+        BytesMut::from(&WAKE_REQUEST[..]),
+    ))
+    .await
+    .expect("Expected to send DATA");
+    let frame = next_frame(&mut host).await;
+    match frame {
+        Frame::Data { mut body, .. } => {
+            randomize(&mut body);
+            assert_eq!(body.as_ref(), WAKE_REQUEST);
+        }
+        frame => panic!("Expected a DATA frame, got {}", frame),
+    }
+}
+
+#[tokio::test]
+async fn it_counts_traffic_through_the_bridge() {
+    let device = scripted_device(&[
+        RESET_RESPONSES[0],
+        RESET_RESPONSES[1],
+        RESET_RESPONSES[2],
+        RESET_RESPONSES[3],
+        &[0xFE, 0x05, 0x01, 0x80, 0x00, 0x00, 0x00, 0xA7],
+    ]);
+    let (_actor, device) =
+        spi_device_handle(device, NcpOptions::default()).expect("Expected to spawn the SPI actor");
+    let (host, client) = duplex(1024);
+    let health = Health::new(&device);
+    let bridge = Bridge::new(device, health, AshStreamOptions::default());
+    let metrics = bridge.metrics();
+    let session = spawn(async move { bridge.run(client).await });
+    let mut host = create_ash_stream(host);
+
+    host.send(Frame::rst()).await.expect("Expected to send RST");
+    next_frame(&mut host).await;
+    host.send(Frame::data(
+        FrameNumber::zero(),
+        false,
+        FrameNumber::zero(),
+        BytesMut::from(&[0x01, 0x00, 0x00][..]),
+    ))
+    .await
+    .expect("Expected to send DATA");
+    next_frame(&mut host).await;
+
+    drop(host);
+    timeout(Duration::from_secs(5), session)
+        .await
+        .expect("Expected the bridge to close")
+        .expect("Expected to join the bridge task")
+        .expect("Expected a clean disconnect");
+
+    let metrics = metrics.lock().expect("Mutex was poisoned");
+    assert_eq!(metrics.frames_rx, 1);
+    assert_eq!(metrics.bytes_rx, 3);
+    assert_eq!(metrics.frames_tx, 1);
+    assert_eq!(metrics.bytes_tx, 5);
+    assert_eq!(metrics.ncp_resets, 1);
+}
+
+#[tokio::test]
+async fn it_reports_an_error_when_the_ncp_reset_times_out() {
+    let mut device = MockSpiDevice::new();
+    device.expect_set_cs_signal().returning(|_| Ok(()));
+    device.expect_set_wake_signal().returning(|_| Ok(()));
+    device.expect_set_reset_signal().returning(|_| Ok(()));
+    // The NCP never signals that it has started up
+    device
+        .expect_poll_interrupt_signal()
+        .returning(|_| Ok(false));
+    device
+        .expect_poll_interrupt_signal_or_wakeup()
+        .returning(|_, _| Ok(false));
+    let (_actor, device) =
+        spi_device_handle(device, NcpOptions::default()).expect("Expected to spawn the SPI actor");
+    let health = Health::new(&device);
+    let (host, client) = duplex(1024);
+    let _bridge = spawn(handle(
+        client,
+        device,
+        health.clone(),
+        AshStreamOptions::default(),
+    ));
+    let mut host = create_ash_stream(host);
+
+    host.send(Frame::rst()).await.expect("Expected to send RST");
+    let frame = next_frame(&mut host).await;
+    assert!(matches!(frame, Frame::Error { code, .. } if code == ERROR_RESET_FAILED));
+    assert_eq!(health.report().ash_state, AshState::Failed);
+}
+
+#[tokio::test]
+async fn it_ends_the_session_when_the_spi_bus_fails_during_a_reset() {
+    let mut device = MockSpiDevice::new();
+    device.expect_get_interrupt_value().returning(|| Ok(false));
+    device
+        .expect_set_reset_signal()
+        .returning(|_| Err(io::Error::from(io::ErrorKind::BrokenPipe)));
+    device
+        .expect_poll_interrupt_signal()
+        .returning(|_| Ok(false));
+    device
+        .expect_poll_interrupt_signal_or_wakeup()
+        .returning(|_, _| Ok(false));
+    let (_actor, device) =
+        spi_device_handle(device, NcpOptions::default()).expect("Expected to spawn the SPI actor");
+    let health = Health::new(&device);
+    let (host, client) = duplex(1024);
+    let bridge = spawn(handle(client, device, health, AshStreamOptions::default()));
+    let mut host = create_ash_stream(host);
+
+    host.send(Frame::rst()).await.expect("Expected to send RST");
+
+    let err = timeout(Duration::from_secs(5), bridge)
+        .await
+        .expect("Expected the bridge to stop")
+        .expect("Expected to join the bridge task")
+        .expect_err("Expected the session to fail");
+    assert!(matches!(
+        err.downcast_ref::<StreamError>(),
+        Some(StreamError::Ncp(e)) if e.is_transfer()
+    ));
+}
+
+#[tokio::test]
+async fn it_sends_a_final_error_when_the_bridge_fails() {
+    let device = scripted_device(&[
+        RESET_RESPONSES[0],
+        RESET_RESPONSES[1],
+        RESET_RESPONSES[2],
+        RESET_RESPONSES[3],
+        // The NCP rejects the frame as oversized
+        &[0x01, 0x00, 0xA7],
+    ]);
+    let (_actor, device) =
+        spi_device_handle(device, NcpOptions::default()).expect("Expected to spawn the SPI actor");
+    let health = Health::new(&device);
+    let (host, client) = duplex(1024);
+    let bridge = spawn(handle(client, device, health, AshStreamOptions::default()));
+    let mut host = create_ash_stream(host);
+
+    host.send(Frame::rst()).await.expect("Expected to send RST");
+    let _ = next_frame(&mut host).await;
+    host.send(Frame::data(
+        FrameNumber::zero(),
+        false,
+        FrameNumber::zero(),
+        BytesMut::from(&[0x01, 0x00, 0x00][..]),
+    ))
+    .await
+    .expect("Expected to send DATA");
+
+    // The DATA is acknowledged before the session fails
+    let frame = next_frame(&mut host).await;
+    assert!(matches!(frame, Frame::Ack { ack_num, .. } if *ack_num == 1));
+    let frame = next_frame(&mut host).await;
+    assert!(matches!(frame, Frame::Error { code, .. } if code == ERROR_BRIDGE_FAILED));
+    let res = timeout(Duration::from_secs(5), bridge)
+        .await
+        .expect("Expected the bridge to stop")
+        .expect("Expected to join the bridge task");
+    assert!(res.is_err());
+}
+
+#[tokio::test]
+async fn it_asks_the_host_to_reset_when_the_ncp_stops_answering() {
+    let answering = Arc::new(AtomicBool::new(true));
+    let interrupt = answering.clone();
+    let mut device = MockSpiDevice::new();
+    scripted_reads(
+        &mut device,
+        &[
+            RESET_RESPONSES[0],
+            RESET_RESPONSES[1],
+            RESET_RESPONSES[2],
+            RESET_RESPONSES[3],
+            // The DATA frame goes unanswered
+            &[],
+            RESET_RESPONSES[0],
+            RESET_RESPONSES[1],
+            RESET_RESPONSES[2],
+            RESET_RESPONSES[3],
+        ],
+    );
+    device.expect_set_wake_signal().returning(|_| Ok(()));
+    device.expect_set_reset_signal().returning(|_| Ok(()));
+    device.expect_write().returning(|_| Ok(()));
+    device
+        .expect_poll_interrupt_signal()
+        .returning(move |_| Ok(interrupt.load(Ordering::SeqCst)));
+    device
+        .expect_poll_interrupt_signal_or_wakeup()
+        .returning(|_, _| Ok(false));
+    device.expect_get_interrupt_value().returning(|| Ok(false));
+    let (_actor, device) =
+        spi_device_handle(device, NcpOptions::default()).expect("Expected to spawn the SPI actor");
+    let health = Health::new(&device);
+    let (host, client) = duplex(1024);
+    let bridge = spawn(handle(client, device, health, AshStreamOptions::default()));
+    let mut host = create_ash_stream(host);
+
+    host.send(Frame::rst()).await.expect("Expected to send RST");
+    let _ = next_frame(&mut host).await;
+    answering.store(false, Ordering::SeqCst);
+    host.send(Frame::data(
+        FrameNumber::zero(),
+        false,
+        FrameNumber::zero(),
+        BytesMut::from(&[0x01, 0x00, 0x00][..]),
+    ))
+    .await
+    .expect("Expected to send DATA");
+
+    // The DATA is acknowledged before the session fails
+    let frame = next_frame(&mut host).await;
+    assert!(matches!(frame, Frame::Ack { ack_num, .. } if *ack_num == 1));
+    let frame = next_frame(&mut host).await;
+    assert!(matches!(frame, Frame::Error { code, .. } if code == RESET_UNKNOWN));
+
+    // The session carries on once the host resets the NCP
+    answering.store(true, Ordering::SeqCst);
+    host.send(Frame::rst()).await.expect("Expected to send RST");
+    let frame = next_frame(&mut host).await;
+    assert!(matches!(frame, Frame::RstAck { code, .. } if code == RESET_POWERON));
+    assert!(!bridge.is_finished());
+}
+
+#[tokio::test]
+async fn it_sends_a_final_error_when_the_session_is_closed() {
+    let device = scripted_device(&[
+        RESET_RESPONSES[0],
+        RESET_RESPONSES[1],
+        RESET_RESPONSES[2],
+        RESET_RESPONSES[3],
+    ]);
+    let (_actor, device) =
+        spi_device_handle(device, NcpOptions::default()).expect("Expected to spawn the SPI actor");
+    let health = Health::new(&device);
+    let (host, client) = duplex(1024);
+    let (close_tx, close_rx) = oneshot::channel::<()>();
+    let bridge = spawn(handle_until(
+        client,
+        device,
+        health,
+        AshStreamOptions::default(),
+        async {
+            let _ = close_rx.await;
+        },
+    ));
+    let mut host = create_ash_stream(host);
+
+    host.send(Frame::rst()).await.expect("Expected to send RST");
+    let _ = next_frame(&mut host).await;
+    close_tx
+        .send(())
+        .expect("Expected the bridge to be running");
+
+    let frame = next_frame(&mut host).await;
+    assert!(matches!(frame, Frame::Error { code, .. } if code == ERROR_BRIDGE_CLOSED));
+    timeout(Duration::from_secs(5), bridge)
+        .await
+        .expect("Expected the bridge to stop")
+        .expect("Expected to join the bridge task")
+        .expect("Expected the session to close cleanly");
+}
+
+#[tokio::test]
+async fn it_reports_the_reset_code_given_by_the_ncp() {
+    let device = scripted_device(&[
+        &[0x00, RESET_WATCHDOG, 0xA7],
+        RESET_RESPONSES[1],
+        RESET_RESPONSES[2],
+        RESET_RESPONSES[3],
+    ]);
+    let (_actor, device) =
+        spi_device_handle(device, NcpOptions::default()).expect("Expected to spawn the SPI actor");
+    let health = Health::new(&device);
+    let (host, client) = duplex(1024);
+    let _bridge = spawn(handle(client, device, health, AshStreamOptions::default()));
+    let mut host = create_ash_stream(host);
+
+    host.send(Frame::rst()).await.expect("Expected to send RST");
+    let frame = next_frame(&mut host).await;
+    assert!(matches!(frame, Frame::RstAck { code, .. } if code == RESET_WATCHDOG));
+}
+
+#[tokio::test]
+async fn it_asks_the_host_to_reset_when_the_ncp_resets_unexpectedly() {
+    let device = scripted_device(&[
+        RESET_RESPONSES[0],
+        RESET_RESPONSES[1],
+        RESET_RESPONSES[2],
+        RESET_RESPONSES[3],
+        // The NCP resets instead of answering the command
+        &[0x00, RESET_WATCHDOG, 0xA7],
+    ]);
+    let (_actor, device) =
+        spi_device_handle(device, NcpOptions::default()).expect("Expected to spawn the SPI actor");
+    let health = Health::new(&device);
+    let (host, client) = duplex(1024);
+    let _bridge = spawn(handle(
+        client,
+        device,
+        health.clone(),
+        AshStreamOptions::default(),
+    ));
+    let mut host = create_ash_stream(host);
+
+    host.send(Frame::rst()).await.expect("Expected to send RST");
+    next_frame(&mut host).await;
+    host.send(Frame::data(
+        FrameNumber::zero(),
+        false,
+        FrameNumber::zero(),
+        BytesMut::from(&[0x01, 0x00, 0x00][..]),
+    ))
+    .await
+    .expect("Expected to send DATA");
+
+    // The DATA is acknowledged before the session fails
+    let frame = next_frame(&mut host).await;
+    assert!(matches!(frame, Frame::Ack { ack_num, .. } if *ack_num == 1));
+    let frame = next_frame(&mut host).await;
+    assert!(matches!(frame, Frame::Error { code, .. } if code == RESET_WATCHDOG));
+    assert_eq!(health.report().ash_state, AshState::Failed);
+}
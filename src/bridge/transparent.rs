@@ -0,0 +1,140 @@
+use super::reset_reason;
+use crate::{
+    health::{AshState, Health},
+    spi::{SpiDeviceHandle, MAX_EXTENDED_FRAME_LEN, MAX_PAYLOAD_LEN},
+};
+use anyhow::Result;
+use bytes::Bytes;
+use futures::{SinkExt, StreamExt};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    pin,
+};
+use tokio_util::codec::{Framed, LengthDelimitedCodec};
+use tracing::{debug, info, trace, warn};
+
+/// Frame messages to and from the host with a big-endian two byte length,
+/// long enough for the extended EZSP frames the NCP may answer with.
+fn transparent_codec() -> LengthDelimitedCodec {
+    LengthDelimitedCodec::builder()
+        .length_field_length(2)
+        .max_frame_length(MAX_EXTENDED_FRAME_LEN as usize)
+        .new_codec()
+}
+
+/// Relay EZSP frames between a host and the NCP without ASH, until the host
+/// disconnects.
+///
+/// Every message in either direction is a big-endian two byte length
+/// followed by that many bytes, and each message from the host is answered
+/// with exactly one message:
+///
+/// - A message with an EZSP frame is sent to the NCP, and answered with the
+///   NCP's response.
+/// - An empty message resets the NCP, and is answered with the single byte
+///   reset code the NCP reports.
+/// - An empty answer means the NCP could not handle the message, such as
+///   after resetting itself or failing to come back from a reset, and must be
+///   reset by the host.
+///
+/// Answers may be up to `MAX_EXTENDED_FRAME_LEN` bytes long, but an EZSP
+/// frame from the host must fit in `MAX_PAYLOAD_LEN` bytes. A longer frame
+/// ends the session.
+///
+/// The host is responsible for everything else ASH would take care of. In
+/// particular, callbacks are only delivered in answer to the host polling
+/// for them. If the SPI bus itself fails, the session ends with that error.
+pub async fn handle_transparent<T>(client: T, device: SpiDeviceHandle, health: Health) -> Result<()>
+where
+    T: AsyncRead + AsyncWrite + Send,
+{
+    health.set_ash_state(AshState::Failed);
+    let res = relay(client, &device, &health).await;
+    health.set_ash_state(AshState::Disconnected);
+    info!("Transparent session ended");
+    res
+}
+
+async fn relay<T>(client: T, device: &SpiDeviceHandle, health: &Health) -> Result<()>
+where
+    T: AsyncRead + AsyncWrite,
+{
+    let framed = Framed::new(client, transparent_codec());
+    pin!(framed);
+    while let Some(message) = framed.next().await {
+        let message = message?;
+        let answer = if message.is_empty() {
+            debug!("Resetting the NCP at the request of the host");
+            match device.reset_normal().await {
+                Ok(code) => {
+                    health.set_ash_state(AshState::Connected);
+                    Bytes::copy_from_slice(&[code])
+                }
+                Err(e) => {
+                    warn!(error = ?e, "Failed to reset the NCP: {}", e);
+                    health.set_ash_state(AshState::Failed);
+                    if e.is_transfer() {
+                        return Err(e.into());
+                    }
+                    Bytes::new()
+                }
+            }
+        } else if message.len() > MAX_PAYLOAD_LEN {
+            warn!(
+                len = message.len(),
+                "Host sent a {} byte EZSP frame, too long for the NCP, closing the session",
+                message.len()
+            );
+            return Ok(());
+        } else {
+            trace!(len = message.len(), "Forwarding EZSP frame to the NCP");
+            match device.send_frame(message.freeze()).await {
+                Ok(response) => response,
+                Err(e) => {
+                    let code = reset_reason(&e, device.ncp_state()).ok_or(e)?;
+                    warn!(code, "NCP needs a reset, telling the host");
+                    health.set_ash_state(AshState::Failed);
+                    Bytes::new()
+                }
+            }
+        };
+        framed.send(answer).await?;
+    }
+    debug!("Host closed the transparent session");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::duplex;
+
+    #[tokio::test]
+    async fn it_carries_messages_longer_than_a_length_byte() {
+        let (host, bridge) = duplex(4096);
+        let mut host = Framed::new(host, transparent_codec());
+        let mut bridge = Framed::new(bridge, transparent_codec());
+        let long = Bytes::from(vec![0x5A; MAX_EXTENDED_FRAME_LEN as usize]);
+
+        host.send(long.clone())
+            .await
+            .expect("Expected to send a long message");
+        let received = bridge
+            .next()
+            .await
+            .expect("Expected a message")
+            .expect("Expected the message to decode");
+        assert_eq!(received, long);
+
+        bridge
+            .send(long.clone())
+            .await
+            .expect("Expected to answer with a long message");
+        let received = host
+            .next()
+            .await
+            .expect("Expected a message")
+            .expect("Expected the message to decode");
+        assert_eq!(received, long);
+    }
+}
@@ -1,5 +1,5 @@
 use std::{
-    cell::UnsafeCell,
+    fmt::{self, LowerHex},
     iter::Enumerate,
     ops::{Deref, DerefMut, RangeFrom},
 };
@@ -7,43 +7,52 @@ use std::{
 use bytes::{buf::IntoIter, Bytes};
 use nom::{Compare, InputIter, InputLength, InputTake, Slice};
 
+use super::hexdump::hexdump;
+
 /// Wrapper around a Bytes struct that implements the necessary traits to use
 /// with the nom parser library.
-#[derive(Debug, Default)]
-pub struct Buffer(UnsafeCell<Bytes>);
+///
+/// Parsing never modifies a buffer; the pieces taken from it share its
+/// storage instead.
+#[derive(Debug, Default, Clone)]
+pub struct Buffer(Bytes);
 
 impl Buffer {
-    fn borrow(&self) -> &Bytes {
-        unsafe { &*self.0.get() }
-    }
-
-    #[allow(clippy::mut_from_ref)]
-    unsafe fn borrow_mut(&self) -> &mut Bytes {
-        &mut *self.0.get()
-    }
-
+    /// Create an empty buffer. This can be used to define a `const` buffer.
     pub const fn new() -> Self {
-        Buffer(UnsafeCell::new(Bytes::new()))
+        Buffer(Bytes::new())
     }
 
+    /// Create a buffer over static data without copying it. This can be used
+    /// to define a `const` buffer, such as a test fixture.
     pub const fn from_static(bytes: &'static [u8]) -> Self {
-        Buffer(UnsafeCell::new(Bytes::from_static(bytes)))
+        Buffer(Bytes::from_static(bytes))
     }
 
     pub fn into_inner(self) -> Bytes {
-        self.0.into_inner()
+        self.0
+    }
+
+    /// Format the buffer as a hex dump with `width` bytes per line, for
+    /// debugging.
+    pub fn hexdump(&self, width: usize) -> String {
+        hexdump(&self.0, width)
     }
 }
 
-impl From<Bytes> for Buffer {
-    fn from(value: Bytes) -> Self {
-        Self(value.into())
+/// Formats the buffer as a single line of hex, without separators.
+impl LowerHex for Buffer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for byte in self.0.iter() {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
     }
 }
 
-impl Clone for Buffer {
-    fn clone(&self) -> Self {
-        Self(self.borrow().clone().into())
+impl From<Bytes> for Buffer {
+    fn from(value: Bytes) -> Self {
+        Self(value)
     }
 }
 
@@ -51,20 +60,19 @@ impl Deref for Buffer {
     type Target = Bytes;
 
     fn deref(&self) -> &Self::Target {
-        self.borrow()
+        &self.0
     }
 }
 
 impl DerefMut for Buffer {
     fn deref_mut(&mut self) -> &mut Self::Target {
-        self.0.get_mut()
+        &mut self.0
     }
 }
 
 impl Slice<RangeFrom<usize>> for Buffer {
     fn slice(&self, range: RangeFrom<usize>) -> Self {
-        // This usage of Slice is different from BufferMut, as we are OK if the parent Buffer overlaps with its children
-        Self::from(self.deref().slice(range))
+        Self(self.0.slice(range))
     }
 }
 
@@ -80,7 +88,7 @@ impl InputIter for Buffer {
     }
 
     fn iter_elements(&self) -> Self::IterElem {
-        self.borrow().clone().into_iter()
+        self.0.clone().into_iter()
     }
 
     fn position<P>(&self, predicate: P) -> Option<usize>
@@ -107,14 +115,11 @@ impl InputLength for Buffer {
 
 impl InputTake for Buffer {
     fn take(&self, count: usize) -> Self {
-        let inner = unsafe { self.borrow_mut().split_to(count) };
-        Self(inner.into())
+        Self(self.0.slice(..count))
     }
 
     fn take_split(&self, count: usize) -> (Self, Self) {
-        let inner = unsafe { self.borrow_mut().split_to(count) };
-        let prefix = Self(inner.into());
-        (self.clone(), prefix)
+        (Self(self.0.slice(count..)), Self(self.0.slice(..count)))
     }
 }
 
@@ -130,3 +135,43 @@ where
         (self.as_ref()).compare_no_case(t.as_ref())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EMPTY: Buffer = Buffer::new();
+    const RESPONSE: Buffer = Buffer::from_static(&[0x01, 0x02, 0x03]);
+
+    #[test]
+    fn it_leaves_the_buffer_untouched_when_taking_from_it() {
+        let buf = RESPONSE;
+        let (rest, prefix) = buf.take_split(1);
+        let taken = buf.take(2);
+
+        assert_eq!(&buf[..], &[0x01, 0x02, 0x03]);
+        assert_eq!(&prefix[..], &[0x01]);
+        assert_eq!(&rest[..], &[0x02, 0x03]);
+        assert_eq!(&taken[..], &[0x01, 0x02]);
+    }
+
+    #[test]
+    fn it_builds_const_buffers() {
+        assert!(EMPTY.is_empty());
+        assert_eq!(RESPONSE.input_len(), 3);
+    }
+
+    #[test]
+    fn it_formats_as_lower_hex() {
+        assert_eq!(format!("{:x}", RESPONSE), "010203");
+        assert_eq!(format!("{:x}", EMPTY), "");
+    }
+
+    #[test]
+    fn it_dumps_its_contents() {
+        assert_eq!(
+            RESPONSE.hexdump(8),
+            format!("0000: 01 02 03{}  ...", " ".repeat(15))
+        );
+    }
+}
@@ -0,0 +1,250 @@
+use std::{
+    cmp::min,
+    io::{self, Read, Write},
+    iter::Enumerate,
+    ops::{Deref, DerefMut, RangeFrom},
+};
+
+use bytes::{buf::IntoIter, Buf, BytesMut};
+use nom::{Compare, InputIter, InputLength, InputTake, Slice};
+
+/// Wrapper around a BytesMut struct that implements the necessary traits to
+/// use with the nom parser library.
+///
+/// Parsing never modifies a buffer. As BytesMut cannot share its storage, the
+/// pieces taken from it are copies.
+#[derive(Debug, Default, Clone)]
+pub struct BufferMut(BytesMut);
+
+impl BufferMut {
+    pub fn new() -> Self {
+        BufferMut(BytesMut::new())
+    }
+
+    pub fn with_capacity(capacity: usize) -> Self {
+        BufferMut(BytesMut::with_capacity(capacity))
+    }
+
+    pub fn into_inner(self) -> BytesMut {
+        self.0
+    }
+
+    /// Split the buffer into the bytes before `at` and the bytes from `at`
+    /// onwards.
+    ///
+    /// The buffer is consumed, and each half owns its bytes, so writing to
+    /// one half never affects the other.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `at > self.len()`.
+    pub fn split_at(self, at: usize) -> (BufferMut, BufferMut) {
+        let mut head = self.0;
+        let tail = head.split_off(at);
+        (Self(head), Self(tail))
+    }
+}
+
+impl From<BytesMut> for BufferMut {
+    fn from(value: BytesMut) -> Self {
+        Self(value)
+    }
+}
+
+impl Deref for BufferMut {
+    type Target = BytesMut;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl DerefMut for BufferMut {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+impl Write for BufferMut {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Read for BufferMut {
+    /// Read from the front of the buffer, consuming the bytes that are read.
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = min(buf.len(), self.len());
+        buf[..len].copy_from_slice(&self[..len]);
+        self.advance(len);
+        Ok(len)
+    }
+}
+
+impl Slice<RangeFrom<usize>> for BufferMut {
+    fn slice(&self, range: RangeFrom<usize>) -> Self {
+        Self(BytesMut::from(&self.0[range]))
+    }
+}
+
+impl InputIter for BufferMut {
+    type Item = u8;
+
+    type Iter = Enumerate<Self::IterElem>;
+
+    type IterElem = IntoIter<BytesMut>;
+
+    fn iter_indices(&self) -> Self::Iter {
+        self.iter_elements().enumerate()
+    }
+
+    fn iter_elements(&self) -> Self::IterElem {
+        self.0.clone().into_iter()
+    }
+
+    fn position<P>(&self, predicate: P) -> Option<usize>
+    where
+        P: Fn(Self::Item) -> bool,
+    {
+        self.iter().position(|b| predicate(*b))
+    }
+
+    fn slice_index(&self, count: usize) -> Result<usize, nom::Needed> {
+        if self.len() >= count {
+            Ok(count)
+        } else {
+            Err(nom::Needed::new(count - self.len()))
+        }
+    }
+}
+
+impl InputLength for BufferMut {
+    fn input_len(&self) -> usize {
+        self.len()
+    }
+}
+
+impl InputTake for BufferMut {
+    fn take(&self, count: usize) -> Self {
+        Self(BytesMut::from(&self.0[..count]))
+    }
+
+    fn take_split(&self, count: usize) -> (Self, Self) {
+        let (prefix, rest) = self.clone().split_at(count);
+        (rest, prefix)
+    }
+}
+
+impl<T> Compare<T> for BufferMut
+where
+    T: AsRef<[u8]>,
+{
+    fn compare(&self, t: T) -> nom::CompareResult {
+        (self.as_ref()).compare(t.as_ref())
+    }
+
+    fn compare_no_case(&self, t: T) -> nom::CompareResult {
+        (self.as_ref()).compare_no_case(t.as_ref())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nom::{bytes::complete::take, IResult};
+    use std::io::{Read, Write};
+
+    #[test]
+    fn it_takes_a_prefix_and_leaves_the_rest() {
+        let input = BufferMut::from(BytesMut::from(&[0x01, 0x02, 0x03][..]));
+        let res: IResult<BufferMut, BufferMut> = take(2usize)(input);
+        let (rest, prefix) = res.expect("Expected to take two bytes");
+
+        assert_eq!(&prefix[..], &[0x01, 0x02]);
+        assert_eq!(&rest[..], &[0x03]);
+    }
+
+    #[test]
+    fn it_leaves_the_buffer_untouched_when_taking_from_it() {
+        let buf = BufferMut::from(BytesMut::from(&[0x01, 0x02, 0x03][..]));
+        let taken = InputTake::take(&buf, 2);
+        let sliced = buf.slice(1..);
+
+        assert_eq!(&buf[..], &[0x01, 0x02, 0x03]);
+        assert_eq!(&taken[..], &[0x01, 0x02]);
+        assert_eq!(&sliced[..], &[0x02, 0x03]);
+    }
+
+    #[test]
+    fn it_never_aliases_the_pieces_taken_from_it() {
+        let mut buf = BufferMut::from(BytesMut::from(&[0x01, 0x02, 0x03, 0x04][..]));
+        let mut taken = InputTake::take(&buf, 2);
+        let (mut suffix, mut prefix) = buf.take_split(2);
+        let mut sliced = buf.slice(1..);
+
+        taken[0] = 0xAA;
+        prefix[0] = 0xBB;
+        suffix[0] = 0xCC;
+        sliced[0] = 0xDD;
+        buf[3] = 0xEE;
+
+        assert_eq!(&taken[..], &[0xAA, 0x02]);
+        assert_eq!(&prefix[..], &[0xBB, 0x02]);
+        assert_eq!(&suffix[..], &[0xCC, 0x04]);
+        assert_eq!(&sliced[..], &[0xDD, 0x03, 0x04]);
+        assert_eq!(&buf[..], &[0x01, 0x02, 0x03, 0xEE]);
+    }
+
+    #[test]
+    fn it_splits_into_disjoint_halves() {
+        let buf = BufferMut::from(BytesMut::from(&[0x01, 0x02, 0x03][..]));
+        let (mut head, mut tail) = buf.split_at(1);
+
+        head[0] = 0xAA;
+        head.extend_from_slice(&[0xBB]);
+        tail[0] = 0xCC;
+
+        assert_eq!(&head[..], &[0xAA, 0xBB]);
+        assert_eq!(&tail[..], &[0xCC, 0x03]);
+    }
+
+    #[test]
+    fn it_splits_at_either_end() {
+        let buf = BufferMut::from(BytesMut::from(&[0x01, 0x02][..]));
+        let (head, tail) = buf.clone().split_at(0);
+        assert!(head.is_empty());
+        assert_eq!(&tail[..], &[0x01, 0x02]);
+
+        let (head, tail) = buf.split_at(2);
+        assert_eq!(&head[..], &[0x01, 0x02]);
+        assert!(tail.is_empty());
+    }
+
+    #[test]
+    fn it_reads_back_formatted_text() {
+        let mut buf = BufferMut::new();
+        write!(buf, "ncp-{:02X}", 0x0A).expect("Expected write to succeed");
+
+        let mut text = String::new();
+        buf.read_to_string(&mut text)
+            .expect("Expected read to succeed");
+
+        assert_eq!(text, "ncp-0A");
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn it_reads_no_more_than_requested() {
+        let mut buf = BufferMut::from(BytesMut::from(&[0x01, 0x02, 0x03][..]));
+        let mut out = [0u8; 2];
+
+        assert_eq!(buf.read(&mut out).expect("Expected read to succeed"), 2);
+        assert_eq!(out, [0x01, 0x02]);
+        assert_eq!(&buf[..], &[0x03]);
+    }
+}
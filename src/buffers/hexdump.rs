@@ -0,0 +1,63 @@
+use std::fmt::Write;
+
+/// Format `data` as a hex dump with `width` bytes per line.
+///
+/// Each line shows the offset of its first byte, the bytes in hex, and the
+/// bytes as ASCII, with `.` in place of unprintable characters:
+///
+/// ```text
+/// 0000: DE AD BE EF  ....
+/// ```
+///
+/// An empty slice produces an empty string.
+pub fn hexdump(data: &[u8], width: usize) -> String {
+    let width = width.max(1);
+    let mut out = String::new();
+    for (line, chunk) in data.chunks(width).enumerate() {
+        if line > 0 {
+            out.push('\n');
+        }
+        let _ = write!(out, "{:04X}:", line * width);
+        for byte in chunk {
+            let _ = write!(out, " {:02X}", byte);
+        }
+        // Pad short lines so the ASCII column stays aligned
+        for _ in chunk.len()..width {
+            out.push_str("   ");
+        }
+        out.push_str("  ");
+        out.extend(chunk.iter().map(|&b| {
+            if b.is_ascii_graphic() || b == b' ' {
+                b as char
+            } else {
+                '.'
+            }
+        }));
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_dumps_bytes_with_their_ascii() {
+        assert_eq!(
+            hexdump(&[0xDE, 0xAD, 0x4F, 0x4B], 4),
+            "0000: DE AD 4F 4B  ..OK"
+        );
+    }
+
+    #[test]
+    fn it_wraps_and_pads_lines_to_the_width() {
+        let dump = hexdump(b"ABCDE", 2);
+
+        assert_eq!(dump, "0000: 41 42  AB\n0002: 43 44  CD\n0004: 45     E");
+    }
+
+    #[test]
+    fn it_dumps_an_empty_buffer_as_an_empty_string() {
+        assert_eq!(hexdump(&[], 16), "");
+    }
+}
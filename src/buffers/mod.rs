@@ -1,3 +1,19 @@
+//! Byte buffers that can be fed to `nom` parsers without copying.
+//!
+//! Use [`Buffer`] for data that is only read, such as responses read from the
+//! SPI bus; it wraps `Bytes`, so slices of it are cheap to clone and share.
+//! Use [`BufferMut`] when the parsed pieces need to be modified afterwards; it
+//! wraps `BytesMut`, so each piece owns a disjoint part of the input.
+//!
+//! Neither buffer uses `unsafe` code, so the pieces handed out by `take`,
+//! `take_split` and `slice` can never alias mutably. The tests for this module
+//! are kept Miri-clean; run them with `just miri`.
+#![forbid(unsafe_code)]
+
 mod buffer;
+mod buffer_mut;
+mod hexdump;
 
 pub use self::buffer::Buffer;
+pub use self::buffer_mut::BufferMut;
+pub use self::hexdump::hexdump;
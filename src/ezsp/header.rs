@@ -0,0 +1,188 @@
+use nom::{
+    number::complete::{le_u16, u8},
+    sequence::tuple,
+    IResult,
+};
+use std::fmt::Display;
+
+/// The length of an EZSP v8 frame header.
+pub const HEADER_LEN: usize = 5;
+
+/// Set in the low frame control byte of responses and callbacks.
+const FC_RESPONSE: u16 = 0x0080;
+const FC_NETWORK_INDEX: u16 = 0x0060;
+const FC_CALLBACK_TYPE: u16 = 0x0018;
+const FC_CALLBACK_PENDING: u16 = 0x0004;
+const FC_TRUNCATED: u16 = 0x0002;
+const FC_OVERFLOW: u16 = 0x0001;
+const FC_FRAME_FORMAT_VERSION: u16 = 0x0300;
+
+/// How a response frame relates to a callback.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CallbackType {
+    /// Not a callback.
+    None,
+    /// A callback returned in reply to a `callback` command.
+    Synchronous,
+    /// A callback sent by the NCP of its own accord.
+    Asynchronous,
+    /// A callback type not defined by EZSP.
+    Reserved,
+}
+
+/// The header at the start of an EZSP v8 frame: a sequence number, the two
+/// frame control bytes and the frame ID.
+///
+/// This only reads the header, so the frame can still be forwarded as-is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EzspHeader {
+    sequence: u8,
+    frame_control: u16,
+    frame_id: u16,
+}
+
+impl EzspHeader {
+    /// Read the header at the start of `frame`, the body of a DATA frame
+    /// once it has been derandomized.
+    ///
+    /// Returns `None` if `frame` is too short to hold a header.
+    pub fn parse(frame: &[u8]) -> Option<EzspHeader> {
+        header(frame).ok().map(|(_, header)| header)
+    }
+
+    pub fn sequence(&self) -> u8 {
+        self.sequence
+    }
+
+    /// The frame control bytes, with the low byte in the lower 8 bits.
+    pub fn frame_control(&self) -> u16 {
+        self.frame_control
+    }
+
+    pub fn frame_id(&self) -> u16 {
+        self.frame_id
+    }
+
+    /// Whether the frame was sent by the NCP, rather than being a command.
+    pub fn is_response(&self) -> bool {
+        self.frame_control & FC_RESPONSE != 0
+    }
+
+    pub fn network_index(&self) -> u8 {
+        ((self.frame_control & FC_NETWORK_INDEX) >> 5) as u8
+    }
+
+    pub fn callback_type(&self) -> CallbackType {
+        if !self.is_response() {
+            return CallbackType::None;
+        }
+        match (self.frame_control & FC_CALLBACK_TYPE) >> 3 {
+            0 => CallbackType::None,
+            1 => CallbackType::Synchronous,
+            2 => CallbackType::Asynchronous,
+            _ => CallbackType::Reserved,
+        }
+    }
+
+    /// Whether the NCP has callbacks waiting to be fetched.
+    pub fn callback_pending(&self) -> bool {
+        self.is_response() && self.frame_control & FC_CALLBACK_PENDING != 0
+    }
+
+    /// Whether the NCP cut the response short for lack of space.
+    pub fn truncated(&self) -> bool {
+        self.is_response() && self.frame_control & FC_TRUNCATED != 0
+    }
+
+    /// Whether the NCP ran out of memory since the last response.
+    pub fn overflow(&self) -> bool {
+        self.is_response() && self.frame_control & FC_OVERFLOW != 0
+    }
+
+    /// The version of the frame format, which is 1 for EZSP v8 frames.
+    pub fn frame_format_version(&self) -> u8 {
+        ((self.frame_control & FC_FRAME_FORMAT_VERSION) >> 8) as u8
+    }
+}
+
+impl Display for EzspHeader {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "seq={} fc={:#06x} id={:#06x}",
+            self.sequence, self.frame_control, self.frame_id
+        )
+    }
+}
+
+fn header(input: &[u8]) -> IResult<&[u8], EzspHeader> {
+    let (rest, (sequence, frame_control, frame_id)) = tuple((u8, le_u16, le_u16))(input)?;
+    Ok((
+        rest,
+        EzspHeader {
+            sequence,
+            frame_control,
+            frame_id,
+        },
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_parses_a_command_header() {
+        // version, asking for EZSP v8
+        let frame = [0x00, 0x00, 0x01, 0x00, 0x00, 0x08];
+        let header = EzspHeader::parse(&frame).expect("Expected a header");
+
+        assert_eq!(header.sequence(), 0);
+        assert_eq!(header.frame_control(), 0x0100);
+        assert_eq!(header.frame_id(), 0x0000);
+        assert_eq!(header.frame_format_version(), 1);
+        assert!(!header.is_response());
+        assert_eq!(header.callback_type(), CallbackType::None);
+    }
+
+    #[test]
+    fn it_parses_a_response_header() {
+        // getNetworkParameters response on network 1, with a callback waiting
+        let frame = [0x2A, 0xA4, 0x01, 0x28, 0x00, 0x00, 0x01];
+        let header = EzspHeader::parse(&frame).expect("Expected a header");
+
+        assert_eq!(header.sequence(), 0x2A);
+        assert_eq!(header.frame_id(), 0x0028);
+        assert!(header.is_response());
+        assert_eq!(header.network_index(), 1);
+        assert!(header.callback_pending());
+        assert!(!header.truncated());
+        assert!(!header.overflow());
+        assert_eq!(header.callback_type(), CallbackType::None);
+    }
+
+    #[test]
+    fn it_parses_an_asynchronous_callback_header() {
+        // stackStatusHandler reporting NETWORK_UP
+        let frame = [0x05, 0x90, 0x01, 0x19, 0x00, 0x90];
+        let header = EzspHeader::parse(&frame).expect("Expected a header");
+
+        assert_eq!(header.frame_id(), 0x0019);
+        assert_eq!(header.callback_type(), CallbackType::Asynchronous);
+    }
+
+    #[test]
+    fn it_leaves_the_parameters_after_the_header() {
+        let frame = [0x05, 0x90, 0x01, 0x19, 0x00, 0x90];
+        let header = EzspHeader::parse(&frame).expect("Expected a header");
+
+        assert_eq!(header.sequence(), frame[0]);
+        assert_eq!(&frame[HEADER_LEN..], &[0x90]);
+    }
+
+    #[test]
+    fn it_rejects_a_truncated_header() {
+        assert!(EzspHeader::parse(&[0x05, 0x90, 0x01, 0x19]).is_none());
+        assert!(EzspHeader::parse(&[]).is_none());
+    }
+}
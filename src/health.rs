@@ -0,0 +1,192 @@
+use crate::spi::{SpiDeviceHandle, State};
+use std::{
+    sync::{
+        atomic::{AtomicU64, AtomicU8, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::{io::AsyncWriteExt, net::TcpListener, spawn, time::sleep};
+use tracing::{debug, error};
+
+const HEALTHY_RESPONSE: &[u8] = b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nOK";
+const UNHEALTHY_RESPONSE: &[u8] =
+    b"HTTP/1.1 503 Service Unavailable\r\nContent-Length: 11\r\n\r\nUNAVAILABLE";
+
+/// How long to wait before accepting again after a failed accept, so that an
+/// error that persists, such as running out of file descriptors, does not
+/// spin the loop.
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// The state of the ASH session with the host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum AshState {
+    /// No host is connected.
+    Disconnected = 0,
+    /// A host is connected, but has not completed a reset.
+    Failed = 1,
+    /// A host is connected and exchanging DATA frames.
+    Connected = 2,
+}
+
+impl From<u8> for AshState {
+    fn from(value: u8) -> Self {
+        match value {
+            1 => AshState::Failed,
+            2 => AshState::Connected,
+            _ => AshState::Disconnected,
+        }
+    }
+}
+
+/// A snapshot of the health of the bridge.
+#[derive(Debug, Clone, Copy)]
+pub struct HealthReport {
+    pub ash_state: AshState,
+    pub ncp_state: State,
+    pub bootloader: bool,
+    /// When the NCP last completed a transaction successfully.
+    pub last_ncp_transaction: Option<SystemTime>,
+}
+
+/// A shared view of the bridge and NCP state, for supervisors to check that
+/// the bridge is not wedged.
+///
+/// This does not hold on to the SPI device, so it does not keep the SPI actor
+/// running.
+#[derive(Clone)]
+pub struct Health {
+    ash_state: Arc<AtomicU8>,
+    ncp_state: Arc<AtomicU8>,
+    last_transaction: Arc<AtomicU64>,
+}
+
+impl Health {
+    pub fn new(device: &SpiDeviceHandle) -> Health {
+        Health {
+            ash_state: Arc::new(AtomicU8::new(AshState::Disconnected as u8)),
+            ncp_state: device.shared_ncp_state(),
+            last_transaction: device.shared_last_transaction(),
+        }
+    }
+
+    pub(crate) fn set_ash_state(&self, state: AshState) {
+        self.ash_state.store(state as u8, Ordering::Relaxed);
+    }
+
+    pub fn report(&self) -> HealthReport {
+        let ncp_state = State::from(self.ncp_state.load(Ordering::Relaxed));
+        let last_transaction = match self.last_transaction.load(Ordering::Relaxed) {
+            0 => None,
+            millis => Some(UNIX_EPOCH + Duration::from_millis(millis)),
+        };
+        HealthReport {
+            ash_state: AshState::from(self.ash_state.load(Ordering::Relaxed)),
+            ncp_state,
+            bootloader: ncp_state == State::Bootloader,
+            last_ncp_transaction: last_transaction,
+        }
+    }
+}
+
+fn health_response(health: &Health) -> &'static [u8] {
+    match health.report().ncp_state {
+        State::Unknown => UNHEALTHY_RESPONSE,
+        _ => HEALTHY_RESPONSE,
+    }
+}
+
+/// Answer every connection on `listener` with an HTTP status reflecting the
+/// NCP state, without reading the request.
+///
+/// The bridge is reported unhealthy with a 503 while the NCP state is unknown.
+pub async fn serve_health(listener: TcpListener, health: Health) {
+    loop {
+        let (mut client, client_addr) = match listener.accept().await {
+            Ok(v) => v,
+            Err(e) => {
+                error!(error = ?e, "Failed to accept health check connection: {}", e);
+                sleep(ACCEPT_RETRY_DELAY).await;
+                continue;
+            }
+        };
+        let response = health_response(&health);
+        spawn(async move {
+            if let Err(e) = client.write_all(response).await {
+                debug!(error = ?e, %client_addr, "Failed to write health check response: {}", e);
+            }
+            let _ = client.shutdown().await;
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::{io::AsyncReadExt, net::TcpStream};
+
+    fn health_with(ash_state: AshState, ncp_state: State, last_transaction: u64) -> Health {
+        Health {
+            ash_state: Arc::new(AtomicU8::new(ash_state as u8)),
+            ncp_state: Arc::new(AtomicU8::new(ncp_state as u8)),
+            last_transaction: Arc::new(AtomicU64::new(last_transaction)),
+        }
+    }
+
+    async fn request_health(state: State) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("Expected listener to bind");
+        let addr = listener.local_addr().expect("Expected a local address");
+        let health = health_with(AshState::Disconnected, state, 0);
+        let server = spawn(serve_health(listener, health));
+
+        let mut client = TcpStream::connect(addr)
+            .await
+            .expect("Expected to connect to health endpoint");
+        let mut response = String::new();
+        client
+            .read_to_string(&mut response)
+            .await
+            .expect("Expected to read health response");
+        server.abort();
+        response
+    }
+
+    #[tokio::test]
+    async fn it_reports_ok_when_the_ncp_state_is_known() {
+        let response = request_health(State::Normal).await;
+        assert_eq!(response, "HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nOK");
+    }
+
+    #[tokio::test]
+    async fn it_reports_unavailable_when_the_ncp_state_is_unknown() {
+        let response = request_health(State::Unknown).await;
+        assert!(response.starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+    }
+
+    #[test]
+    fn it_reports_the_bridge_and_ncp_state() {
+        let health = health_with(AshState::Connected, State::Bootloader, 1_500);
+        let report = health.report();
+
+        assert_eq!(report.ash_state, AshState::Connected);
+        assert_eq!(report.ncp_state, State::Bootloader);
+        assert!(report.bootloader);
+        assert_eq!(
+            report.last_ncp_transaction,
+            Some(UNIX_EPOCH + Duration::from_millis(1_500))
+        );
+    }
+
+    #[test]
+    fn it_reports_no_transaction_before_the_ncp_has_responded() {
+        let health = health_with(AshState::Disconnected, State::Unknown, 0);
+        let report = health.report();
+
+        assert_eq!(report.ash_state, AshState::Disconnected);
+        assert!(!report.bootloader);
+        assert!(report.last_ncp_transaction.is_none());
+    }
+}
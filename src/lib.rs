@@ -0,0 +1,14 @@
+#![allow(dead_code)]
+
+pub mod ash;
+pub mod bridge;
+pub mod buffers;
+pub mod ezsp;
+pub mod health;
+pub mod logging;
+pub mod settings;
+pub mod shutdown;
+pub mod spi;
+#[cfg(test)]
+mod test;
+pub mod tls;
@@ -1,20 +1,30 @@
-#![allow(dead_code)]
+use anyhow::{bail, Context, Result};
+use ezsp_spi_driver::{
+    ash::AshStreamOptions,
+    bridge::{handle_transparent, handle_until},
+    health::{serve_health, Health},
+    logging::setup_logging,
+    settings::{Mode, Settings},
+    shutdown::shutdown_signal,
+    spi::{
+        create_spi_peripheral, spi_device_handle, NcpOptions, Peripheral, SpiDeviceActor,
+        SpiDeviceHandle,
+    },
+    tls::{accept_tls, create_tls_acceptor},
+};
+use std::{any::Any, future::Future, net::SocketAddr, time::Duration};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpListener, TcpStream},
+    pin, select, spawn,
+    sync::oneshot,
+    time::timeout,
+};
+use tokio_rustls::TlsAcceptor;
+use tracing::{error, info, instrument, warn};
 
-mod ash;
-mod bridge;
-mod buffers;
-mod logging;
-mod settings;
-mod spi;
-mod test;
-
-use anyhow::{Context, Result};
-use bridge::handle;
-use logging::setup_logging;
-use settings::Settings;
-use spi::{create_spi_peripheral, spi_device_handle};
-use tokio::net::TcpListener;
-use tracing::{error, info, instrument};
+/// How long the SPI actor is given to stop once its handles are dropped.
+const ACTOR_STOP_TIMEOUT: Duration = Duration::from_secs(5);
 
 /// Bridge starts by listening on the chosen port for a connection.
 /// Once a connection is established, the server initializes the SPI device and
@@ -57,8 +67,11 @@ use tracing::{error, info, instrument};
 #[instrument]
 #[tokio::main]
 async fn main() -> Result<()> {
-    let settings = Settings::new()?;
+    let settings = Settings::from_args()?;
     setup_logging(settings.loglevel);
+    settings.validate().context("Invalid settings")?;
+
+    let tls = settings.tls.as_ref().map(create_tls_acceptor).transpose()?;
 
     let addr = settings.socket_addr();
     let listener = TcpListener::bind(addr).await.map_err(|e| {
@@ -68,26 +81,176 @@ async fn main() -> Result<()> {
     let peripheral = create_spi_peripheral(&settings.spi)
         .await
         .context("Unable to open SPI peripheral")?;
-    let (actor, device) = spi_device_handle(peripheral);
+    let (mut actor, mut device) = spi_device_handle(peripheral, NcpOptions::from(&settings.spi))
+        .context("Unable to start the SPI device actor")?;
     info!("Server listening at {}", addr);
 
+    let health = Health::new(&device);
+    if let Some(health_addr) = settings.health_socket_addr() {
+        let health_listener = TcpListener::bind(health_addr).await.map_err(|e| {
+            error!({ error = ?e }, "Unable to bind health check listener at {}: {}", health_addr, e);
+            e
+        })?;
+        spawn(serve_health(health_listener, health.clone()));
+        info!("Health check listening at {}", health_addr);
+    }
+
+    let shutdown = shutdown_signal().context("Unable to install signal handlers")?;
+    pin!(shutdown);
+
     loop {
-        let (client, client_addr) = loop {
-            match listener.accept().await {
-                Ok(v) => break v,
-                Err(e) => {
-                    error!(error = ?e, "Failed to accept connection from client: {}", e);
-                }
-            };
+        let (client, client_addr) = select! {
+            _ = &mut shutdown => break,
+            accepted = accept_client(&listener) => accepted,
         };
         info!(%client_addr, "Received connection from {}", client_addr);
 
-        if let Err(e) = handle(client, device.clone()).await {
-            error!(error = %e, %client_addr, "Bridge encountered an unrecoverable error: {}", e);
+        let (close_tx, close_rx) = oneshot::channel();
+        let bridge = serve_client(
+            client,
+            client_addr,
+            tls.as_ref(),
+            &settings,
+            device.clone(),
+            health.clone(),
+            async {
+                let _ = close_rx.await;
+            },
+        );
+        pin!(bridge);
+        let (res, shutting_down) = select! {
+            res = &mut bridge => (res, false),
+            _ = &mut shutdown => {
+                let drain_timeout = settings.drain_timeout();
+                info!(%client_addr, "Waiting up to {:?} for connection to {} to close", drain_timeout, client_addr);
+                let _ = close_tx.send(());
+                let res = timeout(drain_timeout, bridge).await.unwrap_or_else(|_| {
+                    warn!(%client_addr, "Connection to {} did not close in time", client_addr);
+                    Ok(())
+                });
+                (res, true)
+            }
+        };
+
+        // A failed session only ends that connection. If the SPI actor went
+        // down with it, the actor is restarted for the next one.
+        let actor_alive = device.is_alive();
+        match res {
+            Err(e) if actor_alive => {
+                error!(error = %e, %client_addr, "Bridge session with {} failed: {}", client_addr, e);
+            }
+            Err(e) => {
+                warn!(error = %e, %client_addr, "Connection to {} failed with the SPI actor: {}", client_addr, e);
+            }
+            Ok(()) => info!(%client_addr, "Connection to {} closed", client_addr),
+        }
+        if shutting_down {
             break;
-        } else {
-            info!(%client_addr, "Connection to {} closed", client_addr);
         }
+        if !actor_alive {
+            (actor, device) = restart_actor(actor, &device, &settings).await?;
+        }
+    }
+
+    drop(device);
+    match timeout(ACTOR_STOP_TIMEOUT, actor.into_inner()).await {
+        Ok(Ok(_)) => {}
+        Ok(Err(payload)) => bail!(
+            "SPI device actor did not shut down cleanly: {}",
+            panic_message(payload.as_ref())
+        ),
+        Err(_) => bail!("SPI device actor did not stop in time"),
     }
+    info!("Server shut down");
     Ok(())
 }
+
+/// The message a panic was raised with, if it has one.
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message
+    } else {
+        "no panic message"
+    }
+}
+
+/// Replace an SPI actor that has stopped, reopening the peripheral it lost.
+async fn restart_actor(
+    actor: SpiDeviceActor<Peripheral>,
+    device: &SpiDeviceHandle,
+    settings: &Settings,
+) -> Result<(SpiDeviceActor<Peripheral>, SpiDeviceHandle)> {
+    match timeout(ACTOR_STOP_TIMEOUT, actor.into_inner()).await {
+        Ok(Ok(_)) => {}
+        Ok(Err(payload)) => {
+            let message = panic_message(payload.as_ref());
+            error!(
+                message,
+                "SPI device actor stopped unexpectedly: {}", message
+            );
+        }
+        Err(_) => warn!("SPI device actor did not stop in time, starting a new one anyway"),
+    }
+    info!("Restarting the SPI device actor");
+    let peripheral = create_spi_peripheral(&settings.spi)
+        .await
+        .context("Unable to reopen SPI peripheral")?;
+    device
+        .respawn(peripheral, NcpOptions::from(&settings.spi))
+        .context("Unable to restart the SPI device actor")
+}
+
+async fn accept_client(listener: &TcpListener) -> (TcpStream, SocketAddr) {
+    loop {
+        match listener.accept().await {
+            Ok(v) => return v,
+            Err(e) => {
+                error!(error = ?e, "Failed to accept connection from client: {}", e);
+            }
+        };
+    }
+}
+
+async fn serve_client(
+    client: TcpStream,
+    client_addr: SocketAddr,
+    tls: Option<&TlsAcceptor>,
+    settings: &Settings,
+    device: SpiDeviceHandle,
+    health: Health,
+    close: impl Future<Output = ()>,
+) -> Result<()> {
+    let mode = settings.mode;
+    let options = settings.ash_stream_options();
+    match tls {
+        Some(acceptor) => match accept_tls(acceptor, client, settings.handshake_timeout()).await {
+            Ok(stream) => bridge_client(stream, device, health, mode, options, close).await,
+            Err(e) => {
+                error!(error = ?e, %client_addr, "TLS handshake with {} failed: {:#}", client_addr, e);
+                Ok(())
+            }
+        },
+        None => bridge_client(client, device, health, mode, options, close).await,
+    }
+}
+
+async fn bridge_client<T>(
+    client: T,
+    device: SpiDeviceHandle,
+    health: Health,
+    mode: Mode,
+    options: AshStreamOptions,
+    close: impl Future<Output = ()>,
+) -> Result<()>
+where
+    T: AsyncRead + AsyncWrite + Send + 'static,
+{
+    match mode {
+        Mode::Ash => handle_until(client, device, health, options, close).await,
+        // Transparent mode has no ERROR frame to close with, so the host is
+        // left to disconnect on its own
+        Mode::Transparent => handle_transparent(client, device, health).await,
+    }
+}
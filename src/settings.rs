@@ -1,17 +1,35 @@
 use anyhow::Result;
+use argh::FromArgs;
 use config::{builder::DefaultState, ConfigBuilder, Environment, File};
 use gpiod::LineId;
-use serde::{de::Visitor, Deserialize, Deserializer};
+use serde::{
+    de::{Unexpected, Visitor},
+    Deserialize, Deserializer,
+};
 use spidev::Spidev;
 use std::{
+    fs, io,
     net::{IpAddr, Ipv4Addr, SocketAddr},
-    path::PathBuf,
+    path::{Path, PathBuf},
     str::FromStr,
+    time::Duration,
 };
+use thiserror::Error;
 use tracing::Level;
 
+use crate::ash::AshStreamOptions;
+
 const LOG_LEVELS: [&'static str; 5] = ["DEBUG", "ERROR", "INFO", "TRACE", "WARN"];
 
+/// The log levels selected by the numbers 1 to 5, from least to most verbose.
+const NUMBERED_LOG_LEVELS: [Level; 5] = [
+    Level::ERROR,
+    Level::WARN,
+    Level::INFO,
+    Level::DEBUG,
+    Level::TRACE,
+];
+
 struct LevelVistor;
 
 impl<'de> Visitor<'de> for LevelVistor {
@@ -27,7 +45,29 @@ impl<'de> Visitor<'de> for LevelVistor {
     where
         E: serde::de::Error,
     {
-        FromStr::from_str(v).map_err(|_| E::unknown_variant(v, &LOG_LEVELS))
+        match v.parse::<u64>() {
+            Ok(number) => self.visit_u64(number),
+            Err(_) => FromStr::from_str(v).map_err(|_| E::unknown_variant(v, &LOG_LEVELS)),
+        }
+    }
+
+    fn visit_u64<E>(self, v: u64) -> Result<Self::Value, E>
+    where
+        E: serde::de::Error,
+    {
+        v.checked_sub(1)
+            .and_then(|i| NUMBERED_LOG_LEVELS.get(i as usize))
+            .copied()
+            .ok_or_else(|| E::invalid_value(Unexpected::Unsigned(v), &self))
+    }
+
+    fn visit_i64<E>(self, v: i64) -> Result<Self::Value, E>
+    where
+        E: serde::de::Error,
+    {
+        u64::try_from(v)
+            .map_err(|_| E::invalid_value(Unexpected::Signed(v), &self))
+            .and_then(|v| self.visit_u64(v))
     }
 }
 
@@ -38,6 +78,20 @@ where
     de.deserialize_string(LevelVistor)
 }
 
+/// Bridge EZSP traffic between a TCP host and an NCP attached over SPI.
+#[derive(Debug, FromArgs)]
+#[argh(
+    note = "Settings are read from config.toml or config.json in the working \
+directory, with config.toml taking priority when both exist. Use --config to \
+read a different file; its format is detected from the extension (toml, json, \
+yaml, ini, ron or json5). Environment variables override file settings."
+)]
+pub struct Args {
+    /// path to the configuration file
+    #[argh(option)]
+    pub config: Option<PathBuf>,
+}
+
 #[derive(Debug, Deserialize)]
 #[serde(default)]
 pub struct Spi {
@@ -47,6 +101,101 @@ pub struct Spi {
     pub int_line: LineId,
     pub reset_line: LineId,
     pub wake_line: LineId,
+    pub speed_hz: u32,
+    /// The lowest speed the bus will fall back to after transaction errors.
+    pub min_speed_hz: u32,
+    /// Number of consecutive transaction errors before lowering the bus
+    /// speed. A value of 0 disables the fallback.
+    pub speed_fallback_errors: u32,
+    /// Milliseconds to wait for the NCP to complete a request before treating
+    /// it as unresponsive.
+    pub request_timeout_ms: u64,
+    /// Number of times an EZSP frame is sent again after the NCP fails to
+    /// respond to it.
+    pub send_retries: u32,
+    /// Microseconds the reset line is held asserted to reset the NCP. Some
+    /// NCPs need 100 or more to start up reliably.
+    pub reset_pulse_us: u64,
+    /// Number of readings of the interrupt line that must agree before a
+    /// callback is fetched. Raise it above 1 if noise on the line causes
+    /// spurious callback requests.
+    pub interrupt_debounce_samples: u32,
+    /// Microseconds between readings of the interrupt line.
+    pub interrupt_debounce_interval_us: u64,
+    /// The longest time, in milliseconds, between checks of the interrupt
+    /// line when the NCP has not signalled a callback. This only matters if
+    /// an interrupt edge is missed: a shorter interval finds such a callback
+    /// sooner, at the cost of waking up more often while idle.
+    pub callback_poll_interval_ms: u64,
+    /// The SPI protocol versions the NCP may report. Only version 2 is known
+    /// to work, add others to try newer NCPs.
+    pub spi_protocol_versions: Vec<u8>,
+    /// Ask the NCP for its EZSP version after each reset, to log it. Turn
+    /// this off if the host must be the first to send an EZSP command.
+    pub query_ezsp_version: bool,
+}
+
+/// A setting that cannot work on this machine, naming the offending field.
+#[derive(Debug, Error)]
+pub enum ValidationError {
+    #[error("spi.device: {path} cannot be found: {source}")]
+    MissingDevice { path: PathBuf, source: io::Error },
+    #[error("spi.gpiochip: {path} cannot be opened: {source}")]
+    InaccessibleGpioChip { path: PathBuf, source: io::Error },
+    #[error("spi.{field}: line {line} is already used by spi.{other}")]
+    DuplicateLine {
+        field: &'static str,
+        other: &'static str,
+        line: LineId,
+    },
+}
+
+impl Spi {
+    /// Check that the SPI device and GPIO chip exist and that each GPIO line
+    /// is used for one signal only.
+    pub fn validate(&self) -> Result<(), ValidationError> {
+        fs::metadata(&self.device).map_err(|source| ValidationError::MissingDevice {
+            path: self.device.clone(),
+            source,
+        })?;
+        fs::File::open(&self.gpiochip).map_err(|source| ValidationError::InaccessibleGpioChip {
+            path: self.gpiochip.clone(),
+            source,
+        })?;
+
+        let lines = [
+            ("cs_line", self.cs_line),
+            ("int_line", self.int_line),
+            ("reset_line", self.reset_line),
+            ("wake_line", self.wake_line),
+        ];
+        for (i, &(field, line)) in lines.iter().enumerate() {
+            if let Some(&(other, _)) = lines[..i].iter().find(|(_, l)| *l == line) {
+                return Err(ValidationError::DuplicateLine { field, other, line });
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Certificate and private key used to terminate TLS on host connections.
+#[derive(Debug, Deserialize)]
+pub struct Tls {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+/// How the bridge talks to a host.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    /// Run an ASH session with the host, as if it were talking to the NCP
+    /// over a UART.
+    #[default]
+    Ash,
+    /// Relay length-prefixed EZSP frames without ASH, see
+    /// [`crate::bridge::handle_transparent`].
+    Transparent,
 }
 
 #[derive(Debug, Deserialize)]
@@ -54,25 +203,119 @@ pub struct Spi {
 pub struct Settings {
     pub address: IpAddr,
     pub port: u16,
+    /// Port of the HTTP health check endpoint, disabled when unset.
+    pub health_port: Option<u16>,
     pub spi: Spi,
+    pub tls: Option<Tls>,
+    /// Whether hosts speak ASH to the bridge, or send it EZSP frames as is.
+    pub mode: Mode,
+    /// Seconds to wait for an open host connection to close on shutdown.
+    pub drain_timeout_secs: u64,
+    /// Seconds a host may take to complete the TLS handshake before it is
+    /// disconnected.
+    pub handshake_timeout_secs: u64,
+    /// Seconds of inactivity after which a host connection is closed, never
+    /// when unset.
+    pub idle_timeout_secs: Option<u64>,
+    /// The most DATA frames a host may send before waiting for them to be
+    /// acknowledged, from 1 to 7.
+    pub ack_window: u8,
+    /// Milliseconds a DATA frame sent to a host may go unacknowledged before
+    /// it is sent again, at the start of a session. The timeout then adapts
+    /// to how quickly the host acknowledges DATA, between the bounds below.
+    pub retransmit_timeout_ms: u64,
+    /// The shortest the retransmission timeout adapts down to, in
+    /// milliseconds.
+    pub min_retransmit_timeout_ms: u64,
+    /// The longest the retransmission timeout adapts up to, in milliseconds.
+    pub max_retransmit_timeout_ms: u64,
+    /// The most times a DATA frame is sent again before the session with the
+    /// host fails.
+    pub max_retransmits: u32,
+    /// The most frames queued for a host that is slow to read them before it
+    /// is told to stop sending DATA. A deeper queue rides out longer stalls
+    /// on the connection, but holds more frames in memory.
+    pub write_queue_depth: usize,
     #[serde(deserialize_with = "deserialize_level")]
     pub loglevel: Level,
 }
 
 impl Settings {
     pub fn new() -> Result<Settings> {
-        let reader = ConfigBuilder::<DefaultState>::default()
-            .add_source(File::with_name("config").required(false))
-            .add_source(Environment::default())
-            .build()?;
+        Settings::load(None)
+    }
+
+    /// Load the settings using the config file path passed with `--config`,
+    /// falling back to the default config file if it is not provided.
+    pub fn from_args() -> Result<Settings> {
+        Settings::from_parsed_args(argh::from_env())
+    }
+
+    /// Load the settings using arguments that have already been parsed.
+    pub fn from_parsed_args(args: Args) -> Result<Settings> {
+        Settings::load(args.config.as_deref())
+    }
+
+    fn load(config: Option<&Path>) -> Result<Settings> {
+        Settings::load_from(config, Path::new(""))
+    }
+
+    /// Load the settings from `config` if given, otherwise from the default
+    /// config files in `dir`. Later sources take priority over earlier ones.
+    fn load_from(config: Option<&Path>, dir: &Path) -> Result<Settings> {
+        let builder = ConfigBuilder::<DefaultState>::default();
+        let builder = match config {
+            Some(path) => builder.add_source(File::from(path)),
+            None => builder
+                .add_source(File::from(dir.join("config.json")).required(false))
+                .add_source(File::from(dir.join("config.toml")).required(false)),
+        };
+        let reader = builder.add_source(Environment::default()).build()?;
 
         Ok(reader.try_deserialize()?)
     }
 
+    /// Check the settings against this machine, so that a misconfiguration is
+    /// reported before the bridge starts rather than as an IO error later.
+    pub fn validate(&self) -> Result<(), ValidationError> {
+        self.spi.validate()
+    }
+
     pub fn socket_addr(&self) -> SocketAddr {
         SocketAddr::new(self.address, self.port)
     }
 
+    pub fn drain_timeout(&self) -> Duration {
+        Duration::from_secs(self.drain_timeout_secs)
+    }
+
+    pub fn handshake_timeout(&self) -> Duration {
+        Duration::from_secs(self.handshake_timeout_secs)
+    }
+
+    pub fn idle_timeout(&self) -> Option<Duration> {
+        self.idle_timeout_secs.map(Duration::from_secs)
+    }
+
+    /// The settings for each ASH session with a host.
+    pub fn ash_stream_options(&self) -> AshStreamOptions {
+        AshStreamOptions {
+            window_size: self.ack_window,
+            retransmit_timeout: Duration::from_millis(self.retransmit_timeout_ms),
+            min_retransmit_timeout: Duration::from_millis(self.min_retransmit_timeout_ms),
+            max_retransmit_timeout: Duration::from_millis(self.max_retransmit_timeout_ms),
+            max_retransmits: self.max_retransmits,
+            idle_timeout: self.idle_timeout(),
+            write_queue_depth: self.write_queue_depth,
+            ..AshStreamOptions::default()
+        }
+    }
+
+    pub fn health_socket_addr(&self) -> Option<SocketAddr> {
+        self.health_port
+            .map(|port| SocketAddr::new(self.address, port))
+    }
+
     pub async fn spi_device(&self) -> Result<Spidev> {
         Ok(Spidev::open(&self.spi.device)?)
     }
@@ -83,7 +326,19 @@ impl Default for Settings {
         Settings {
             address: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
             port: 5555,
+            health_port: None,
             spi: Default::default(),
+            tls: None,
+            mode: Mode::Ash,
+            drain_timeout_secs: 10,
+            handshake_timeout_secs: 10,
+            idle_timeout_secs: None,
+            ack_window: 7,
+            retransmit_timeout_ms: 1600,
+            min_retransmit_timeout_ms: 400,
+            max_retransmit_timeout_ms: 3200,
+            max_retransmits: 4,
+            write_queue_depth: 8,
             loglevel: Level::INFO,
         }
     }
@@ -98,6 +353,190 @@ impl Default for Spi {
             int_line: 2,
             reset_line: 43,
             wake_line: 48,
+            speed_hz: 2000,
+            min_speed_hz: 500,
+            speed_fallback_errors: 3,
+            request_timeout_ms: 2000,
+            send_retries: 0,
+            reset_pulse_us: 26,
+            interrupt_debounce_samples: 1,
+            interrupt_debounce_interval_us: 50,
+            callback_poll_interval_ms: 250,
+            spi_protocol_versions: vec![2],
+            query_ezsp_version: true,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::de::value::StrDeserializer;
+    use std::{env::temp_dir, fs};
+
+    #[test]
+    fn it_loads_the_bridge_mode() {
+        let path = temp_dir().join("ezsp-spi-bridge-mode-settings-test.toml");
+        fs::write(&path, "mode = \"transparent\"\n").expect("Expected to write config file");
+
+        let settings = Settings::load(Some(&path));
+        let _ = fs::remove_file(&path);
+        let settings = settings.expect("Expected settings to load");
+
+        assert_eq!(settings.mode, Mode::Transparent);
+        assert_eq!(Settings::default().mode, Mode::Ash);
+    }
+
+    fn fixtures() -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("src/test/fixtures")
+    }
+
+    #[test]
+    fn it_loads_settings_from_a_toml_file() {
+        let settings = Settings::load(Some(&fixtures().join("config.toml")))
+            .expect("Expected settings to load");
+
+        assert_eq!(settings.port, 6100);
+        assert_eq!(settings.loglevel, Level::WARN);
+        assert_eq!(settings.spi.device, PathBuf::from("/dev/spidev2.0"));
+        assert_eq!(settings.spi.int_line, 7);
+    }
+
+    #[test]
+    fn it_prefers_toml_over_json_config_files() {
+        let settings = Settings::load_from(None, &fixtures()).expect("Expected settings to load");
+
+        assert_eq!(settings.port, 6100);
+        assert_eq!(settings.loglevel, Level::WARN);
+    }
+
+    #[test]
+    fn it_ignores_other_config_file_formats_by_default() {
+        let dir = temp_dir().join("ezsp-spi-bridge-format-settings-test");
+        fs::create_dir_all(&dir).expect("Expected to create config directory");
+        fs::write(dir.join("config.yaml"), "port: 6200\n").expect("Expected to write config file");
+
+        let settings = Settings::load_from(None, &dir);
+        let _ = fs::remove_dir_all(&dir);
+        let settings = settings.expect("Expected settings to load");
+
+        assert_eq!(settings.port, Settings::default().port);
+    }
+
+    #[test]
+    fn it_leaves_tls_disabled_by_default() {
+        assert!(Settings::default().tls.is_none());
+    }
+
+    #[test]
+    fn it_loads_tls_settings_from_the_config_file() {
+        let path = temp_dir().join("ezsp-spi-bridge-tls-settings-test.toml");
+        fs::write(
+            &path,
+            "[tls]\ncert = \"/etc/bridge/cert.pem\"\nkey = \"/etc/bridge/key.pem\"\n",
+        )
+        .expect("Expected to write config file");
+
+        let settings = Settings::load(Some(&path));
+        let _ = fs::remove_file(&path);
+        let tls = settings
+            .expect("Expected settings to load")
+            .tls
+            .expect("Expected TLS settings");
+
+        assert_eq!(tls.cert, PathBuf::from("/etc/bridge/cert.pem"));
+        assert_eq!(tls.key, PathBuf::from("/etc/bridge/key.pem"));
+    }
+
+    fn spi_with_paths(device: PathBuf, gpiochip: PathBuf) -> Spi {
+        Spi {
+            device,
+            gpiochip,
+            ..Spi::default()
+        }
+    }
+
+    #[test]
+    fn it_accepts_existing_paths_and_distinct_lines() {
+        let config = fixtures().join("config.toml");
+        let spi = spi_with_paths(config.clone(), config);
+
+        assert!(spi.validate().is_ok());
+    }
+
+    #[test]
+    fn it_rejects_a_missing_spi_device() {
+        let spi = spi_with_paths(
+            PathBuf::from("/dev/ezsp-spi-bridge-missing"),
+            fixtures().join("config.toml"),
+        );
+
+        let err = spi.validate().expect_err("Expected validation to fail");
+        assert!(matches!(err, ValidationError::MissingDevice { .. }));
+        assert!(err.to_string().starts_with("spi.device: "));
+    }
+
+    #[test]
+    fn it_rejects_an_inaccessible_gpiochip() {
+        let spi = spi_with_paths(
+            fixtures().join("config.toml"),
+            PathBuf::from("/dev/ezsp-spi-bridge-missing"),
+        );
+
+        let err = spi.validate().expect_err("Expected validation to fail");
+        assert!(matches!(err, ValidationError::InaccessibleGpioChip { .. }));
+    }
+
+    #[test]
+    fn it_rejects_a_gpio_line_used_twice() {
+        let config = fixtures().join("config.toml");
+        let spi = Spi {
+            wake_line: 2,
+            ..spi_with_paths(config.clone(), config)
+        };
+
+        let err = spi.validate().expect_err("Expected validation to fail");
+        assert_eq!(
+            err.to_string(),
+            "spi.wake_line: line 2 is already used by spi.int_line"
+        );
+    }
+
+    fn level_from(value: &str) -> Result<Level, serde::de::value::Error> {
+        deserialize_level(StrDeserializer::new(value))
+    }
+
+    #[test]
+    fn it_parses_log_levels_by_name() {
+        assert_eq!(level_from("INFO").unwrap(), Level::INFO);
+        assert_eq!(level_from("trace").unwrap(), Level::TRACE);
+    }
+
+    #[test]
+    fn it_parses_log_levels_by_number() {
+        assert_eq!(level_from("1").unwrap(), Level::ERROR);
+        assert_eq!(level_from("3").unwrap(), Level::INFO);
+        assert_eq!(level_from("5").unwrap(), Level::TRACE);
+    }
+
+    #[test]
+    fn it_rejects_log_levels_out_of_range() {
+        assert!(level_from("0").is_err());
+        assert!(level_from("6").is_err());
+        assert!(level_from("LOUD").is_err());
+    }
+
+    #[test]
+    fn it_loads_a_numeric_log_level_from_the_config_file() {
+        let path = temp_dir().join("ezsp-spi-bridge-loglevel-settings-test.toml");
+        fs::write(&path, "loglevel = 4\n").expect("Expected to write config file");
+
+        let settings = Settings::load(Some(&path));
+        let _ = fs::remove_file(&path);
+
+        assert_eq!(
+            settings.expect("Expected settings to load").loglevel,
+            Level::DEBUG
+        );
+    }
+}
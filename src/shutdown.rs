@@ -0,0 +1,44 @@
+use std::io::Result;
+use tokio::{
+    select,
+    signal::unix::{signal, SignalKind},
+};
+use tracing::info;
+
+/// Register handlers for `SIGTERM` and `SIGINT`, returning a future that
+/// resolves once either signal is received.
+///
+/// The handlers are installed before the future is first polled, so a signal
+/// that arrives while the server is busy is not missed.
+pub fn shutdown_signal() -> Result<impl std::future::Future<Output = ()>> {
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    Ok(async move {
+        select! {
+            _ = terminate.recv() => info!("Received SIGTERM, shutting down"),
+            _ = interrupt.recv() => info!("Received SIGINT, shutting down"),
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{process::Command, time::Duration};
+    use tokio::time::timeout;
+
+    #[tokio::test]
+    async fn it_resolves_when_the_process_receives_sigterm() {
+        let shutdown = shutdown_signal().expect("Expected to install signal handlers");
+
+        let status = Command::new("kill")
+            .args(["-TERM", &std::process::id().to_string()])
+            .status()
+            .expect("Expected to run kill");
+        assert!(status.success());
+
+        timeout(Duration::from_secs(5), shutdown)
+            .await
+            .expect("Expected shutdown signal to resolve");
+    }
+}
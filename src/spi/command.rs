@@ -1,7 +1,20 @@
 use bytes::{BufMut, Bytes};
 
+use super::error::{Error, Result};
+
+/// The longest payload a frame command can carry, as its length is sent in a
+/// single byte.
+pub const MAX_PAYLOAD_LEN: usize = u8::MAX as usize;
+
+/// A command sent to the NCP over SPI.
+///
+/// Frame commands are built with [`Command::new_ezsp_frame`] or
+/// [`Command::new_bootloader_frame`], which check the payload fits.
+#[derive(Debug, Clone)]
+pub struct Command(Kind);
+
 #[derive(Debug, Clone)]
-pub enum Command {
+enum Kind {
     EzspFrame(Bytes),
     BootloaderFrame(Bytes),
     SpiStatus,
@@ -9,25 +22,54 @@ pub enum Command {
 }
 
 impl Command {
+    /// Create a command carrying an EZSP frame, or `Error::OversizedPayload`
+    /// if `data` is longer than `MAX_PAYLOAD_LEN`.
+    pub fn new_ezsp_frame(data: Bytes) -> Result<Command> {
+        check_payload_len(&data)?;
+        Ok(Command(Kind::EzspFrame(data)))
+    }
+
+    /// Create a command carrying a bootloader frame, or
+    /// `Error::OversizedPayload` if `data` is longer than `MAX_PAYLOAD_LEN`.
+    pub fn new_bootloader_frame(data: Bytes) -> Result<Command> {
+        check_payload_len(&data)?;
+        Ok(Command(Kind::BootloaderFrame(data)))
+    }
+
+    /// Create a command asking for the SPI status of the NCP.
+    pub fn spi_status() -> Command {
+        Command(Kind::SpiStatus)
+    }
+
+    /// Create a command asking for the SPI protocol version of the NCP.
+    pub fn spi_protocol_version() -> Command {
+        Command(Kind::SpiProtocolVersion)
+    }
+
+    /// Returns true if the command carries an EZSP frame.
+    pub fn is_ezsp_frame(&self) -> bool {
+        matches!(self.0, Kind::EzspFrame(_))
+    }
+
     pub fn size(&self) -> usize {
-        match self {
-            Command::EzspFrame(b) | Command::BootloaderFrame(b) => 3 + b.len(),
-            Command::SpiStatus | Command::SpiProtocolVersion => 2,
+        match &self.0 {
+            Kind::EzspFrame(b) | Kind::BootloaderFrame(b) => 3 + b.len(),
+            Kind::SpiStatus | Kind::SpiProtocolVersion => 2,
         }
     }
 
     fn command_byte(&self) -> u8 {
-        match self {
-            Command::EzspFrame(_) => 0xFE,
-            Command::BootloaderFrame(_) => 0xFD,
-            Command::SpiStatus => 0x0B,
-            Command::SpiProtocolVersion => 0x0A,
+        match self.0 {
+            Kind::EzspFrame(_) => 0xFE,
+            Kind::BootloaderFrame(_) => 0xFD,
+            Kind::SpiStatus => 0x0B,
+            Kind::SpiProtocolVersion => 0x0A,
         }
     }
 
     pub fn serialize(&self, mut buf: &mut [u8]) {
         buf.put_u8(self.command_byte());
-        if let Command::EzspFrame(b) | Command::BootloaderFrame(b) = self {
+        if let Kind::EzspFrame(b) | Kind::BootloaderFrame(b) = &self.0 {
             buf.put_u8(b.len().try_into().unwrap());
             buf.put_slice(b);
         }
@@ -35,6 +77,13 @@ impl Command {
     }
 }
 
+fn check_payload_len(data: &[u8]) -> Result<()> {
+    if data.len() > MAX_PAYLOAD_LEN {
+        return Err(Error::OversizedPayload);
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use bytes::BytesMut;
@@ -44,23 +93,28 @@ mod tests {
     #[test]
     fn it_returns_the_correct_command_size() {
         let data = BytesMut::zeroed(25).freeze();
-        assert_eq!(Command::BootloaderFrame(data.clone()).size(), 28);
-        assert_eq!(Command::EzspFrame(data).size(), 28);
-        assert_eq!(Command::SpiProtocolVersion.size(), 2);
-        assert_eq!(Command::SpiStatus.size(), 2);
+        assert_eq!(Command(Kind::BootloaderFrame(data.clone())).size(), 28);
+        assert_eq!(Command(Kind::EzspFrame(data)).size(), 28);
+        assert_eq!(Command::spi_protocol_version().size(), 2);
+        assert_eq!(Command::spi_status().size(), 2);
     }
 
     #[test]
     fn it_returns_the_correct_command_byte() {
-        assert_eq!(Command::BootloaderFrame(Bytes::new()).command_byte(), 0xFD);
-        assert_eq!(Command::EzspFrame(Bytes::new()).command_byte(), 0xFE);
-        assert_eq!(Command::SpiProtocolVersion.command_byte(), 0x0A);
-        assert_eq!(Command::SpiStatus.command_byte(), 0x0B);
+        assert_eq!(
+            Command(Kind::BootloaderFrame(Bytes::new())).command_byte(),
+            0xFD
+        );
+        assert_eq!(Command(Kind::EzspFrame(Bytes::new())).command_byte(), 0xFE);
+        assert_eq!(Command::spi_protocol_version().command_byte(), 0x0A);
+        assert_eq!(Command::spi_status().command_byte(), 0x0B);
     }
 
     #[test]
     fn it_serialize_a_bootloader_frame_correctly() {
-        let command = Command::BootloaderFrame(Bytes::from_static(&[0xA7, 0xFE, 0x0B]));
+        let command = Command(Kind::BootloaderFrame(Bytes::from_static(&[
+            0xA7, 0xFE, 0x0B,
+        ])));
         let mut buf = BytesMut::zeroed(command.size());
         command.serialize(&mut buf);
 
@@ -69,7 +123,7 @@ mod tests {
 
     #[test]
     fn it_serialize_an_ezsp_frame_correctly() {
-        let command = Command::EzspFrame(Bytes::from_static(&[0xA7, 0xFE, 0x0B]));
+        let command = Command(Kind::EzspFrame(Bytes::from_static(&[0xA7, 0xFE, 0x0B])));
         let mut buf = BytesMut::zeroed(command.size());
         command.serialize(&mut buf);
 
@@ -78,7 +132,7 @@ mod tests {
 
     #[test]
     fn it_serialize_the_spi_protocol_version_command_correctly() {
-        let command = Command::SpiProtocolVersion;
+        let command = Command::spi_protocol_version();
         let mut buf = BytesMut::zeroed(command.size());
         command.serialize(&mut buf);
 
@@ -87,10 +141,34 @@ mod tests {
 
     #[test]
     fn it_serialize_the_spi_status_command_correctly() {
-        let command = Command::SpiStatus;
+        let command = Command::spi_status();
         let mut buf = BytesMut::zeroed(command.size());
         command.serialize(&mut buf);
 
         assert_eq!(buf, [0x0B, 0xA7].as_ref());
     }
+
+    #[test]
+    fn it_rejects_a_frame_too_long_for_its_length_byte() {
+        let data = BytesMut::zeroed(MAX_PAYLOAD_LEN + 1).freeze();
+
+        assert!(matches!(
+            Command::new_ezsp_frame(data.clone()),
+            Err(Error::OversizedPayload)
+        ));
+        assert!(matches!(
+            Command::new_bootloader_frame(data),
+            Err(Error::OversizedPayload)
+        ));
+    }
+
+    #[test]
+    fn it_accepts_a_frame_of_the_maximum_length() {
+        let command = Command::new_ezsp_frame(BytesMut::zeroed(MAX_PAYLOAD_LEN).freeze())
+            .expect("Expected the frame to fit");
+        let mut buf = BytesMut::zeroed(command.size());
+        command.serialize(&mut buf);
+
+        assert_eq!(buf[1], 0xFF);
+    }
 }
@@ -1,5 +1,6 @@
 use std::{
     io::{self, ErrorKind},
+    os::unix::{io::AsRawFd, net::UnixStream},
     path::Path,
     time::Duration,
 };
@@ -9,7 +10,8 @@ use gpiod::{
     Output,
 };
 use popol::{interest, Sources};
-use spidev::{SpiModeFlags, Spidev, SpidevOptions, SpidevTransfer};
+use spidev::{spidevioctl, SpiModeFlags, Spidev, SpidevOptions, SpidevTransfer};
+use tracing::warn;
 
 use super::traits::SpiDevice;
 use crate::spi::error::Result;
@@ -38,19 +40,145 @@ fn setup_output_pins(
     )
 }
 
-fn configure_spi_dev(spi: &mut Spidev) -> io::Result<()> {
+/// The settings of an SPI device that the bridge configures.
+trait SpiBus {
+    fn configure(&mut self, options: &SpidevOptions) -> io::Result<()>;
+
+    /// The word size the device is configured with.
+    fn bits_per_word(&self) -> io::Result<u8>;
+}
+
+impl SpiBus for Spidev {
+    fn configure(&mut self, options: &SpidevOptions) -> io::Result<()> {
+        Spidev::configure(self, options)
+    }
+
+    fn bits_per_word(&self) -> io::Result<u8> {
+        spidevioctl::get_bits_per_word(self.as_raw_fd())
+    }
+}
+
+/// Configure the device for the NCP at `speed_hz`.
+///
+/// The kernel rejects settings the device cannot support without saying
+/// which, so a rejection is reported as the speed being unsupported, the only
+/// setting that varies. The word size is read back afterwards, as some
+/// drivers quietly ignore it.
+fn configure_spi_dev(spi: &mut impl SpiBus, speed_hz: u32) -> io::Result<()> {
     let mut options = SpidevOptions::new();
     options.mode(SpiModeFlags::SPI_NO_CS);
     options.bits_per_word(8);
-    options.max_speed_hz(2000);
-    spi.configure(&options)
+    options.max_speed_hz(speed_hz);
+    spi.configure(&options).map_err(|e| match e.kind() {
+        ErrorKind::InvalidInput => io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("SPI device does not support speed {} Hz", speed_hz),
+        ),
+        _ => e,
+    })?;
+
+    let bits_per_word = spi.bits_per_word()?;
+    if bits_per_word != 8 {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "SPI device uses {} bits per word rather than 8",
+                bits_per_word
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Edges detected on the interrupt line, waiting to be read.
+trait EdgeSource {
+    /// Wait up to `timeout` for an edge, returning true if one can be read.
+    fn wait_edge(&mut self, timeout: Duration) -> io::Result<bool>;
+
+    /// Read the oldest edge.
+    fn read_edge(&mut self) -> io::Result<()>;
+}
+
+/// Wait up to `timeout` for edges on the interrupt line, returning true if
+/// there were any.
+///
+/// Several edges may arrive during a single wait. They all stand for the same
+/// signal from the NCP, so every one of them is read, rather than leaving the
+/// rest to end the next wait straight away.
+fn wait_for_edges(source: &mut impl EdgeSource, timeout: Duration) -> io::Result<bool> {
+    if !source.wait_edge(timeout)? {
+        return Ok(false);
+    }
+    read_edges(source)?;
+    Ok(true)
+}
+
+/// Read every edge waiting on the interrupt line, once one is known to be.
+fn read_edges(source: &mut impl EdgeSource) -> io::Result<()> {
+    loop {
+        match source.read_edge() {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+            Err(e) => return Err(e),
+        }
+        if !source.wait_edge(Duration::ZERO)? {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// What the interrupt poller is waiting on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PollKey {
+    Line,
+    Wakeup,
+}
+
+/// The interrupt line, and the poller waiting for edges on it.
+struct Interrupt {
+    line: Lines<Input>,
+    poll: Sources<PollKey>,
+}
+
+impl Interrupt {
+    /// Wait up to `timeout` for an edge, or for `wakeup` to have something to
+    /// read, returning true if there is an edge to read.
+    fn wait_edge_or_wakeup(&mut self, timeout: Duration, wakeup: &UnixStream) -> io::Result<bool> {
+        self.poll.register(PollKey::Wakeup, wakeup, interest::READ);
+        let mut events = Vec::new();
+        let res = self.poll.wait_timeout(&mut events, timeout);
+        self.poll.unregister(&PollKey::Wakeup);
+        match res {
+            Ok(_) => Ok(events.iter().any(|event| event.key == PollKey::Line)),
+            Err(e) if e.kind() == ErrorKind::TimedOut => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl EdgeSource for Interrupt {
+    fn wait_edge(&mut self, timeout: Duration) -> io::Result<bool> {
+        let mut events = Vec::new();
+        match self.poll.wait_timeout(&mut events, timeout) {
+            Ok(_) => Ok(true),
+            Err(e) if e.kind() == ErrorKind::TimedOut => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn read_edge(&mut self) -> io::Result<()> {
+        self.line.read_event().map(|_| ())
+    }
 }
 
 pub struct Peripheral {
     io: Spidev,
-    interrupt: Lines<Input>,
+    interrupt: Interrupt,
     output_pins: Lines<Output>,
-    poll: Sources<()>,
+    /// The GPIO chip the lines were requested from, kept open so lines can be
+    /// requested again without reopening it.
+    chip: Chip,
 }
 
 impl Peripheral {
@@ -61,19 +189,20 @@ impl Peripheral {
         int_id: LineId,
         reset_id: LineId,
         wake_id: LineId,
+        speed_hz: u32,
     ) -> Result<Peripheral> {
-        configure_spi_dev(&mut spi)?;
+        configure_spi_dev(&mut spi, speed_hz)?;
         let chip = Chip::new(path)?;
-        let interrupt = setup_interrupt_pin(&chip, int_id)?;
+        let line = setup_interrupt_pin(&chip, int_id)?;
         let output_pins = setup_output_pins(&chip, cs_id, reset_id, wake_id)?;
         let mut poll = Sources::new();
-        poll.register((), &interrupt, interest::READ);
+        poll.register(PollKey::Line, &line, interest::READ);
 
         Ok(Peripheral {
             io: spi,
-            interrupt,
+            interrupt: Interrupt { line, poll },
             output_pins,
-            poll,
+            chip,
         })
     }
 
@@ -90,6 +219,24 @@ impl Peripheral {
     }
 }
 
+/// Deassert chip select, reset and wake before the lines are released, so
+/// the NCP is not left held in reset or mid-transaction once the bridge has
+/// exited.
+///
+/// The SPI actor hands the peripheral back once it has stopped, so on a
+/// graceful shutdown this only happens after the last transaction.
+impl Drop for Peripheral {
+    fn drop(&mut self) {
+        let mut values: Masked<u8> = Default::default();
+        for line in 0..3 {
+            values.set(line, Some(false));
+        }
+        if let Err(e) = self.output_pins.set_values(values) {
+            warn!(error = ?e, "Failed to return the NCP control lines to idle: {}", e);
+        }
+    }
+}
+
 impl SpiDevice for Peripheral {
     fn read(&mut self, mut buf: &mut [u8]) -> io::Result<()> {
         let mut transfer = SpidevTransfer::read(&mut buf);
@@ -121,22 +268,153 @@ impl SpiDevice for Peripheral {
         self.output_pins.set_values(values)
     }
 
+    fn set_speed(&mut self, speed_hz: u32) -> io::Result<()> {
+        configure_spi_dev(&mut self.io, speed_hz)
+    }
+
     fn poll_interrupt_signal(&mut self, dur: Duration) -> io::Result<bool> {
-        let mut events = Vec::new();
+        wait_for_edges(&mut self.interrupt, dur)
+    }
 
-        match self.poll.wait_timeout(&mut events, dur) {
-            Ok(_) => {
-                self.interrupt.read_event()?;
-                Ok(true)
-            }
-            Err(e) if e.kind() == ErrorKind::TimedOut => Ok(false),
-            Err(e) => Err(e),
+    fn poll_interrupt_signal_or_wakeup(
+        &mut self,
+        dur: Duration,
+        wakeup: &UnixStream,
+    ) -> io::Result<bool> {
+        if !self.interrupt.wait_edge_or_wakeup(dur, wakeup)? {
+            return Ok(false);
         }
+        read_edges(&mut self.interrupt)?;
+        Ok(true)
     }
 
     fn get_interrupt_value(&mut self) -> io::Result<bool> {
         let values = [false; 1];
-        let res = self.interrupt.get_values(values)?;
+        let res = self.interrupt.line.get_values(values)?;
         Ok(res.get(0).unwrap_or(false))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A device that fails to configure with `error`, or otherwise takes on
+    /// `bits_per_word`.
+    struct FakeBus {
+        error: Option<ErrorKind>,
+        bits_per_word: u8,
+    }
+
+    impl SpiBus for FakeBus {
+        fn configure(&mut self, _options: &SpidevOptions) -> io::Result<()> {
+            match self.error {
+                Some(kind) => Err(kind.into()),
+                None => Ok(()),
+            }
+        }
+
+        fn bits_per_word(&self) -> io::Result<u8> {
+            Ok(self.bits_per_word)
+        }
+    }
+
+    #[test]
+    fn it_names_the_speed_a_device_rejects() {
+        let mut bus = FakeBus {
+            error: Some(ErrorKind::InvalidInput),
+            bits_per_word: 8,
+        };
+
+        let err =
+            configure_spi_dev(&mut bus, 4_000_000).expect_err("Expected configuration to fail");
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        assert_eq!(
+            err.to_string(),
+            "SPI device does not support speed 4000000 Hz"
+        );
+    }
+
+    #[test]
+    fn it_passes_on_other_configuration_errors() {
+        let mut bus = FakeBus {
+            error: Some(ErrorKind::PermissionDenied),
+            bits_per_word: 8,
+        };
+
+        let err = configure_spi_dev(&mut bus, 2000).expect_err("Expected configuration to fail");
+        assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+    }
+
+    #[test]
+    fn it_rejects_a_device_left_with_another_word_size() {
+        let mut bus = FakeBus {
+            error: None,
+            bits_per_word: 16,
+        };
+
+        let err = configure_spi_dev(&mut bus, 2000).expect_err("Expected configuration to fail");
+        assert_eq!(
+            err.to_string(),
+            "SPI device uses 16 bits per word rather than 8"
+        );
+
+        bus.bits_per_word = 8;
+        assert!(configure_spi_dev(&mut bus, 2000).is_ok());
+    }
+
+    /// Edges that have already arrived, read one at a time.
+    struct PendingEdges {
+        pending: usize,
+    }
+
+    impl EdgeSource for PendingEdges {
+        fn wait_edge(&mut self, _timeout: Duration) -> io::Result<bool> {
+            Ok(self.pending > 0)
+        }
+
+        fn read_edge(&mut self) -> io::Result<()> {
+            if self.pending == 0 {
+                return Err(ErrorKind::WouldBlock.into());
+            }
+            self.pending -= 1;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn it_reads_every_edge_that_arrived_during_a_wait() {
+        let mut edges = PendingEdges { pending: 3 };
+
+        assert!(wait_for_edges(&mut edges, Duration::from_millis(1)).unwrap());
+        assert_eq!(edges.pending, 0);
+        assert!(!wait_for_edges(&mut edges, Duration::from_millis(1)).unwrap());
+    }
+
+    #[test]
+    fn it_stops_reading_once_no_edge_is_left() {
+        /// Claims an edge is always waiting, but has a single one to read.
+        struct SpuriousEdges {
+            reads: usize,
+        }
+
+        impl EdgeSource for SpuriousEdges {
+            fn wait_edge(&mut self, _timeout: Duration) -> io::Result<bool> {
+                Ok(true)
+            }
+
+            fn read_edge(&mut self) -> io::Result<()> {
+                self.reads += 1;
+                match self.reads {
+                    1 => Ok(()),
+                    _ => Err(ErrorKind::WouldBlock.into()),
+                }
+            }
+        }
+
+        let mut edges = SpuriousEdges { reads: 0 };
+
+        assert!(wait_for_edges(&mut edges, Duration::from_millis(1)).unwrap());
+        assert_eq!(edges.reads, 2);
+    }
+}
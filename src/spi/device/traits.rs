@@ -1,4 +1,5 @@
 use std::io::Result;
+use std::os::unix::net::UnixStream;
 use std::time::Duration;
 
 use mockall::automock;
@@ -10,6 +11,12 @@ pub trait SpiDevice {
     fn set_cs_signal(&mut self, value: bool) -> Result<()>;
     fn set_wake_signal(&mut self, value: bool) -> Result<()>;
     fn set_reset_signal(&mut self, value: bool) -> Result<()>;
+    fn set_speed(&mut self, speed_hz: u32) -> Result<()>;
     fn poll_interrupt_signal(&mut self, dur: Duration) -> Result<bool>;
+    fn poll_interrupt_signal_or_wakeup(
+        &mut self,
+        dur: Duration,
+        wakeup: &UnixStream,
+    ) -> Result<bool>;
     fn get_interrupt_value(&mut self) -> Result<bool>;
 }
@@ -2,12 +2,17 @@ use std::result::Result as StdResult;
 
 use thiserror::Error;
 
+/// Errors from talking to the NCP.
+///
+/// `Transfer` means the SPI bus or GPIO lines failed, and may call for the
+/// bus to be reopened. The other errors come from the NCP itself, and call
+/// for the NCP to be reset.
 #[derive(Debug, Error)]
 pub enum Error {
     #[error("An invalid response was sent")]
     InvalidResponse,
-    #[error("An IO error occurred")]
-    Io(#[from] std::io::Error),
+    #[error("An SPI transfer failed: {0}")]
+    Transfer(#[from] std::io::Error),
     #[error("The NCP is in an unknown state")]
     NeedsReset,
     #[error("The NCP is unresponsive")]
@@ -20,4 +25,12 @@ pub enum Error {
     UnexpectedReset(u8),
 }
 
+impl Error {
+    /// Returns true if the error came from the SPI bus or GPIO lines, rather
+    /// than from the NCP.
+    pub fn is_transfer(&self) -> bool {
+        matches!(self, Error::Transfer(_))
+    }
+}
+
 pub type Result<T> = StdResult<T, Error>;
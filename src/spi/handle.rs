@@ -1,21 +1,39 @@
 use super::{
     device::SpiDevice,
     error::{Error, Result},
-    ncp::NCP,
+    ncp::{NcpOptions, State, NCP, RESET_STARTUP_TIME},
 };
 use bytes::Bytes;
-use std::{result, sync::Arc};
+use futures::FutureExt;
+use std::{
+    any::Any,
+    io::{self, Read, Write},
+    os::unix::net::UnixStream,
+    result,
+    sync::{
+        atomic::{AtomicU64, AtomicU8, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 use tokio::{
     sync::{
         mpsc::{channel, error::TryRecvError, Receiver, Sender},
         oneshot::{channel as oneshot_channel, Sender as OneshotSender},
         Notify,
     },
-    task::{spawn_blocking, JoinError, JoinHandle},
+    task::{spawn_blocking, JoinHandle},
+    time::timeout,
 };
+use tracing::{debug, info};
 
 type MessageResponseSender<T> = OneshotSender<Result<T>>;
 
+/// The longest the idle actor waits on the interrupt line before checking
+/// its mailbox again. Each request wakes the actor as it is sent, so this
+/// only matters if a wakeup goes astray.
+const IDLE_WAIT: Duration = Duration::from_millis(500);
+
 enum SpiActorMessage {
     SendFrame {
         frame: Bytes,
@@ -23,50 +41,144 @@ enum SpiActorMessage {
     },
     Reset {
         to_bootloader: bool,
-        ret: MessageResponseSender<()>,
+        ret: MessageResponseSender<u8>,
     },
     Wakeup {
         ret: MessageResponseSender<()>,
     },
 }
 
+/// Create the sockets a handle wakes its actor with, the sending end first.
+/// Neither end blocks, so sending a request never waits on the actor.
+fn wakeup_pair() -> io::Result<(UnixStream, UnixStream)> {
+    let (tx, rx) = UnixStream::pair()?;
+    tx.set_nonblocking(true)?;
+    rx.set_nonblocking(true)?;
+    Ok((tx, rx))
+}
+
+/// Read every pending wakeup, so the next wait blocks until another arrives.
+fn drain_wakeups(mut wakeup: &UnixStream) {
+    let mut buf = [0; 64];
+    while matches!(wakeup.read(&mut buf), Ok(len) if len > 0) {}
+}
+
+/// Record the current time as the last successful transaction, in
+/// milliseconds since the Unix epoch.
+fn record_transaction<T>(res: &Result<T>, last_transaction: &AtomicU64) {
+    if res.is_ok() {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        last_transaction.store(now.as_millis() as u64, Ordering::Relaxed);
+    }
+}
+
 fn spi_device_actor<D>(
     device: D,
+    options: NcpOptions,
     mut mailbox: Receiver<SpiActorMessage>,
+    wakeup: UnixStream,
     interrupt: Arc<Notify>,
+    ncp_state: Arc<AtomicU8>,
+    last_transaction: Arc<AtomicU64>,
 ) -> impl FnOnce() -> D + Send
 where
     D: SpiDevice + Send,
 {
     move || {
-        let mut ncp = NCP::new(device);
+        let callback_poll_interval = options.callback_poll_interval;
+        let mut ncp = NCP::with_options(device, options);
+        // The line is checked straight away, as the NCP may have raised it
+        // before the actor started waiting for edges
+        let mut next_callback_check = Instant::now();
         loop {
-            match mailbox.try_recv() {
+            // The state is shared before replying, so the caller sees the
+            // state the request left the NCP in. A request may leave more
+            // callbacks pending without a new edge, so the line is checked
+            // after each one.
+            let check_callback = match mailbox.try_recv() {
                 Ok(SpiActorMessage::SendFrame { frame, ret }) => {
-                    let _ = ret.send(ncp.send(frame));
+                    let res = ncp.send(frame);
+                    record_transaction(&res, &last_transaction);
+                    ncp_state.store(ncp.state() as u8, Ordering::Relaxed);
+                    let _ = ret.send(res);
+                    true
                 }
                 Ok(SpiActorMessage::Reset { to_bootloader, ret }) => {
-                    let _ = ret.send(ncp.reset(to_bootloader));
+                    let res = ncp.reset(to_bootloader);
+                    // The NCP has forgotten any callback it signalled before
+                    // the reset, while one it signals since is found below
+                    let _ = interrupt.notified().now_or_never();
+                    record_transaction(&res, &last_transaction);
+                    ncp_state.store(ncp.state() as u8, Ordering::Relaxed);
+                    let _ = ret.send(res);
+                    true
                 }
                 Ok(SpiActorMessage::Wakeup { ret }) => {
-                    let _ = ret.send(ncp.wakeup());
+                    let res = ncp.wakeup();
+                    record_transaction(&res, &last_transaction);
+                    ncp_state.store(ncp.state() as u8, Ordering::Relaxed);
+                    let _ = ret.send(res);
+                    true
+                }
+                Err(TryRecvError::Empty) => {
+                    // Sleep until the NCP signals a callback, a request is
+                    // sent, or it is time to check the line anyway
+                    let wait = next_callback_check
+                        .saturating_duration_since(Instant::now())
+                        .min(IDLE_WAIT);
+                    let res = ncp.wait_for_interrupt_or_wakeup(wait, &wakeup);
+                    drain_wakeups(&wakeup);
+                    match res {
+                        Ok(edge) => edge,
+                        Err(e) => {
+                            debug!(error = ?e, "Failed to wait for the NCP interrupt: {}", e);
+                            false
+                        }
+                    }
                 }
-                Err(TryRecvError::Empty) => {}
+                // Only reported once the mailbox is empty, so every request
+                // sent before the last handle was dropped is still answered
                 Err(TryRecvError::Disconnected) => {
                     break;
                 }
-            }
-            match ncp.has_callback() {
-                Ok(true) => interrupt.notify_one(),
-                _ => {}
+            };
+            // Without an edge the line is still checked now and then, so a
+            // missed edge does not leave a callback waiting forever
+            if check_callback || Instant::now() >= next_callback_check {
+                next_callback_check = Instant::now() + callback_poll_interval;
+                if let Ok(true) = ncp.has_callback() {
+                    interrupt.notify_one();
+                }
             }
         }
-        ncp.into_inner()
+        ncp.into_device()
+    }
+}
+
+/// The task the SPI actor runs on, shared between the actor and its handles.
+///
+/// The join handle is taken out once the actor is waited on, after which the
+/// actor is no longer reported as running.
+struct ActorTask<D>(Mutex<Option<JoinHandle<D>>>);
+
+/// Whether an actor's task is still running, whatever device it drives.
+trait Liveness: Send + Sync {
+    fn is_finished(&self) -> bool;
+}
+
+impl<D: Send> Liveness for ActorTask<D> {
+    fn is_finished(&self) -> bool {
+        match self.0.lock().as_deref() {
+            Ok(Some(handle)) => handle.is_finished(),
+            _ => true,
+        }
     }
 }
 
 pub struct SpiDeviceActor<D> {
-    handle: JoinHandle<D>,
+    task: Arc<ActorTask<D>>,
 }
 
 impl<D> SpiDeviceActor<D>
@@ -75,62 +187,237 @@ where
 {
     fn new(
         device: D,
+        options: NcpOptions,
         mailbox: Receiver<SpiActorMessage>,
+        wakeup: UnixStream,
         interrupt: Arc<Notify>,
+        ncp_state: Arc<AtomicU8>,
+        last_transaction: Arc<AtomicU64>,
     ) -> SpiDeviceActor<D> {
-        let handle = spawn_blocking(spi_device_actor(device, mailbox, interrupt));
+        let actor = spi_device_actor(
+            device,
+            options,
+            mailbox,
+            wakeup,
+            interrupt,
+            ncp_state,
+            last_transaction,
+        );
+        let handle = spawn_blocking(actor);
 
-        SpiDeviceActor { handle }
+        SpiDeviceActor {
+            task: Arc::new(ActorTask(Mutex::new(Some(handle)))),
+        }
     }
 
-    pub async fn into_inner(self) -> result::Result<D, JoinError> {
-        self.handle.await
+    /// Wait for the actor to stop, returning the device it was driving.
+    ///
+    /// If the actor panicked, the panic payload is returned instead, so it
+    /// can be reported or resumed with [`std::panic::resume_unwind`].
+    pub async fn into_inner(self) -> result::Result<D, Box<dyn Any + Send>> {
+        let handle = self
+            .task
+            .0
+            .lock()
+            .ok()
+            .and_then(|mut handle| handle.take())
+            .expect("Expected the actor to be waited on only once");
+        handle.await.map_err(|e| match e.try_into_panic() {
+            Ok(payload) => payload,
+            // The runtime shut down before the actor finished
+            Err(e) => Box::new(e),
+        })
     }
 }
 
 #[derive(Clone)]
 pub struct SpiDeviceHandle {
     mailbox: Sender<SpiActorMessage>,
+    /// Written to after each request, to wake the actor from its idle wait.
+    /// Dropped after the mailbox, so the actor finds the mailbox closed once
+    /// the last handle is gone.
+    wakeup: Arc<UnixStream>,
     interrupt: Arc<Notify>,
+    ncp_state: Arc<AtomicU8>,
+    last_transaction: Arc<AtomicU64>,
+    task: Arc<dyn Liveness>,
+    request_timeout: Duration,
+    send_retries: u32,
 }
 
 impl SpiDeviceHandle {
-    fn new(mailbox: Sender<SpiActorMessage>, interrupt: Arc<Notify>) -> SpiDeviceHandle {
-        SpiDeviceHandle { mailbox, interrupt }
+    fn new(
+        mailbox: Sender<SpiActorMessage>,
+        wakeup: UnixStream,
+        interrupt: Arc<Notify>,
+        ncp_state: Arc<AtomicU8>,
+        last_transaction: Arc<AtomicU64>,
+        task: Arc<dyn Liveness>,
+        options: &NcpOptions,
+    ) -> SpiDeviceHandle {
+        SpiDeviceHandle {
+            mailbox,
+            wakeup: Arc::new(wakeup),
+            interrupt,
+            ncp_state,
+            last_transaction,
+            task,
+            request_timeout: options.request_timeout,
+            send_retries: options.send_retries,
+        }
+    }
+
+    /// The last known state of the NCP, as of the last completed request.
+    pub fn ncp_state(&self) -> State {
+        State::from(self.ncp_state.load(Ordering::Relaxed))
+    }
+
+    /// The last known state of the NCP, shared with the SPI actor.
+    pub fn shared_ncp_state(&self) -> Arc<AtomicU8> {
+        self.ncp_state.clone()
+    }
+
+    /// The time of the last successful NCP transaction in milliseconds since
+    /// the Unix epoch, or zero if there has not been one, shared with the SPI
+    /// actor.
+    pub fn shared_last_transaction(&self) -> Arc<AtomicU64> {
+        self.last_transaction.clone()
+    }
+
+    /// Whether the SPI actor is still running.
+    ///
+    /// The actor only stops by itself if it panics, after which every request
+    /// fails with an `Error::InternalError` until it is replaced with
+    /// [`SpiDeviceHandle::respawn`]. Once the actor is being waited on with
+    /// [`SpiDeviceActor::into_inner`], it is no longer reported as alive.
+    pub fn is_alive(&self) -> bool {
+        !self.task.is_finished()
+    }
+
+    /// Start a new SPI actor for `device` in place of this handle's actor,
+    /// returning the new actor and a handle to it.
+    ///
+    /// The new handle shares the NCP state and last transaction time with
+    /// this one, so anything observing them, such as the health check,
+    /// follows the new actor. The NCP state starts out unknown.
+    ///
+    /// Fails if the sockets the new handle wakes its actor with cannot be
+    /// created, such as when the process is out of file descriptors.
+    pub fn respawn<D>(
+        &self,
+        device: D,
+        options: NcpOptions,
+    ) -> io::Result<(SpiDeviceActor<D>, SpiDeviceHandle)>
+    where
+        D: SpiDevice + Send + 'static,
+    {
+        self.ncp_state
+            .store(State::Unknown as u8, Ordering::Relaxed);
+        spawn_actor(
+            device,
+            options,
+            self.interrupt.clone(),
+            self.ncp_state.clone(),
+            self.last_transaction.clone(),
+        )
     }
 
     async fn send_message(&self, msg: SpiActorMessage) -> Result<()> {
         self.mailbox
             .send(msg)
             .await
-            .map_err(|_| Error::InternalError)
+            .map_err(|_| Error::InternalError)?;
+        // A full socket already holds a wakeup the actor has yet to read
+        let _ = (&*self.wakeup).write(&[0]);
+        Ok(())
     }
 
-    pub async fn send_frame(&self, frame: Bytes) -> Result<Bytes> {
+    /// Send a message to the SPI actor and wait for its response.
+    ///
+    /// If the actor does not respond within `limit`, for example because it is
+    /// stuck on an SPI transfer, an `Error::Unresponsive` is returned.
+    async fn request<T>(
+        &self,
+        msg: impl FnOnce(MessageResponseSender<T>) -> SpiActorMessage,
+        limit: Duration,
+    ) -> Result<T> {
         let (ret, res) = oneshot_channel();
-        let msg = SpiActorMessage::SendFrame { frame, ret };
-
-        self.send_message(msg).await?;
+        let request = async {
+            self.send_message(msg(ret)).await?;
+            res.await.map_err(|_| Error::InternalError)?
+        };
 
-        res.await.map_err(|_| Error::InternalError)?
+        timeout(limit, request)
+            .await
+            .map_err(|_| Error::Unresponsive)?
     }
 
-    pub async fn reset(&self, to_bootloader: bool) -> Result<()> {
-        let (ret, res) = oneshot_channel();
-        let msg = SpiActorMessage::Reset { to_bootloader, ret };
+    /// Send an EZSP frame to the NCP and wait for the response.
+    ///
+    /// If the NCP is unresponsive, the frame is sent again up to the
+    /// configured number of retries, so the NCP may receive it more than once.
+    pub async fn send_frame(&self, frame: Bytes) -> Result<Bytes> {
+        let mut retries = self.send_retries;
+        loop {
+            let frame = frame.clone();
+            let res = self
+                .request(
+                    |ret| SpiActorMessage::SendFrame { frame, ret },
+                    self.request_timeout,
+                )
+                .await;
+            match res {
+                Err(Error::Unresponsive) if retries > 0 => {
+                    retries -= 1;
+                    debug!(retries, "NCP was unresponsive, sending the frame again");
+                }
+                res => return res,
+            }
+        }
+    }
 
-        self.send_message(msg).await?;
+    /// Send a raw EZSP frame directly to the NCP and return the raw response.
+    ///
+    /// This is a diagnostic entry point for injecting frames from an operator
+    /// console. It bypasses ASH framing and the bridge's sequence tracking, so
+    /// it should not be used while a host session is relying on the NCP.
+    pub async fn send_raw(&self, frame: Bytes) -> Result<Bytes> {
+        self.send_frame(frame).await
+    }
 
-        res.await.map_err(|_| Error::InternalError)?
+    /// Reset the NCP into normal operation, returning the reset code it
+    /// reports.
+    ///
+    /// A failed reset is never retried here. The caller should decide whether
+    /// to try again, as a reset interrupted by the timeout may still be in
+    /// progress and a second reset pulse would restart the NCP's startup.
+    ///
+    /// Any callback the NCP signalled before the reset is discarded, as the
+    /// NCP has forgotten it.
+    pub async fn reset_normal(&self) -> Result<u8> {
+        info!("Resetting the NCP");
+        self.reset(false).await
     }
 
-    pub async fn wake(&self) -> Result<()> {
-        let (ret, res) = oneshot_channel();
-        let msg = SpiActorMessage::Wakeup { ret };
+    /// Reset the NCP into its bootloader, returning the reset code it
+    /// reports. As with [`SpiDeviceHandle::reset_normal`], a failed reset is
+    /// never retried.
+    pub async fn reset_to_bootloader(&self) -> Result<u8> {
+        info!("Resetting the NCP into its bootloader");
+        self.reset(true).await
+    }
 
-        self.send_message(msg).await?;
+    async fn reset(&self, to_bootloader: bool) -> Result<u8> {
+        self.request(
+            |ret| SpiActorMessage::Reset { to_bootloader, ret },
+            RESET_STARTUP_TIME + self.request_timeout,
+        )
+        .await
+    }
 
-        res.await.map_err(|_| Error::InternalError)?
+    pub async fn wake(&self) -> Result<()> {
+        self.request(|ret| SpiActorMessage::Wakeup { ret }, self.request_timeout)
+            .await
     }
 
     pub async fn has_callback(&self) {
@@ -138,13 +425,463 @@ impl SpiDeviceHandle {
     }
 }
 
-pub fn spi_device_handle<D>(device: D) -> (SpiDeviceActor<D>, SpiDeviceHandle)
+/// Start an SPI actor for `device`, returning the actor and a handle to it.
+///
+/// Fails if the sockets the handle wakes the actor with cannot be created.
+pub fn spi_device_handle<D>(
+    device: D,
+    options: NcpOptions,
+) -> io::Result<(SpiDeviceActor<D>, SpiDeviceHandle)>
+where
+    D: SpiDevice + Send + 'static,
+{
+    spawn_actor(
+        device,
+        options,
+        Arc::new(Notify::new()),
+        Arc::new(AtomicU8::new(State::Unknown as u8)),
+        Arc::new(AtomicU64::new(0)),
+    )
+}
+
+fn spawn_actor<D>(
+    device: D,
+    options: NcpOptions,
+    interrupt: Arc<Notify>,
+    ncp_state: Arc<AtomicU8>,
+    last_transaction: Arc<AtomicU64>,
+) -> io::Result<(SpiDeviceActor<D>, SpiDeviceHandle)>
 where
     D: SpiDevice + Send + 'static,
 {
     let (tx, rx) = channel(1);
-    let interrupt = Arc::new(Notify::new());
-    let actor = SpiDeviceActor::new(device, rx, interrupt.clone());
-    let handle = SpiDeviceHandle::new(tx, interrupt);
-    (actor, handle)
+    let (wakeup_tx, wakeup_rx) = wakeup_pair()?;
+    let handle_options = options.clone();
+    let actor = SpiDeviceActor::new(
+        device,
+        options,
+        rx,
+        wakeup_rx,
+        interrupt.clone(),
+        ncp_state.clone(),
+        last_transaction.clone(),
+    );
+    let handle = SpiDeviceHandle::new(
+        tx,
+        wakeup_tx,
+        interrupt,
+        ncp_state,
+        last_transaction,
+        actor.task.clone(),
+        &handle_options,
+    );
+    Ok((actor, handle))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        ash::{RESET_BOOTLOADER, RESET_SOFTWARE},
+        spi::MockSpiDevice,
+        test::{scripted_device_with_interrupt, scripted_reads},
+    };
+    use std::{
+        io,
+        sync::{
+            atomic::{AtomicBool, AtomicUsize, Ordering},
+            Mutex,
+        },
+        thread::sleep,
+    };
+
+    #[tokio::test]
+    async fn it_gives_up_on_a_wedged_actor() {
+        let polling = Arc::new(Notify::new());
+        let mut device = MockSpiDevice::new();
+        let notify = polling.clone();
+        device.expect_get_interrupt_value().returning(move || {
+            notify.notify_one();
+            sleep(Duration::from_millis(500));
+            Ok(false)
+        });
+        device
+            .expect_poll_interrupt_signal_or_wakeup()
+            .returning(|_, _| Ok(false));
+        let options = NcpOptions {
+            request_timeout: Duration::from_millis(50),
+            send_retries: 1,
+            ..NcpOptions::default()
+        };
+        let (_actor, handle) =
+            spi_device_handle(device, options).expect("Expected to spawn the SPI actor");
+        polling.notified().await;
+
+        let res = handle.send_frame(Bytes::from_static(&[0x01])).await;
+
+        assert!(matches!(res, Err(Error::Unresponsive)));
+    }
+
+    #[tokio::test]
+    async fn it_answers_queued_requests_after_the_handle_is_dropped() {
+        let wakes = Arc::new(AtomicUsize::new(0));
+        let woken = wakes.clone();
+        let mut device = MockSpiDevice::new();
+        device.expect_set_wake_signal().returning(move |value| {
+            if value && woken.fetch_add(1, Ordering::SeqCst) == 0 {
+                // Hold up the first request while the rest are queued
+                sleep(Duration::from_millis(100));
+            }
+            Ok(())
+        });
+        device
+            .expect_poll_interrupt_signal()
+            .returning(|_| Ok(true));
+        device
+            .expect_poll_interrupt_signal_or_wakeup()
+            .returning(|_, _| Ok(true));
+        device.expect_get_interrupt_value().returning(|| Ok(false));
+        let (actor, handle) = spi_device_handle(device, NcpOptions::default())
+            .expect("Expected to spawn the SPI actor");
+
+        let mut responses = Vec::new();
+        for _ in 0..2 {
+            let (ret, res) = oneshot_channel();
+            handle
+                .send_message(SpiActorMessage::Wakeup { ret })
+                .await
+                .expect("Expected the request to be queued");
+            responses.push(res);
+        }
+        drop(handle);
+
+        for res in responses {
+            res.await
+                .expect("Expected the request to be answered")
+                .expect("Expected the NCP to wake");
+        }
+        actor
+            .into_inner()
+            .await
+            .expect("Expected the actor to stop");
+        assert_eq!(wakes.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn it_reports_an_actor_that_panicked_as_dead() {
+        let mut device = MockSpiDevice::new();
+        device
+            .expect_poll_interrupt_signal_or_wakeup()
+            .returning(|_, _| panic!("Bus exploded"));
+        let (actor, handle) = spi_device_handle(device, NcpOptions::default())
+            .expect("Expected to spawn the SPI actor");
+
+        let stopped = timeout(Duration::from_secs(5), async {
+            while handle.is_alive() {
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+        })
+        .await;
+        assert!(stopped.is_ok(), "Expected the actor to be reported dead");
+        let res = handle.wake().await;
+        assert!(matches!(res, Err(Error::InternalError)));
+        assert!(actor.into_inner().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn it_hands_back_the_panic_that_stopped_the_actor() {
+        let mut device = MockSpiDevice::new();
+        device
+            .expect_poll_interrupt_signal_or_wakeup()
+            .returning(|_, _| panic!("Bus exploded"));
+        let (actor, _handle) = spi_device_handle(device, NcpOptions::default())
+            .expect("Expected to spawn the SPI actor");
+
+        let payload = match actor.into_inner().await {
+            Ok(_) => panic!("Expected the actor to panic"),
+            Err(payload) => payload,
+        };
+        assert_eq!(payload.downcast_ref::<&str>(), Some(&"Bus exploded"));
+    }
+
+    #[tokio::test]
+    async fn it_respawns_an_actor_sharing_the_ncp_state() {
+        let mut device = MockSpiDevice::new();
+        device
+            .expect_poll_interrupt_signal_or_wakeup()
+            .returning(|_, _| panic!("Bus exploded"));
+        let (actor, handle) = spi_device_handle(device, NcpOptions::default())
+            .expect("Expected to spawn the SPI actor");
+        assert!(actor.into_inner().await.is_err());
+        handle
+            .ncp_state
+            .store(State::Normal as u8, Ordering::Relaxed);
+
+        let device = scripted_device_with_interrupt(
+            &[
+                &[0x00, RESET_SOFTWARE, 0xA7],
+                &[0x82, 0xA7],
+                &[0xC1, 0xA7],
+                &[0xFE, 0x07, 0x00, 0x80, 0x00, 0x08, 0x02, 0x00, 0x67, 0xA7],
+            ],
+            || false,
+        );
+        let shared_state = handle.shared_ncp_state();
+        let (_actor, respawned) = handle
+            .respawn(device, NcpOptions::default())
+            .expect("Expected to respawn the SPI actor");
+        assert!(respawned.is_alive());
+        assert_eq!(respawned.ncp_state(), State::Unknown);
+
+        respawned
+            .reset_normal()
+            .await
+            .expect("Expected the NCP to reset");
+        assert_eq!(
+            State::from(shared_state.load(Ordering::Relaxed)),
+            State::Normal
+        );
+    }
+
+    #[tokio::test]
+    async fn it_discards_a_callback_signalled_before_a_reset() {
+        let pending = Arc::new(AtomicBool::new(true));
+        let interrupt = pending.clone();
+        let device = scripted_device_with_interrupt(
+            &[
+                &[0x00, 0x02, 0xA7],
+                &[0x82, 0xA7],
+                &[0xC1, 0xA7],
+                &[0xFE, 0x07, 0x00, 0x80, 0x00, 0x08, 0x02, 0x00, 0x67, 0xA7],
+            ],
+            move || interrupt.swap(false, Ordering::SeqCst),
+        );
+        let (_actor, handle) = spi_device_handle(device, NcpOptions::default())
+            .expect("Expected to spawn the SPI actor");
+        while pending.load(Ordering::SeqCst) {
+            tokio::task::yield_now().await;
+        }
+
+        handle
+            .reset_normal()
+            .await
+            .expect("Expected the NCP to reset");
+
+        let callback = timeout(Duration::from_millis(100), handle.has_callback()).await;
+        assert!(callback.is_err(), "Expected no callback after the reset");
+    }
+
+    #[tokio::test]
+    async fn it_keeps_a_callback_signalled_as_the_ncp_comes_back_from_a_reset() {
+        let mut device = MockSpiDevice::new();
+        scripted_reads(
+            &mut device,
+            &[
+                &[0x00, 0x02, 0xA7],
+                &[0x82, 0xA7],
+                &[0xC1, 0xA7],
+                &[0xFE, 0x07, 0x00, 0x80, 0x00, 0x08, 0x02, 0x00, 0x67, 0xA7],
+            ],
+        );
+        device.expect_set_wake_signal().returning(|_| Ok(()));
+        device.expect_set_reset_signal().returning(|_| Ok(()));
+        device.expect_write().returning(|_| Ok(()));
+        // The NCP answers each command, but raises no edge while idle
+        device
+            .expect_poll_interrupt_signal()
+            .returning(|_| Ok(true));
+        device
+            .expect_poll_interrupt_signal_or_wakeup()
+            .returning(|_, _| Ok(false));
+        device.expect_get_interrupt_value().returning(|| Ok(true));
+        let options = NcpOptions {
+            callback_poll_interval: Duration::from_secs(10),
+            ..NcpOptions::default()
+        };
+        let (_actor, handle) =
+            spi_device_handle(device, options).expect("Expected to spawn the SPI actor");
+
+        handle
+            .reset_normal()
+            .await
+            .expect("Expected the NCP to reset");
+
+        let callback = timeout(Duration::from_millis(100), handle.has_callback()).await;
+        assert!(callback.is_ok(), "Expected the callback to be kept");
+    }
+
+    #[tokio::test]
+    async fn it_returns_the_reset_code_the_ncp_reports() {
+        let device = scripted_device_with_interrupt(
+            &[
+                &[0x00, RESET_SOFTWARE, 0xA7],
+                &[0x82, 0xA7],
+                &[0xC1, 0xA7],
+                &[0xFE, 0x07, 0x00, 0x80, 0x00, 0x08, 0x02, 0x00, 0x67, 0xA7],
+                &[0x00, RESET_BOOTLOADER, 0xA7],
+                &[0x82, 0xA7],
+                &[0xC1, 0xA7],
+            ],
+            || false,
+        );
+        let (_actor, handle) = spi_device_handle(device, NcpOptions::default())
+            .expect("Expected to spawn the SPI actor");
+
+        let code = handle
+            .reset_normal()
+            .await
+            .expect("Expected the NCP to reset");
+        assert_eq!(code, RESET_SOFTWARE);
+
+        let code = handle
+            .reset_to_bootloader()
+            .await
+            .expect("Expected the NCP to reset");
+        assert_eq!(code, RESET_BOOTLOADER);
+    }
+
+    #[tokio::test]
+    async fn it_shares_the_state_each_request_leaves_the_ncp_in() {
+        let reset: [&[u8]; 3] = [&[0x00, 0x02, 0xA7], &[0x82, 0xA7], &[0xC1, 0xA7]];
+        let version: &[u8] = &[0xFE, 0x07, 0x00, 0x80, 0x00, 0x08, 0x02, 0x00, 0x67, 0xA7];
+        let device = scripted_device_with_interrupt(
+            &[
+                reset[0],
+                reset[1],
+                reset[2],
+                version,
+                // Unsupported command, the NCP has fallen into its bootloader
+                &[0x04, 0x00, 0xA7],
+                reset[0],
+                reset[1],
+                reset[2],
+                // Not a reset code
+                &[0xC1, 0xA7],
+            ],
+            || false,
+        );
+        let (_actor, handle) = spi_device_handle(device, NcpOptions::default())
+            .expect("Expected to spawn the SPI actor");
+        assert_eq!(handle.ncp_state(), State::Unknown);
+
+        handle
+            .reset_normal()
+            .await
+            .expect("Expected the NCP to reset");
+        assert_eq!(handle.ncp_state(), State::Normal);
+
+        let res = handle.send_frame(Bytes::from_static(&[0x01])).await;
+        assert!(matches!(res, Err(Error::NeedsReset)));
+        assert_eq!(handle.ncp_state(), State::Bootloader);
+
+        handle
+            .reset_to_bootloader()
+            .await
+            .expect("Expected the NCP to reset");
+        assert_eq!(handle.ncp_state(), State::Bootloader);
+
+        let res = handle.reset_normal().await;
+        assert!(matches!(res, Err(Error::InvalidResponse)));
+        assert_eq!(handle.ncp_state(), State::Unknown);
+    }
+
+    /// Wait up to `dur` for `wakeup` to have something to read, as a device
+    /// that never sees an edge on its interrupt line would.
+    fn wait_for_wakeup(mut wakeup: &UnixStream, dur: Duration) -> io::Result<bool> {
+        let deadline = Instant::now() + dur;
+        while Instant::now() < deadline {
+            match wakeup.read(&mut [0]) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => sleep(Duration::from_millis(1)),
+                _ => break,
+            }
+        }
+        Ok(false)
+    }
+
+    #[tokio::test]
+    async fn it_waits_on_the_interrupt_line_while_idle() {
+        let waits = Arc::new(Mutex::new(Vec::new()));
+        let recorded = waits.clone();
+        let mut device = MockSpiDevice::new();
+        device.expect_get_interrupt_value().returning(|| Ok(false));
+        device
+            .expect_poll_interrupt_signal_or_wakeup()
+            .returning(move |dur, wakeup| {
+                recorded.lock().unwrap().push(dur);
+                wait_for_wakeup(wakeup, dur)
+            });
+        let options = NcpOptions {
+            callback_poll_interval: Duration::from_secs(10),
+            ..NcpOptions::default()
+        };
+        let (actor, handle) =
+            spi_device_handle(device, options).expect("Expected to spawn the SPI actor");
+
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        drop(handle);
+        actor
+            .into_inner()
+            .await
+            .expect("Expected the actor to stop");
+
+        // One wait before the first look at the line, which is due straight
+        // away, then a single wait until the handle is dropped
+        let waits = waits.lock().unwrap();
+        assert_eq!(*waits, [Duration::ZERO, IDLE_WAIT]);
+    }
+
+    #[tokio::test]
+    async fn it_wakes_the_idle_actor_for_a_request() {
+        let mut device = MockSpiDevice::new();
+        device.expect_get_interrupt_value().returning(|| Ok(false));
+        device.expect_set_wake_signal().returning(|_| Ok(()));
+        device
+            .expect_poll_interrupt_signal()
+            .returning(|_| Ok(true));
+        device
+            .expect_poll_interrupt_signal_or_wakeup()
+            .returning(|dur, wakeup| wait_for_wakeup(wakeup, dur));
+        let options = NcpOptions {
+            callback_poll_interval: Duration::from_secs(10),
+            ..NcpOptions::default()
+        };
+        let (_actor, handle) =
+            spi_device_handle(device, options).expect("Expected to spawn the SPI actor");
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let res = timeout(IDLE_WAIT / 5, handle.wake()).await;
+        assert!(
+            matches!(res, Ok(Ok(()))),
+            "Expected the actor to wake for the request"
+        );
+    }
+
+    #[tokio::test]
+    async fn it_finds_a_callback_when_the_interrupt_edge_is_missed() {
+        let mut device = MockSpiDevice::new();
+        // The line is raised, but no edge is ever seen
+        let raised = Arc::new(AtomicBool::new(false));
+        let interrupt = raised.clone();
+        device
+            .expect_get_interrupt_value()
+            .returning(move || Ok(interrupt.load(Ordering::SeqCst)));
+        device
+            .expect_poll_interrupt_signal_or_wakeup()
+            .returning(|dur, _| {
+                sleep(dur);
+                Ok(false)
+            });
+        let options = NcpOptions {
+            callback_poll_interval: Duration::from_millis(20),
+            ..NcpOptions::default()
+        };
+        let (_actor, handle) =
+            spi_device_handle(device, options).expect("Expected to spawn the SPI actor");
+
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        raised.store(true, Ordering::SeqCst);
+
+        let callback = timeout(Duration::from_millis(500), handle.has_callback()).await;
+        assert!(callback.is_ok(), "Expected the callback to be found");
+    }
 }
@@ -6,10 +6,16 @@ mod ncp;
 mod response;
 
 use anyhow::Result;
+pub use command::MAX_PAYLOAD_LEN;
+pub use device::MockSpiDevice;
 pub use device::Peripheral;
 pub use device::SpiDevice;
+pub use error::Error;
 pub use handle::{spi_device_handle, SpiDeviceActor, SpiDeviceHandle};
+pub use ncp::{NcpOptions, State};
+pub use response::MAX_EXTENDED_FRAME_LEN;
 use spidev::Spidev;
+use std::time::Duration;
 
 use crate::settings::Spi;
 
@@ -22,6 +28,27 @@ pub async fn create_spi_peripheral(settings: &Spi) -> Result<Peripheral> {
         settings.int_line,
         settings.reset_line,
         settings.wake_line,
+        settings.speed_hz,
     )
     .await?)
 }
+
+impl From<&Spi> for NcpOptions {
+    fn from(settings: &Spi) -> Self {
+        NcpOptions {
+            speed_hz: settings.speed_hz,
+            min_speed_hz: settings.min_speed_hz,
+            speed_fallback_errors: settings.speed_fallback_errors,
+            request_timeout: Duration::from_millis(settings.request_timeout_ms),
+            send_retries: settings.send_retries,
+            reset_pulse: Duration::from_micros(settings.reset_pulse_us),
+            interrupt_samples: settings.interrupt_debounce_samples,
+            interrupt_sample_interval: Duration::from_micros(
+                settings.interrupt_debounce_interval_us,
+            ),
+            callback_poll_interval: Duration::from_millis(settings.callback_poll_interval_ms),
+            spi_protocol_versions: settings.spi_protocol_versions.clone(),
+            query_ezsp_version: settings.query_ezsp_version,
+        }
+    }
+}
@@ -1,7 +1,15 @@
-use std::time::{Duration, Instant};
+use std::{
+    fmt::{self, Display},
+    os::unix::net::UnixStream,
+    thread,
+    time::{Duration, Instant},
+};
 
-use bytes::{Buf, Bytes, BytesMut};
+use bytes::{Bytes, BytesMut};
 use nom::{Err, Finish, Needed};
+use tracing::{debug, info, warn};
+
+use crate::buffers::hexdump;
 
 use super::{
     command::Command,
@@ -12,15 +20,52 @@ use super::{
 
 const RESPONSE_TIMEOUT: Duration = Duration::from_millis(350);
 const RESET_PULSE_TIME: Duration = Duration::from_micros(26);
-const RESET_STARTUP_TIME: Duration = Duration::from_millis(7500);
+pub(super) const RESET_STARTUP_TIME: Duration = Duration::from_millis(7500);
 const INTER_COMMAND_SPACING: Duration = Duration::from_millis(1);
 const WAKE_HANDSHAKE_TIMEOUT: Duration = Duration::from_millis(300);
-
-#[derive(Debug, Clone, Copy)]
+/// The SPI protocol version spoken by the NCPs this driver was written for.
+pub(super) const SPI_PROTOCOL_VERSION: u8 = 2;
+/// How many bytes are read at a time while skipping the padding before a
+/// response. Bytes past the end of the response read as padding, so reading
+/// ahead is harmless.
+const PADDING_CHUNK_LEN: usize = 8;
+/// The EZSP `version` command, in the legacy frame format every NCP accepts:
+/// sequence number, frame control, frame ID, then the desired protocol
+/// version. The NCP answers with the version it supports regardless.
+///
+/// The desired version is the command's only parameter and is not optional.
+/// The three byte frame without it is a truncated command, which the NCP
+/// answers with an EZSP error rather than its version.
+const EZSP_VERSION_COMMAND: [u8; 4] = [0x00, 0x00, 0x00, 0x04];
+const EZSP_VERSION_FRAME_ID: u8 = 0x00;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
 pub enum State {
-    Normal,
-    Bootloader,
-    Unknown,
+    Normal = 0,
+    Bootloader = 1,
+    Unknown = 2,
+}
+
+impl Display for State {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            State::Normal => "Normal",
+            State::Bootloader => "Bootloader",
+            State::Unknown => "Unknown",
+        };
+        f.write_str(name)
+    }
+}
+
+impl From<u8> for State {
+    fn from(value: u8) -> Self {
+        match value {
+            0 => State::Normal,
+            1 => State::Bootloader,
+            _ => State::Unknown,
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -49,65 +94,172 @@ impl Into<Result<SuccessResponse>> for RawResponse {
     }
 }
 
+/// Tunable parameters of the NCP driver.
+#[derive(Debug, Clone)]
+pub struct NcpOptions {
+    /// The speed the SPI bus is initially configured with.
+    pub speed_hz: u32,
+    /// The lowest speed the bus will fall back to.
+    pub min_speed_hz: u32,
+    /// Number of consecutive transaction errors before halving the bus speed.
+    /// A value of 0 disables the fallback.
+    pub speed_fallback_errors: u32,
+    /// How long to wait for the driver to complete a request before giving
+    /// up on it. A reset is given this long on top of the NCP startup time.
+    pub request_timeout: Duration,
+    /// Number of times a frame is sent again after the NCP fails to respond.
+    pub send_retries: u32,
+    /// The shortest time the reset line is held asserted to reset the NCP.
+    pub reset_pulse: Duration,
+    /// Number of times the interrupt line is sampled before a callback is
+    /// believed to be pending. Every sample must agree, so a value above 1
+    /// filters out noise on the line.
+    pub interrupt_samples: u32,
+    /// The time between samples of the interrupt line.
+    pub interrupt_sample_interval: Duration,
+    /// The longest time between checks for a pending callback while the
+    /// interrupt line is quiet, in case an edge on the line was missed.
+    pub callback_poll_interval: Duration,
+    /// The SPI protocol versions an NCP may report after a reset. A reset of
+    /// an NCP reporting any other version fails.
+    pub spi_protocol_versions: Vec<u8>,
+    /// Whether the EZSP version is queried once the NCP has reset into
+    /// normal operation. Hosts that negotiate the version themselves may
+    /// want the NCP left alone until they do.
+    pub query_ezsp_version: bool,
+}
+
+impl Default for NcpOptions {
+    fn default() -> Self {
+        NcpOptions {
+            speed_hz: 2000,
+            min_speed_hz: 500,
+            speed_fallback_errors: 3,
+            request_timeout: Duration::from_secs(2),
+            send_retries: 0,
+            reset_pulse: RESET_PULSE_TIME,
+            interrupt_samples: 1,
+            interrupt_sample_interval: Duration::from_micros(50),
+            callback_poll_interval: Duration::from_millis(250),
+            spi_protocol_versions: vec![SPI_PROTOCOL_VERSION],
+            query_ezsp_version: true,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct NCP<D: SpiDevice> {
     device: D,
     state: State,
     read_buf: BytesMut,
     last_command_time: Instant,
+    options: NcpOptions,
+    speed_hz: u32,
+    transaction_errors: u32,
+    spi_protocol_version: Option<u8>,
+    ezsp_version: Option<u8>,
 }
 
 impl<D: SpiDevice> NCP<D> {
     pub fn new(device: D) -> NCP<D> {
+        NCP::with_options(device, NcpOptions::default())
+    }
+
+    pub fn with_options(device: D, options: NcpOptions) -> NCP<D> {
         NCP {
             device,
             state: State::Unknown,
             read_buf: BytesMut::with_capacity(1024),
             last_command_time: Instant::now(),
+            speed_hz: options.speed_hz,
+            options,
+            transaction_errors: 0,
+            spi_protocol_version: None,
+            ezsp_version: None,
         }
     }
 
     fn read_response(&mut self) -> Result<RawResponse> {
-        let mut write_buffer = self.read_buf.clone();
-        // Read and discard 0xFF bytes until a different byte is encountered.
-        write_buffer[0] = 0xFF;
-        while self.read_buf[0] == 0xFF {
-            self.device.read(&mut write_buffer[..1])?;
-        }
-        write_buffer.advance(1);
+        // Read and discard 0xFF bytes until a different byte is encountered,
+        // keeping the rest of the chunk it was read in.
+        let mut chunk = [0xFF; PADDING_CHUNK_LEN];
+        let start = loop {
+            self.device.read(&mut chunk)?;
+            if let Some(start) = chunk.iter().position(|&b| b != 0xFF) {
+                break start;
+            }
+        };
+        self.read_buf.clear();
+        self.read_buf.extend_from_slice(&chunk[start..]);
 
-        // Start parsing a response from the first byte
-        let res = self.try_parse_response(&mut write_buffer);
+        // Continue parsing the response from the first byte
+        let res = self.try_parse_response();
         self.device.set_cs_signal(false)?;
-        self.read_buf = write_buffer;
         res
     }
 
-    fn try_parse_response(&mut self, buffer: &mut BytesMut) -> Result<RawResponse> {
+    fn try_parse_response(&mut self) -> Result<RawResponse> {
         loop {
             let input = self.read_buf.clone().freeze().into();
             let parse_res = RawResponse::parse(input);
 
             if let Err(Err::Incomplete(needed)) = parse_res {
-                if let Needed::Size(size) = needed {
-                    // The response is incomplete, allocate and read the bytes
-                    // into the write buffer.
-                    let additional: usize = size.into();
-                    buffer.reserve(additional);
-                    self.device.read(&mut buffer[..=additional])?;
-                    buffer.advance(additional);
-                } else {
-                    return Err(Error::InvalidResponse);
-                }
+                // The response is incomplete, read the missing bytes onto the
+                // end of the read buffer.
+                let additional = match needed {
+                    Needed::Size(size) => size.get(),
+                    Needed::Unknown => 1,
+                };
+                let start = self.read_buf.len();
+                self.read_buf.resize(start + additional, 0xFF);
+                self.device.read(&mut self.read_buf[start..])?;
             } else {
                 return parse_res
                     .finish()
-                    .map_err(|_| Error::InvalidResponse)
+                    .map_err(|_| {
+                        debug!(
+                            "Failed to parse response from the NCP:\n{}",
+                            hexdump(&self.read_buf, 16)
+                        );
+                        Error::InvalidResponse
+                    })
                     .map(|(_, res)| res);
             }
         }
     }
 
+    /// Count a failed SPI transaction, lowering the bus speed once too many
+    /// consecutive errors have occurred at the current speed.
+    fn record_transaction_error(&mut self) -> Result<()> {
+        if self.options.speed_fallback_errors == 0 {
+            return Ok(());
+        }
+        self.transaction_errors += 1;
+        if self.transaction_errors < self.options.speed_fallback_errors {
+            return Ok(());
+        }
+        self.transaction_errors = 0;
+
+        let speed_hz = (self.speed_hz / 2).max(self.options.min_speed_hz);
+        if speed_hz < self.speed_hz {
+            self.device.set_speed(speed_hz)?;
+            warn!(
+                from = self.speed_hz,
+                to = speed_hz,
+                "Lowered SPI speed from {} Hz to {} Hz after repeated transaction errors",
+                self.speed_hz,
+                speed_hz
+            );
+            self.speed_hz = speed_hz;
+        }
+        Ok(())
+    }
+
+    /// The speed the SPI bus is currently configured with.
+    pub fn speed_hz(&self) -> u32 {
+        self.speed_hz
+    }
+
     fn check_state(&self) -> Result<()> {
         match self.state {
             State::Unknown => Err(Error::NeedsReset),
@@ -115,9 +267,43 @@ impl<D: SpiDevice> NCP<D> {
         }
     }
 
+    /// Wait up to `timeout` for the NCP to raise its interrupt line, returning
+    /// true if it did. The wait ends early once `wakeup` has something to
+    /// read.
+    pub fn wait_for_interrupt_or_wakeup(
+        &mut self,
+        timeout: Duration,
+        wakeup: &UnixStream,
+    ) -> Result<bool> {
+        Ok(self
+            .device
+            .poll_interrupt_signal_or_wakeup(timeout, wakeup)?)
+    }
+
+    /// Returns true if the NCP is signalling a pending callback, sampling the
+    /// interrupt line `interrupt_samples` times.
     pub fn has_callback(&mut self) -> Result<bool> {
-        let res = self.device.get_interrupt_value()?;
-        Ok(res)
+        for sample in 0..self.options.interrupt_samples.max(1) {
+            if sample > 0 {
+                thread::sleep(self.options.interrupt_sample_interval);
+            }
+            if !self.device.get_interrupt_value()? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// The SPI protocol version the NCP reported during its last reset, if the
+    /// reset got that far.
+    pub fn spi_protocol_version(&self) -> Option<u8> {
+        self.spi_protocol_version
+    }
+
+    /// The EZSP protocol version the NCP reported after its last reset, if it
+    /// has been queried.
+    pub fn ezsp_version(&self) -> Option<u8> {
+        self.ezsp_version
     }
 
     /// Get the state of the device.
@@ -140,12 +326,16 @@ impl<D: SpiDevice> NCP<D> {
     /// Write a frame to the SPI bus and wait for a response.
     ///
     /// If the device state is unknown, an 'Error::NeedsReset` will be returned.
+    /// The same error is returned if the NCP is found to have fallen into
+    /// bootloader mode, after which the state is `State::Bootloader`.
     /// If the device is sleeping, an `Error::Unresponsive` will be returned.
+    /// A frame too long to send returns an `Error::OversizedPayload`.
     pub fn send(&mut self, data: Bytes) -> Result<Bytes> {
+        self.check_state()?;
         let command = if self.is_bootloader() {
-            Command::BootloaderFrame(data)
+            Command::new_bootloader_frame(data)?
         } else {
-            Command::EzspFrame(data)
+            Command::new_ezsp_frame(data)?
         };
 
         match self.send_command(&command)? {
@@ -156,13 +346,19 @@ impl<D: SpiDevice> NCP<D> {
         }
     }
 
+    /// Perform a single command transaction. The NCP state is not checked, as
+    /// the reset sequence needs to query the NCP before its state is known.
     fn send_command(&mut self, command: &Command) -> Result<SuccessResponse> {
-        self.check_state()?;
-        while self.last_command_time.elapsed() < INTER_COMMAND_SPACING {}
+        // The driver runs on a blocking thread, so sleeping out the rest of
+        // the spacing gives the CPU back rather than spinning on it
+        let since_last_command = self.last_command_time.elapsed();
+        if since_last_command < INTER_COMMAND_SPACING {
+            thread::sleep(INTER_COMMAND_SPACING - since_last_command);
+        }
 
         self.device.set_cs_signal(true)?;
 
-        let mut buf = BytesMut::with_capacity(command.size());
+        let mut buf = BytesMut::zeroed(command.size());
         command.serialize(&mut buf);
         self.device.write(&buf.freeze())?;
 
@@ -171,63 +367,122 @@ impl<D: SpiDevice> NCP<D> {
             return Err(Error::Unresponsive);
         }
 
-        let res = self.read_response()?;
+        let res = self.read_response();
         self.last_command_time = Instant::now();
 
-        res.into()
+        match res {
+            Ok(RawResponse::AbortedTransaction | RawResponse::MissingFrameTerminator)
+            | Err(Error::InvalidResponse) => self.record_transaction_error()?,
+            Ok(_) => self.transaction_errors = 0,
+            Err(_) => {}
+        }
+
+        // An NCP that has crashed into its bootloader rejects EZSP frames, or
+        // answers them with the bootloader menu.
+        if command.is_ezsp_frame()
+            && matches!(
+                res,
+                Ok(RawResponse::UnsupportedSpiCommand | RawResponse::BootloaderFrame(_))
+            )
+        {
+            warn!("NCP has unexpectedly entered bootloader mode");
+            self.state = State::Bootloader;
+            return Err(Error::NeedsReset);
+        }
+
+        res?.into()
+    }
+
+    /// Ask the NCP which EZSP protocol version it supports, and remember the
+    /// answer.
+    ///
+    /// If the NCP answers with anything other than a version response, an
+    /// `Error::InvalidResponse` is returned.
+    pub fn query_ezsp_version(&mut self) -> Result<u8> {
+        let response = self.send(Bytes::from_static(&EZSP_VERSION_COMMAND))?;
+        let version = match response.as_ref() {
+            [_, _, EZSP_VERSION_FRAME_ID, version, ..] => *version,
+            _ => return Err(Error::InvalidResponse),
+        };
+        info!(version, "NCP supports EZSP version {}", version);
+        self.ezsp_version = Some(version);
+        Ok(version)
     }
 
     fn pulse_reset(&mut self, wake: bool) -> Result<()> {
-        let start_time = Instant::now();
         self.device.set_reset_signal(true)?;
+        let start_time = Instant::now();
         self.device.set_wake_signal(wake)?;
-        while start_time.elapsed() < RESET_PULSE_TIME {}
+        // A sleep may overrun by the timer slack, but is never cut short, and
+        // the NCP only needs the line held for at least the pulse width
+        let remaining = self
+            .options
+            .reset_pulse
+            .saturating_sub(start_time.elapsed());
+        thread::sleep(remaining);
         self.device.set_reset_signal(false)?;
         Ok(())
     }
 
     /// Reset the NCP, optionally into bootloader mode, and wait for the NCP to signal readiness.
     ///
+    /// Returns the reset code the NCP reports, such as power-on or watchdog.
+    /// Outside of bootloader mode, the EZSP version is queried once the NCP
+    /// is ready if `query_ezsp_version` is set, see [`NCP::ezsp_version`].
     /// If the NCP fails to respond to the reset, an `Error::Unresponsive` is
-    /// returned.
-    pub fn reset(&mut self, bootloader: bool) -> Result<()> {
+    /// returned. An NCP reporting an SPI protocol version that is not one of
+    /// `spi_protocol_versions` fails with an `Error::InvalidResponse`.
+    pub fn reset(&mut self, bootloader: bool) -> Result<u8> {
         self.pulse_reset(bootloader)?;
         self.state = State::Unknown;
+        self.spi_protocol_version = None;
+        self.ezsp_version = None;
 
         if !self.device.poll_interrupt_signal(RESET_STARTUP_TIME)? {
             return Err(Error::Unresponsive);
         }
         self.device.set_wake_signal(false)?;
 
-        let version_command = Command::SpiProtocolVersion;
-        if !matches!(
-            self.send_command(&version_command),
-            Err(Error::UnexpectedReset(0x02))
-        ) {
-            return Err(Error::InvalidResponse);
-        }
+        let version_command = Command::spi_protocol_version();
+        let code = match self.send_command(&version_command) {
+            Err(Error::UnexpectedReset(code)) => code,
+            _ => return Err(Error::InvalidResponse),
+        };
 
-        if !matches!(
-            self.send_command(&version_command)?,
-            SuccessResponse::SpiProtocolVersion(2)
-        ) {
+        let version = match self.send_command(&version_command)? {
+            SuccessResponse::SpiProtocolVersion(version) => version,
+            _ => return Err(Error::InvalidResponse),
+        };
+        if !self.options.spi_protocol_versions.contains(&version) {
+            warn!(
+                version,
+                "NCP reported unsupported SPI protocol version {}", version
+            );
             return Err(Error::InvalidResponse);
         }
+        self.spi_protocol_version = Some(version);
 
         if !matches!(
-            self.send_command(&Command::SpiStatus)?,
+            self.send_command(&Command::spi_status())?,
             SuccessResponse::SpiStatus(true)
         ) {
             return Err(Error::InvalidResponse);
         }
 
-        self.state = if bootloader {
-            State::Bootloader
-        } else {
-            State::Normal
-        };
+        if bootloader {
+            self.state = State::Bootloader;
+            return Ok(code);
+        }
 
-        Ok(())
+        self.state = State::Normal;
+        if !self.options.query_ezsp_version {
+            return Ok(code);
+        }
+        if let Err(e) = self.query_ezsp_version() {
+            self.state = State::Unknown;
+            return Err(e);
+        }
+        Ok(code)
     }
 
     /// Wakeup the NCP and wait for the NCP to signal readiness.
@@ -246,17 +501,385 @@ impl<D: SpiDevice> NCP<D> {
         Ok(())
     }
 
-    pub fn into_inner(self) -> D {
+    /// Give up the NCP, returning the device it was driven through.
+    pub fn into_device(self) -> D {
         self.device
     }
+
+    #[deprecated(since = "0.2.0", note = "Use into_device()")]
+    pub fn into_inner(self) -> D {
+        self.into_device()
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::spi::device::MockSpiDevice;
+    use crate::{
+        ash::{RESET_EXTERNAL, RESET_POWERON, RESET_SOFTWARE, RESET_WATCHDOG},
+        spi::device::MockSpiDevice,
+        test::{scripted_device, scripted_reads},
+    };
+    use mockall::predicate::eq;
+    use std::{
+        io,
+        sync::{Arc, Mutex},
+    };
 
     use super::*;
 
+    /// The answer to the EZSP version command from an NCP supporting EZSP
+    /// version 8.
+    const EZSP_VERSION_RESPONSE: &[u8] =
+        &[0xFE, 0x07, 0x00, 0x80, 0x00, 0x08, 0x02, 0x00, 0x67, 0xA7];
+
+    #[test]
+    fn it_reads_a_response_after_padding_bytes() {
+        let device = scripted_device(&[&[0xFF, 0xFF, 0xFE, 0x02, 0x01, 0x02, 0xA7]]);
+        let mut ncp = NCP::new(device);
+        ncp.state = State::Normal;
+
+        let res = ncp.send(Bytes::from_static(&[0x01]));
+        assert_eq!(res.unwrap(), Bytes::from_static(&[0x01, 0x02]));
+    }
+
+    #[test]
+    fn it_skips_padding_in_chunks() {
+        let mut response = vec![0xFF; 40];
+        response.extend_from_slice(&[0xFE, 0x02, 0x01, 0x02, 0xA7]);
+        let mut response = response.into_iter();
+        let reads = Arc::new(Mutex::new(0));
+        let counted = reads.clone();
+        let mut device = MockSpiDevice::new();
+        device.expect_set_cs_signal().returning(|_| Ok(()));
+        device.expect_write().returning(|_| Ok(()));
+        device
+            .expect_poll_interrupt_signal()
+            .returning(|_| Ok(true));
+        device.expect_read().returning(move |buf| {
+            *counted.lock().unwrap() += 1;
+            buf.fill_with(|| response.next().unwrap_or(0xFF));
+            Ok(())
+        });
+        let mut ncp = NCP::new(device);
+        ncp.state = State::Normal;
+
+        let res = ncp.send(Bytes::from_static(&[0x01]));
+        assert_eq!(res.unwrap(), Bytes::from_static(&[0x01, 0x02]));
+        // One read for each chunk of padding, rather than each byte
+        assert!(*reads.lock().unwrap() <= 40 / PADDING_CHUNK_LEN + 2);
+    }
+
+    #[test]
+    fn it_rejects_a_response_longer_than_the_bytes_sent() {
+        // The NCP claims 16 bytes but sends 2, then idles at 0xFF
+        let device = scripted_device(&[&[0xFE, 0x10, 0x01, 0x02, 0xA7]]);
+        let mut ncp = NCP::new(device);
+        ncp.state = State::Normal;
+
+        let res = ncp.send(Bytes::from_static(&[0x01]));
+        assert!(matches!(res, Err(Error::InvalidResponse)));
+    }
+
+    #[test]
+    fn it_lowers_the_spi_speed_after_repeated_transaction_errors() {
+        let aborted: &[u8] = &[0x02, 0x00, 0xA7];
+        let mut device = scripted_device(&[aborted, aborted, aborted]);
+        device
+            .expect_set_speed()
+            .with(eq(1000))
+            .times(1)
+            .returning(|_| Ok(()));
+        let options = NcpOptions {
+            speed_hz: 2000,
+            min_speed_hz: 500,
+            speed_fallback_errors: 3,
+            ..NcpOptions::default()
+        };
+        let mut ncp = NCP::with_options(device, options);
+        ncp.state = State::Normal;
+
+        for _ in 0..3 {
+            assert!(ncp.send(Bytes::from_static(&[0x01])).is_err());
+        }
+        assert_eq!(ncp.speed_hz(), 1000);
+    }
+
+    #[test]
+    fn it_does_not_lower_the_spi_speed_below_the_floor() {
+        let aborted: &[u8] = &[0x02, 0x00, 0xA7];
+        let mut device = scripted_device(&[aborted, aborted]);
+        device.expect_set_speed().never();
+        let options = NcpOptions {
+            speed_hz: 500,
+            min_speed_hz: 500,
+            speed_fallback_errors: 1,
+            ..NcpOptions::default()
+        };
+        let mut ncp = NCP::with_options(device, options);
+        ncp.state = State::Normal;
+
+        for _ in 0..2 {
+            assert!(ncp.send(Bytes::from_static(&[0x01])).is_err());
+        }
+        assert_eq!(ncp.speed_hz(), 500);
+    }
+
+    #[test]
+    fn it_detects_an_unsupported_command_as_a_fall_into_the_bootloader() {
+        let device = scripted_device(&[&[0x04, 0x00, 0xA7]]);
+        let mut ncp = NCP::new(device);
+        ncp.state = State::Normal;
+
+        let res = ncp.send(Bytes::from_static(&[0x01]));
+        assert!(matches!(res, Err(Error::NeedsReset)));
+        assert_eq!(ncp.state(), State::Bootloader);
+    }
+
+    #[test]
+    fn it_detects_a_bootloader_response_as_a_fall_into_the_bootloader() {
+        let device = scripted_device(&[&[0xFD, 0x01, 0x0A, 0xA7]]);
+        let mut ncp = NCP::new(device);
+        ncp.state = State::Normal;
+
+        let res = ncp.send(Bytes::from_static(&[0x01]));
+        assert!(matches!(res, Err(Error::NeedsReset)));
+        assert!(ncp.is_bootloader());
+    }
+
+    #[test]
+    fn it_reports_a_failed_write_as_a_transfer_error() {
+        let mut device = MockSpiDevice::new();
+        device.expect_set_cs_signal().returning(|_| Ok(()));
+        device
+            .expect_write()
+            .returning(|_| Err(io::Error::from(io::ErrorKind::BrokenPipe)));
+        let mut ncp = NCP::new(device);
+        ncp.state = State::Normal;
+
+        let err = ncp
+            .send(Bytes::from_static(&[0x01]))
+            .expect_err("Expected the send to fail");
+        assert!(err.is_transfer());
+        assert!(matches!(err, Error::Transfer(e) if e.kind() == io::ErrorKind::BrokenPipe));
+    }
+
+    #[test]
+    fn it_spaces_back_to_back_commands_apart() {
+        let writes = Arc::new(Mutex::new(Vec::new()));
+        let recorded = writes.clone();
+        let mut device = MockSpiDevice::new();
+        let response: &[u8] = &[0xFE, 0x01, 0x01, 0xA7];
+        scripted_reads(&mut device, &[response, response]);
+        device.expect_write().returning(move |_| {
+            recorded.lock().unwrap().push(Instant::now());
+            Ok(())
+        });
+        device
+            .expect_poll_interrupt_signal()
+            .returning(|_| Ok(true));
+        let mut ncp = NCP::new(device);
+        ncp.state = State::Normal;
+
+        for _ in 0..2 {
+            ncp.send(Bytes::from_static(&[0x01]))
+                .expect("Expected the NCP to respond");
+        }
+
+        let writes = writes.lock().unwrap();
+        assert!(writes[1] - writes[0] >= INTER_COMMAND_SPACING);
+    }
+
+    #[test]
+    fn it_holds_the_reset_line_for_the_configured_pulse_width() {
+        for reset_pulse in [RESET_PULSE_TIME, Duration::from_millis(2)] {
+            let edges = Arc::new(Mutex::new(Vec::new()));
+            let recorded = edges.clone();
+            let mut device = MockSpiDevice::new();
+            device.expect_set_wake_signal().returning(|_| Ok(()));
+            device.expect_set_reset_signal().returning(move |_| {
+                recorded.lock().unwrap().push(Instant::now());
+                Ok(())
+            });
+            let options = NcpOptions {
+                reset_pulse,
+                ..NcpOptions::default()
+            };
+            let mut ncp = NCP::with_options(device, options);
+
+            ncp.pulse_reset(false)
+                .expect("Expected the pulse to be sent");
+
+            let edges = edges.lock().unwrap();
+            assert!(edges[1] - edges[0] >= reset_pulse);
+        }
+    }
+
+    #[test]
+    fn it_refuses_to_send_a_frame_too_long_for_the_ncp() {
+        // Nothing is written to the device
+        let mut ncp = NCP::new(MockSpiDevice::new());
+        ncp.state = State::Normal;
+
+        let res = ncp.send(BytesMut::zeroed(256).freeze());
+        assert!(matches!(res, Err(Error::OversizedPayload)));
+    }
+
+    #[test]
+    fn it_returns_the_reset_code_reported_by_the_ncp() {
+        for code in [
+            RESET_POWERON,
+            RESET_EXTERNAL,
+            RESET_WATCHDOG,
+            RESET_SOFTWARE,
+        ] {
+            let device = scripted_device(&[
+                &[0x00, code, 0xA7],
+                &[0x82, 0xA7],
+                &[0xC1, 0xA7],
+                EZSP_VERSION_RESPONSE,
+            ]);
+            let mut ncp = NCP::new(device);
+
+            assert_eq!(ncp.reset(false).expect("Expected the NCP to reset"), code);
+            assert_eq!(ncp.state(), State::Normal);
+        }
+    }
+
+    #[test]
+    fn it_queries_the_ezsp_version_after_a_reset() {
+        let device = scripted_device(&[
+            &[0x00, RESET_POWERON, 0xA7],
+            &[0x82, 0xA7],
+            &[0xC1, 0xA7],
+            EZSP_VERSION_RESPONSE,
+        ]);
+        let mut ncp = NCP::new(device);
+        assert_eq!(ncp.ezsp_version(), None);
+
+        ncp.reset(false).expect("Expected the NCP to reset");
+        assert_eq!(ncp.ezsp_version(), Some(8));
+    }
+
+    #[test]
+    fn it_leaves_the_ezsp_version_alone_when_told_to() {
+        let device = scripted_device(&[
+            &[0x00, RESET_POWERON, 0xA7],
+            &[0x82, 0xA7],
+            &[0xC1, 0xA7],
+            EZSP_VERSION_RESPONSE,
+        ]);
+        let options = NcpOptions {
+            query_ezsp_version: false,
+            ..NcpOptions::default()
+        };
+        let mut ncp = NCP::with_options(device, options);
+
+        ncp.reset(false).expect("Expected the NCP to reset");
+        assert_eq!(ncp.state(), State::Normal);
+        assert_eq!(ncp.ezsp_version(), None);
+    }
+
+    #[test]
+    fn it_remembers_an_accepted_spi_protocol_version() {
+        let device = scripted_device(&[
+            &[0x00, RESET_POWERON, 0xA7],
+            &[0x83, 0xA7],
+            &[0xC1, 0xA7],
+            EZSP_VERSION_RESPONSE,
+        ]);
+        let options = NcpOptions {
+            spi_protocol_versions: vec![2, 3],
+            ..NcpOptions::default()
+        };
+        let mut ncp = NCP::with_options(device, options);
+        assert_eq!(ncp.spi_protocol_version(), None);
+
+        ncp.reset(false).expect("Expected the NCP to reset");
+        assert_eq!(ncp.spi_protocol_version(), Some(3));
+    }
+
+    #[test]
+    fn it_rejects_an_unsupported_spi_protocol_version() {
+        let device = scripted_device(&[&[0x00, RESET_POWERON, 0xA7], &[0x83, 0xA7]]);
+        let mut ncp = NCP::new(device);
+
+        assert!(matches!(ncp.reset(false), Err(Error::InvalidResponse)));
+        assert_eq!(ncp.spi_protocol_version(), None);
+        assert_eq!(ncp.state(), State::Unknown);
+    }
+
+    #[test]
+    fn it_sends_the_ezsp_version_command() {
+        let mut device = MockSpiDevice::new();
+        scripted_reads(&mut device, &[EZSP_VERSION_RESPONSE]);
+        device
+            .expect_write()
+            .withf(|buf| buf == [0xFE, 0x04, 0x00, 0x00, 0x00, 0x04, 0xA7])
+            .times(1)
+            .returning(|_| Ok(()));
+        device
+            .expect_poll_interrupt_signal()
+            .returning(|_| Ok(true));
+        let mut ncp = NCP::new(device);
+        ncp.state = State::Normal;
+
+        assert_eq!(ncp.query_ezsp_version().unwrap(), 8);
+        assert_eq!(ncp.ezsp_version(), Some(8));
+    }
+
+    #[test]
+    fn it_rejects_a_response_that_is_not_a_version_response() {
+        let device = scripted_device(&[&[0xFE, 0x03, 0x00, 0x80, 0x58, 0xA7]]);
+        let mut ncp = NCP::new(device);
+        ncp.state = State::Normal;
+
+        assert!(matches!(
+            ncp.query_ezsp_version(),
+            Err(Error::InvalidResponse)
+        ));
+        assert_eq!(ncp.ezsp_version(), None);
+    }
+
+    #[test]
+    fn it_names_each_state() {
+        assert_eq!(State::Normal.to_string(), "Normal");
+        assert_eq!(State::Bootloader.to_string(), "Bootloader");
+        assert_eq!(State::Unknown.to_string(), "Unknown");
+    }
+
+    #[test]
+    fn has_callback_ignores_a_line_that_toggles_between_samples() {
+        let mut level = false;
+        let mut device = MockSpiDevice::new();
+        device.expect_get_interrupt_value().returning(move || {
+            level = !level;
+            Ok(level)
+        });
+        let options = NcpOptions {
+            interrupt_samples: 3,
+            ..NcpOptions::default()
+        };
+
+        let mut ncp = NCP::with_options(device, options);
+        assert!(matches!(ncp.has_callback(), Ok(false)));
+    }
+
+    #[test]
+    fn has_callback_samples_a_steady_line_the_configured_number_of_times() {
+        let mut device = MockSpiDevice::new();
+        device
+            .expect_get_interrupt_value()
+            .times(3)
+            .returning(|| Ok(true));
+        let options = NcpOptions {
+            interrupt_samples: 3,
+            ..NcpOptions::default()
+        };
+
+        let mut ncp = NCP::with_options(device, options);
+        assert!(matches!(ncp.has_callback(), Ok(true)));
+    }
+
     #[test]
     fn has_callback_returns_true_when_callback_is_present() {
         let mut device = MockSpiDevice::new();
@@ -5,9 +5,9 @@ use nom::{
         streaming::{bool, tag as bits_tag, take as bits_take},
     },
     bytes::streaming::{tag, take},
-    combinator::{flat_map, map, value},
+    combinator::{flat_map, map, peek, value, verify},
     error::Error,
-    number::streaming::u8,
+    number::streaming::{be_u16, u8},
     sequence::{preceded, terminated},
     IResult,
 };
@@ -29,6 +29,11 @@ pub enum RawResponse {
 
 pub type ParserResult<O> = IResult<Buffer, O>;
 
+/// The longest EZSP frame an extended frame response may declare, well beyond
+/// any real EZSP frame. A longer length is taken to be corrupt, rather than
+/// waiting for bytes the NCP will never send.
+pub const MAX_EXTENDED_FRAME_LEN: u16 = 1024;
+
 impl RawResponse {
     pub fn parse(input: Buffer) -> ParserResult<RawResponse> {
         terminated(
@@ -41,6 +46,7 @@ impl RawResponse {
                 RawResponse::parse_spi_protocol_version,
                 RawResponse::parse_spi_status,
                 RawResponse::parse_bootloader_frame,
+                RawResponse::parse_extended_ezsp_frame,
                 RawResponse::parse_ezsp_frame,
             )),
             tag([0xA7]),
@@ -107,6 +113,24 @@ impl RawResponse {
         )(input)
     }
 
+    /// Parse an EZSP frame too long for a single length byte. A length byte
+    /// of `0xFF` is followed by the real length as a big-endian `u16`.
+    ///
+    /// A normal frame of exactly 255 bytes starts the same way, so only a
+    /// length above 255 that is followed by the frame terminator is taken
+    /// as an extended frame. The NCP pads with `0xFF` after the terminator of
+    /// a normal frame, so a 255 byte frame never passes for an extended one.
+    fn parse_extended_ezsp_frame(input: Buffer) -> ParserResult<RawResponse> {
+        let len = verify(be_u16, |len| (0x100..=MAX_EXTENDED_FRAME_LEN).contains(len));
+        preceded(
+            tag([0xFE, 0xFF]),
+            map(
+                terminated(flat_map(len, take), peek(tag([0xA7]))),
+                |b: Buffer| RawResponse::EzspFrame(b.into_inner()),
+            ),
+        )(input)
+    }
+
     fn parse_ezsp_frame(input: Buffer) -> ParserResult<RawResponse> {
         preceded(
             tag([0xFE]),
@@ -169,6 +193,16 @@ mod tests {
         assert_eq!(res, RawResponse::SpiProtocolVersion(0x2A))
     }
 
+    #[test]
+    fn it_reads_the_spi_protocol_version_from_the_low_six_bits() {
+        for (byte, version) in [(0x82, 2), (0x80, 0), (0xBF, 63)] {
+            let buf = Buffer::from(Bytes::from(vec![byte, 0xA7]));
+            let (_rest, res) = RawResponse::parse(buf).unwrap();
+
+            assert_eq!(res, RawResponse::SpiProtocolVersion(version));
+        }
+    }
+
     #[test]
     fn it_parses_spi_status_response() {
         let buf = Buffer::from_static(&[0xC1, 0xA7]);
@@ -198,4 +232,69 @@ mod tests {
             RawResponse::EzspFrame(Bytes::from_static(&[0x01, 0x02, 0x03]))
         )
     }
+
+    fn extended_ezsp_frame(payload: &[u8]) -> Buffer {
+        let mut buf = vec![0xFE, 0xFF];
+        buf.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+        buf.extend_from_slice(payload);
+        buf.push(0xA7);
+        Bytes::from(buf).into()
+    }
+
+    #[test]
+    fn it_parses_extended_ezsp_frame_responses() {
+        for len in [256, 512] {
+            let payload: Vec<u8> = (0..len).map(|i| i as u8).collect();
+            let (rest, res) = RawResponse::parse(extended_ezsp_frame(&payload)).unwrap();
+
+            assert_eq!(res, RawResponse::EzspFrame(Bytes::from(payload)));
+            assert!(rest.is_empty());
+        }
+    }
+
+    fn ezsp_frame(payload: &[u8]) -> Vec<u8> {
+        let mut buf = vec![0xFE, payload.len() as u8];
+        buf.extend_from_slice(payload);
+        buf.push(0xA7);
+        buf
+    }
+
+    #[test]
+    fn it_parses_a_255_byte_ezsp_frame_response() {
+        let payload: Vec<u8> = (0..255).map(|i| (i + 0x10) as u8).collect();
+        let buf = Buffer::from(Bytes::from(ezsp_frame(&payload)));
+        let (rest, res) = RawResponse::parse(buf).unwrap();
+
+        assert_eq!(res, RawResponse::EzspFrame(Bytes::from(payload)));
+        assert!(rest.is_empty());
+    }
+
+    #[test]
+    fn it_parses_a_255_byte_ezsp_frame_that_looks_extended() {
+        // The sequence number and frame control read as a length of 384
+        let payload: Vec<u8> = [0x01, 0x80].into_iter().chain([0x00; 253]).collect();
+        let mut buf = ezsp_frame(&payload);
+        buf.resize(512, 0xFF);
+        let (_rest, res) = RawResponse::parse(Bytes::from(buf).into()).unwrap();
+
+        assert_eq!(res, RawResponse::EzspFrame(Bytes::from(payload)));
+    }
+
+    #[test]
+    fn it_rejects_an_implausible_extended_frame_length() {
+        // Read as a normal frame instead, it is left without a terminator
+        let mut buf = vec![0xFE, 0xFF, 0xFF, 0xFF, 0x01, 0xA7];
+        buf.resize(512, 0xFF);
+        let res = RawResponse::parse(Bytes::from(buf).into());
+
+        assert!(matches!(res, Err(nom::Err::Error(_))));
+    }
+
+    #[test]
+    fn it_asks_for_the_rest_of_an_extended_ezsp_frame() {
+        let buf = Buffer::from_static(&[0xFE, 0xFF, 0x01, 0x00, 0x01, 0x02]);
+        let res = RawResponse::parse(buf);
+
+        assert!(matches!(res, Err(nom::Err::Incomplete(_))));
+    }
 }
@@ -0,0 +1,61 @@
+use crate::spi::MockSpiDevice;
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+};
+
+/// Create a device that answers each transaction with the next of
+/// `responses`, and reads `0xFF` once it has been read in full, as the NCP
+/// does.
+///
+/// The device never raises a callback interrupt and accepts any GPIO changes,
+/// so it can be driven through resets as well as plain commands.
+pub fn scripted_device(responses: &[&[u8]]) -> MockSpiDevice {
+    scripted_device_with_interrupt(responses, || false)
+}
+
+/// Create a scripted device like [`scripted_device`], reading the level of
+/// the callback interrupt line from `interrupt`.
+pub fn scripted_device_with_interrupt(
+    responses: &[&[u8]],
+    mut interrupt: impl FnMut() -> bool + Send + 'static,
+) -> MockSpiDevice {
+    let mut device = MockSpiDevice::new();
+    scripted_reads(&mut device, responses);
+    device.expect_set_wake_signal().returning(|_| Ok(()));
+    device.expect_set_reset_signal().returning(|_| Ok(()));
+    device.expect_write().returning(|_| Ok(()));
+    device
+        .expect_poll_interrupt_signal()
+        .returning(|_| Ok(true));
+    device
+        .expect_poll_interrupt_signal_or_wakeup()
+        .returning(|_, _| Ok(true));
+    device
+        .expect_get_interrupt_value()
+        .returning(move || Ok(interrupt()));
+    device
+}
+
+/// Answer each transaction on `device`, started by selecting the chip, with
+/// the next of `responses`, leaving the other expectations to the caller.
+pub fn scripted_reads(device: &mut MockSpiDevice, responses: &[&[u8]]) {
+    let mut responses: VecDeque<Vec<u8>> = responses.iter().map(|r| r.to_vec()).collect();
+    let current = Arc::new(Mutex::new(VecDeque::new()));
+
+    let transaction = current.clone();
+    device.expect_set_cs_signal().returning(move |selected| {
+        if selected {
+            let next = responses.pop_front().unwrap_or_default();
+            *transaction.lock().expect("Mutex was poisoned") = next.into();
+        }
+        Ok(())
+    });
+    device.expect_read().returning(move |buf| {
+        let mut current = current.lock().expect("Mutex was poisoned");
+        for byte in buf.iter_mut() {
+            *byte = current.pop_front().unwrap_or(0xFF);
+        }
+        Ok(())
+    });
+}
@@ -1,3 +1,5 @@
+mod device;
 mod sink;
 
-pub use sink::MockTestSink;
\ No newline at end of file
+pub use device::{scripted_device, scripted_device_with_interrupt, scripted_reads};
+pub use sink::MockTestSink;
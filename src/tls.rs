@@ -0,0 +1,184 @@
+use anyhow::{anyhow, Context, Result};
+use rustls_pemfile::{certs, pkcs8_private_keys, rsa_private_keys};
+use std::{fs::File, io::BufReader, path::Path, sync::Arc, time::Duration};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    time::timeout,
+};
+use tokio_rustls::{
+    rustls::{Certificate, PrivateKey, ServerConfig},
+    server::TlsStream,
+    TlsAcceptor,
+};
+
+use crate::settings::Tls;
+
+fn load_certs(path: &Path) -> Result<Vec<Certificate>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let certs = certs(&mut reader)?;
+    if certs.is_empty() {
+        return Err(anyhow!("No certificates found in {}", path.display()));
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn load_key(path: &Path) -> Result<PrivateKey> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut keys = pkcs8_private_keys(&mut reader)?;
+    if keys.is_empty() {
+        let mut reader = BufReader::new(File::open(path)?);
+        keys = rsa_private_keys(&mut reader)?;
+    }
+    keys.into_iter()
+        .next()
+        .map(PrivateKey)
+        .ok_or_else(|| anyhow!("No private key found in {}", path.display()))
+}
+
+pub fn create_tls_acceptor(settings: &Tls) -> Result<TlsAcceptor> {
+    let certs = load_certs(&settings.cert)
+        .with_context(|| format!("Unable to load TLS certificate {}", settings.cert.display()))?;
+    let key = load_key(&settings.key)
+        .with_context(|| format!("Unable to load TLS key {}", settings.key.display()))?;
+    let config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Complete the TLS handshake with a host, giving up if it takes longer than
+/// `handshake_timeout` so that a host which stalls cannot hold the bridge.
+pub async fn accept_tls<IO>(
+    acceptor: &TlsAcceptor,
+    client: IO,
+    handshake_timeout: Duration,
+) -> Result<TlsStream<IO>>
+where
+    IO: AsyncRead + AsyncWrite + Unpin,
+{
+    timeout(handshake_timeout, acceptor.accept(client))
+        .await
+        .map_err(|_| {
+            anyhow!(
+                "TLS handshake did not complete within {:?}",
+                handshake_timeout
+            )
+        })?
+        .context("TLS handshake failed")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use tokio::{
+        io::{duplex, AsyncReadExt, AsyncWriteExt},
+        spawn,
+    };
+    use tokio_rustls::{
+        rustls::{ClientConfig, RootCertStore, ServerName},
+        TlsConnector,
+    };
+
+    fn fixture(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures")
+            .join(name)
+    }
+
+    fn self_signed() -> Tls {
+        Tls {
+            cert: fixture("cert.pem"),
+            key: fixture("key.pem"),
+        }
+    }
+
+    fn connector() -> TlsConnector {
+        let mut roots = RootCertStore::empty();
+        for cert in load_certs(&fixture("cert.pem")).expect("Expected to load the certificate") {
+            roots
+                .add(&cert)
+                .expect("Expected the certificate to be a valid root");
+        }
+        let config = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        TlsConnector::from(Arc::new(config))
+    }
+
+    #[test]
+    fn it_creates_an_acceptor_from_a_self_signed_certificate() {
+        assert!(create_tls_acceptor(&self_signed()).is_ok());
+    }
+
+    #[test]
+    fn it_reports_a_missing_certificate() {
+        let settings = Tls {
+            cert: fixture("missing.pem"),
+            key: fixture("key.pem"),
+        };
+        let error = create_tls_acceptor(&settings)
+            .map(|_| ())
+            .expect_err("Expected a missing certificate to be an error");
+        assert!(error.to_string().contains("Unable to load TLS certificate"));
+    }
+
+    #[test]
+    fn it_reports_a_file_without_a_private_key() {
+        let settings = Tls {
+            cert: fixture("cert.pem"),
+            key: fixture("cert.pem"),
+        };
+        let error = create_tls_acceptor(&settings)
+            .map(|_| ())
+            .expect_err("Expected a file without a key to be an error");
+        assert!(error.to_string().contains("Unable to load TLS key"));
+    }
+
+    #[tokio::test]
+    async fn it_completes_a_handshake_with_a_host() {
+        let acceptor = create_tls_acceptor(&self_signed()).expect("Expected an acceptor");
+        let (host, client) = duplex(4096);
+        let server = spawn(async move {
+            let mut stream = accept_tls(&acceptor, client, Duration::from_secs(5))
+                .await
+                .expect("Expected the handshake to complete");
+            let mut message = [0; 4];
+            stream
+                .read_exact(&mut message)
+                .await
+                .expect("Expected to read from the host");
+            message
+        });
+
+        let domain = ServerName::try_from("localhost").expect("Expected a valid server name");
+        let mut host = connector()
+            .connect(domain, host)
+            .await
+            .expect("Expected the host to complete the handshake");
+        host.write_all(&[0x1A, 0xC0, 0x38, 0xBC])
+            .await
+            .expect("Expected to write to the bridge");
+        host.flush().await.expect("Expected to flush the stream");
+
+        let message = timeout(Duration::from_secs(5), server)
+            .await
+            .expect("Expected the bridge to read before the timeout")
+            .expect("Expected the bridge not to panic");
+        assert_eq!(message, [0x1A, 0xC0, 0x38, 0xBC]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn it_gives_up_on_a_host_that_stalls_the_handshake() {
+        let acceptor = create_tls_acceptor(&self_signed()).expect("Expected an acceptor");
+        let (_host, client) = duplex(4096);
+
+        let error = accept_tls(&acceptor, client, Duration::from_secs(10))
+            .await
+            .map(|_| ())
+            .expect_err("Expected the handshake to time out");
+        assert!(error.to_string().contains("did not complete"));
+    }
+}
@@ -0,0 +1,239 @@
+//! Runs full sessions against a mock NCP, without any hardware.
+//!
+//! In an ASH session, a real `AshStreamTask` talks to the host over in-memory
+//! channels, while the test plays the part of the bridge, relaying between
+//! the task and the NCP. A transparent session runs the bridge itself.
+use bytes::BytesMut;
+use ezsp_spi_driver::{
+    ash::{
+        create_ash_stream_task, randomize, AshStreamOptions, Error, Frame, FrameNumber,
+        RESET_POWERON,
+    },
+    bridge::handle_transparent,
+    health::Health,
+    spi::{spi_device_handle, MockSpiDevice, NcpOptions},
+};
+use futures::sink;
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    io::{duplex, AsyncReadExt, AsyncWriteExt},
+    spawn,
+    sync::mpsc::{unbounded_channel, UnboundedReceiver},
+    time::timeout,
+};
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use tokio_util::either::Either;
+
+/// The SPI responses the NCP gives to a reset: the reset code, its protocol
+/// version and status, then its answer to the EZSP version command.
+const RESET_RESPONSES: [&[u8]; 4] = [
+    &[0x00, 0x02, 0xA7],
+    &[0x82, 0xA7],
+    &[0xC1, 0xA7],
+    &[0xFE, 0x07, 0x00, 0x80, 0x00, 0x08, 0x02, 0x00, 0x67, 0xA7],
+];
+
+/// Create a device that answers each transaction with the next of
+/// `responses`, and reads `0xFF` once it has been read in full, as the NCP
+/// does.
+fn scripted_device(responses: &[&[u8]]) -> MockSpiDevice {
+    let mut responses: VecDeque<Vec<u8>> = responses.iter().map(|r| r.to_vec()).collect();
+    let current = Arc::new(Mutex::new(VecDeque::new()));
+
+    let mut device = MockSpiDevice::new();
+    let transaction = current.clone();
+    device.expect_set_cs_signal().returning(move |selected| {
+        if selected {
+            let next = responses.pop_front().unwrap_or_default();
+            *transaction.lock().expect("Mutex was poisoned") = next.into();
+        }
+        Ok(())
+    });
+    device.expect_set_wake_signal().returning(|_| Ok(()));
+    device.expect_set_reset_signal().returning(|_| Ok(()));
+    device.expect_write().returning(|_| Ok(()));
+    device
+        .expect_poll_interrupt_signal()
+        .returning(|_| Ok(true));
+    device
+        .expect_poll_interrupt_signal_or_wakeup()
+        .returning(|_, _| Ok(true));
+    device.expect_get_interrupt_value().returning(|| Ok(false));
+    device.expect_read().returning(move |buf| {
+        let mut current = current.lock().expect("Mutex was poisoned");
+        for byte in buf.iter_mut() {
+            *byte = current.pop_front().unwrap_or(0xFF);
+        }
+        Ok(())
+    });
+    device
+}
+
+async fn next_frame(host: &mut UnboundedReceiver<Frame>) -> Frame {
+    timeout(Duration::from_secs(5), host.recv())
+        .await
+        .expect("Expected a frame before the timeout")
+        .expect("Expected the ASH task to stay running")
+}
+
+#[tokio::test]
+async fn it_resets_the_ncp_and_relays_a_data_frame() {
+    let device = scripted_device(&[
+        RESET_RESPONSES[0],
+        RESET_RESPONSES[1],
+        RESET_RESPONSES[2],
+        RESET_RESPONSES[3],
+        &[0xFE, 0x03, 0x01, 0x80, 0x00, 0xA7],
+    ]);
+    let (_actor, device) =
+        spi_device_handle(device, NcpOptions::default()).expect("Expected to spawn the SPI actor");
+
+    let (host_tx, reader) = unbounded_channel::<Result<Frame, Error>>();
+    let (writer, mut host_rx) = unbounded_channel();
+    let writer = sink::unfold(writer, |writer, frame: Frame| async move {
+        writer.send(frame)?;
+        Ok::<_, Error>(writer)
+    });
+    let (mut task, mut stream) = create_ash_stream_task(
+        UnboundedReceiverStream::new(reader),
+        Box::pin(writer),
+        AshStreamOptions::default(),
+    );
+    let _task = spawn(async move { task.run().await });
+
+    host_tx.send(Ok(Frame::rst())).unwrap();
+    match stream.receive().await.expect("Expected a reset request") {
+        Either::Right(ret) => {
+            let code = device
+                .reset_normal()
+                .await
+                .expect("Expected the NCP to reset");
+            ret.send(Ok(code)).unwrap();
+        }
+        Either::Left(data) => panic!("Expected a reset request, got {:?}", data),
+    }
+    let frame = next_frame(&mut host_rx).await;
+    assert!(matches!(frame, Frame::RstAck { code, .. } if code == RESET_POWERON));
+
+    // DATA bodies arrive from the host still randomized
+    let mut body = BytesMut::from(&[0x01, 0x00, 0x00][..]);
+    randomize(&mut body);
+    host_tx
+        .send(Ok(Frame::data(
+            FrameNumber::zero(),
+            false,
+            FrameNumber::zero(),
+            body,
+        )))
+        .unwrap();
+    let command = match stream.receive().await.expect("Expected an EZSP frame") {
+        Either::Left(command) => command,
+        Either::Right(_) => panic!("Expected an EZSP frame, got a reset request"),
+    };
+    assert_eq!(command.as_ref(), [0x01, 0x00, 0x00]);
+    let response = device
+        .send_frame(command.freeze())
+        .await
+        .expect("Expected the NCP to respond");
+    stream
+        .send(Either::Left(BytesMut::from(&response[..])))
+        .await
+        .expect("Expected the response to be sent");
+
+    match next_frame(&mut host_rx).await {
+        // Frames are randomized as they are serialized, so the body of a frame
+        // written by the task is still plain
+        Frame::Data {
+            frm_num,
+            ack_num,
+            body,
+            ..
+        } => {
+            assert_eq!(*frm_num, 0);
+            assert_eq!(*ack_num, 1);
+            assert_eq!(body.as_ref(), [0x01, 0x80, 0x00]);
+        }
+        frame => panic!("Expected a DATA frame, got {}", frame),
+    }
+}
+
+#[tokio::test]
+async fn it_relays_frames_in_transparent_mode() {
+    let device = scripted_device(&[
+        RESET_RESPONSES[0],
+        RESET_RESPONSES[1],
+        RESET_RESPONSES[2],
+        RESET_RESPONSES[3],
+        &[0xFE, 0x03, 0x01, 0x80, 0x00, 0xA7],
+    ]);
+    let (_actor, device) =
+        spi_device_handle(device, NcpOptions::default()).expect("Expected to spawn the SPI actor");
+    let health = Health::new(&device);
+    let (mut host, client) = duplex(1024);
+    let _bridge = spawn(handle_transparent(client, device, health));
+
+    // An empty message resets the NCP, and is answered with the reset code
+    host.write_all(&[0x00, 0x00])
+        .await
+        .expect("Expected to request a reset");
+    let mut answer = [0; 3];
+    timeout(Duration::from_secs(5), host.read_exact(&mut answer))
+        .await
+        .expect("Expected an answer before the timeout")
+        .expect("Expected to read the answer");
+    assert_eq!(answer, [0x00, 0x01, RESET_POWERON]);
+
+    host.write_all(&[0x00, 0x03, 0x01, 0x00, 0x00])
+        .await
+        .expect("Expected to send an EZSP frame");
+    let mut answer = [0; 5];
+    timeout(Duration::from_secs(5), host.read_exact(&mut answer))
+        .await
+        .expect("Expected an answer before the timeout")
+        .expect("Expected to read the answer");
+    assert_eq!(answer, [0x00, 0x03, 0x01, 0x80, 0x00]);
+}
+
+#[tokio::test]
+async fn it_relays_extended_responses_in_transparent_mode() {
+    let body = vec![0x5A; 300];
+    let mut extended = vec![0xFE, 0xFF, 0x01, 0x2C];
+    extended.extend_from_slice(&body);
+    extended.push(0xA7);
+    let device = scripted_device(&[
+        RESET_RESPONSES[0],
+        RESET_RESPONSES[1],
+        RESET_RESPONSES[2],
+        RESET_RESPONSES[3],
+        &extended,
+    ]);
+    let (_actor, device) =
+        spi_device_handle(device, NcpOptions::default()).expect("Expected to spawn the SPI actor");
+    let health = Health::new(&device);
+    let (mut host, client) = duplex(1024);
+    let _bridge = spawn(handle_transparent(client, device, health));
+
+    host.write_all(&[0x00, 0x00])
+        .await
+        .expect("Expected to request a reset");
+    let mut answer = [0; 3];
+    timeout(Duration::from_secs(5), host.read_exact(&mut answer))
+        .await
+        .expect("Expected an answer before the timeout")
+        .expect("Expected to read the answer");
+
+    host.write_all(&[0x00, 0x03, 0x01, 0x00, 0x00])
+        .await
+        .expect("Expected to send an EZSP frame");
+    let mut answer = vec![0; 2 + body.len()];
+    timeout(Duration::from_secs(5), host.read_exact(&mut answer))
+        .await
+        .expect("Expected an answer before the timeout")
+        .expect("Expected to read the answer");
+    assert_eq!(answer[..2], [0x01, 0x2C]);
+    assert_eq!(answer[2..], body[..]);
+}
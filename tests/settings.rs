@@ -0,0 +1,37 @@
+//! Loads settings the way the binary does, from a `--config` path given on
+//! the command line.
+use argh::FromArgs;
+use ezsp_spi_driver::settings::{Args, Settings};
+use std::{env::temp_dir, fs, path::PathBuf};
+use tracing::Level;
+
+#[test]
+fn it_parses_the_config_path_argument() {
+    let args = Args::from_args(&["ezsp-spi-driver"], &["--config", "/etc/bridge.toml"])
+        .expect("Expected arguments to parse");
+
+    assert_eq!(args.config, Some(PathBuf::from("/etc/bridge.toml")));
+}
+
+#[test]
+fn it_loads_settings_from_the_config_path_argument() {
+    let path = temp_dir().join("ezsp-spi-bridge-args-settings-test.toml");
+    fs::write(
+        &path,
+        "port = 6000\nloglevel = \"debug\"\n[spi]\ndevice = \"/dev/spidev0.1\"\n",
+    )
+    .expect("Expected to write config file");
+    let config = path.to_str().expect("Expected a UTF-8 temporary path");
+
+    let settings = Args::from_args(&["ezsp-spi-driver"], &["--config", config])
+        .map_err(|e| e.output)
+        .map(Settings::from_parsed_args);
+    let _ = fs::remove_file(&path);
+    let settings = settings
+        .expect("Expected arguments to parse")
+        .expect("Expected settings to load");
+
+    assert_eq!(settings.port, 6000);
+    assert_eq!(settings.loglevel, Level::DEBUG);
+    assert_eq!(settings.spi.device, PathBuf::from("/dev/spidev0.1"));
+}